@@ -1,26 +1,164 @@
-use std::{error::Error, path::PathBuf};
+use std::{
+  collections::HashMap,
+  error::Error,
+  fs,
+  io::{self, BufRead},
+  path::{Path, PathBuf},
+  time::Duration,
+};
 
-use clap::{arg, Args, Parser, Subcommand};
+use clap::{Args, Parser, Subcommand};
 
 #[derive(Args)]
 struct CompressArgs {
-  /// Input directory name. If a file is provided, empty archive is generated
-  #[arg(long, short = 'i')]
-  input_path: PathBuf,
+  /// Input directory name. If a file is provided, the archive contains just that file. Can be
+  /// repeated to merge several inputs into one archive. With --files-from, the directory the
+  /// listed paths are named relative to, the current one if not given
+  #[arg(long, short = 'i', required_unless_present = "files_from")]
+  input_path: Vec<PathBuf>,
+  /// Archive exactly the files and empty folders listed one per line in this file, or stdin
+  /// with '-', instead of walking the input directory. Listed folders don't bring their contents
+  #[arg(long)]
+  files_from: Option<PathBuf>,
+  /// Paths in the --files-from list are separated by NUL instead of newlines, as printed by
+  /// `find -print0`
+  #[arg(long, requires = "files_from")]
+  null: bool,
   /// Output file's name. Expected extention name is .bda
   #[arg(long, short = 'o')]
   output_path: PathBuf,
   /// Number of block to compress in parallel
   #[arg(long, short = 't', default_value_t = 1)]
   thread_count: u8,
+  /// Compress the biggest blocks first, so threads aren't left waiting on a big one at the end
+  #[arg(long)]
+  largest_first: bool,
   /// Compression to use. Defaults to ZSTD
-  /// supported: LZMA, LZ4, ZSTD, NONE
+  /// supported: LZMA, LZ4, ZSTD, SNAPPY, BZIP2, NONE
   #[arg(long, short = 'c', default_value_t = String::from("ZSTD"))]
   compression: String,
+  /// Compression level for blocks. Defaults to 6 for ZSTD and 9 for LZMA (xz) and BZIP2
+  #[arg(long)]
+  level: Option<u32>,
+  /// Compression for entries matching a regex as REGEX=COMPRESSION, e.g. '\.png$=NONE'. Matching
+  /// files get blocks of their own. Can be repeated, the first match wins
+  #[arg(long = "compress-with", value_parser = parse_key_value)]
+  compression_overrides: Vec<(String, String)>,
   /// Max size of file in bytes to be processed in memory instead of writing to temp file.
   /// Use 0 to reduce RAM usage
   #[arg(long, short = 'b', default_value_t = 64 * 1024 * 1024)]
   block_size: u64,
+  /// Prefix to add to every stored entry name
+  #[arg(long)]
+  base: Option<String>,
+  /// Store absolute input paths instead of paths relative to the input directory
+  #[arg(long, conflicts_with = "base")]
+  absolute: bool,
+  /// Store the entries of every input directory under the directory's name, so inputs with the
+  /// same layout don't collide
+  #[arg(long, conflicts_with = "absolute")]
+  prefix_inputs: bool,
+  /// Store a content hash of every file
+  #[arg(long)]
+  hash: bool,
+  /// Hash algorithm used with --hash
+  /// supported: blake3, sha256, xxh3
+  #[arg(long, default_value = "blake3")]
+  hash_algo: bloda_sys::HashAlgorithm,
+  /// Order in which files are packed into blocks
+  /// supported: size-sorted, path-grouped, as-found
+  #[arg(long, default_value = "size-sorted")]
+  pack_strategy: bloda_sys::PackStrategy,
+  /// Put at most this many files in a block, closing blocks at whichever of this and the block
+  /// size comes first
+  #[arg(long)]
+  max_files_per_block: Option<usize>,
+  /// Order in which entries are listed from the archive, independent of packing
+  /// supported: name, size
+  #[arg(long, default_value = "name")]
+  index_order: bloda_sys::IndexOrder,
+  /// Compression to use for the archive index
+  /// supported: LZMA, LZ4, ZSTD, SNAPPY, BZIP2
+  #[arg(long, default_value_t = String::from("ZSTD"))]
+  index_compression: String,
+  /// Metadata to store in the archive as key=value. Can be repeated
+  #[arg(long = "meta", value_parser = parse_key_value)]
+  metadata: Vec<(String, String)>,
+  /// Don't store the creation time, so identical inputs give identical archives
+  #[arg(long)]
+  reproducible: bool,
+  /// Frame blocks so they can be recovered if the index is lost
+  #[arg(long)]
+  recoverable: bool,
+  /// Store the uid and gid of files and empty folders. Unix only
+  #[arg(long)]
+  owner: bool,
+  /// Store hardlinked files once and recreate the links on extraction. Unix only
+  #[arg(long)]
+  hardlinks: bool,
+  /// Store flags like immutable and append-only of files and empty folders. Linux and BSD only
+  #[arg(long)]
+  file_flags: bool,
+  /// Only archive this many levels below the input directory. Folders at the limit are stored
+  /// empty
+  #[arg(long)]
+  max_depth: Option<usize>,
+  /// Read the archive back after writing it and fail if anything doesn't decompress or match its
+  /// hash. Doubles the I/O
+  #[arg(long)]
+  verify: bool,
+  /// Leave out files that can't be listed or read, e.g. unreadable ones or ones that vanished,
+  /// instead of stopping
+  #[arg(long)]
+  keep_going: bool,
+  /// Times to retry transient I/O errors on input files and the output before giving up
+  #[arg(long, default_value_t = 0)]
+  retries: u32,
+  /// Wait before the first retry in milliseconds, doubled on every following one
+  #[arg(long, default_value_t = 100)]
+  retry_backoff_ms: u64,
+}
+
+/// Paths of the list at `path`, or stdin if it's `-`, separated by `separator`. Empty entries
+/// are skipped
+fn read_path_list(path: &Path, separator: u8) -> Result<Vec<PathBuf>, String> {
+  let reader: Box<dyn BufRead> = if path == Path::new("-") {
+    Box::new(io::stdin().lock())
+  } else {
+    let file = fs::File::open(path).map_err(|e| format!("at opening {path:?}: {e}"))?;
+    Box::new(io::BufReader::new(file))
+  };
+  let mut paths = vec![];
+  for entry in reader.split(separator) {
+    let mut entry = entry.map_err(|e| format!("at reading path list: {e}"))?;
+    if separator == b'\n' && entry.last() == Some(&b'\r') {
+      entry.pop();
+    }
+    if entry.is_empty() {
+      continue;
+    }
+    paths.push(path_from_bytes(entry));
+  }
+  Ok(paths)
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+  use std::os::unix::ffi::OsStringExt;
+
+  PathBuf::from(std::ffi::OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> PathBuf {
+  PathBuf::from(String::from_utf8_lossy(&bytes).to_string())
+}
+
+fn parse_key_value(arg: &str) -> Result<(String, String), String> {
+  arg
+    .split_once('=')
+    .map(|(key, value)| (key.to_string(), value.to_string()))
+    .ok_or(format!("expected key=value, got {arg}"))
 }
 
 #[derive(Args)]
@@ -31,15 +169,204 @@ struct DecompressArgs {
   /// Output Dir name. Will be created if not present
   #[arg(long, short = 'o')]
   output_dir: PathBuf,
-  /// Number of block to compress in parallel
+  /// Number of files to write in parallel within a block
   #[arg(long, short = 't', default_value_t = 1)]
   thread_count: u8,
+  /// What to do with files that already exist in the output dir. Other existing files are kept
+  /// supported: overwrite, skip, error
+  #[arg(long, default_value = "overwrite")]
+  on_conflict: bloda_sys::ConflictPolicy,
+  /// Only extract entries matching this regex. Can be repeated, everything is extracted if none
+  /// are given
+  #[arg(long)]
+  include: Vec<String>,
+  /// Skip entries matching this regex, even if they match an include. Can be repeated
+  #[arg(long)]
+  exclude: Vec<String>,
+  /// Only extract files with this extension. Can be repeated, adds to the includes
+  #[arg(long)]
+  ext: Vec<String>,
+  /// Only extract entries under directories with this name, at any depth. Can be repeated, adds
+  /// to the includes
+  #[arg(long)]
+  under: Vec<String>,
+  /// Write a CSV of the extracted files with their sizes and stored hashes to this path
+  #[arg(long)]
+  manifest: Option<PathBuf>,
+  /// Restore the stored uids and gids of files and empty folders. Unix only, needs privileges
+  /// and is skipped without them
+  #[arg(long)]
+  numeric_owner: bool,
+  /// Restore the stored flags like immutable and append-only. Skipped where the platform,
+  /// filesystem or privileges don't allow it
+  #[arg(long)]
+  file_flags: bool,
+  /// Make the directories created while extracting readable and traversable by everyone (at
+  /// least 0755) whatever the umask. Unix only
+  #[arg(long)]
+  repair_permissions: bool,
+  /// Rename entries on the way out as 'PATTERN=>REPLACEMENT', a regex substitution on their
+  /// names, e.g. '^build/=>' to drop a leading folder or '(\d+)=>v$1'
+  #[arg(long)]
+  rename: Option<bloda_sys::Rename>,
+  /// Try other compressions if the index doesn't decompress with the one in the header
+  #[arg(long)]
+  lenient: bool,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+  /// Input archive name. Expecting a .bda file
+  #[arg(long, short = 'i')]
+  input_arc: PathBuf,
+  /// Compare the archive to this directory instead, listing missing, extra and changed files
+  #[arg(long)]
+  against: Option<PathBuf>,
+  /// Try other compressions if the index doesn't decompress with the one in the header
+  #[arg(long)]
+  lenient: bool,
+}
+
+#[derive(Args)]
+struct InfoArgs {
+  /// Input archive name. Expecting a .bda file
+  #[arg(long, short = 'i')]
+  input_arc: PathBuf,
+  /// Also list every file with its size and estimated compressed size
+  #[arg(long, short = 'l')]
+  long: bool,
+  /// Try other compressions if the index doesn't decompress with the one in the header
+  #[arg(long)]
+  lenient: bool,
+}
+
+#[derive(Args)]
+struct ListArgs {
+  /// Input archive name. Expecting a .bda file
+  #[arg(long, short = 'i')]
+  input_arc: PathBuf,
+  /// Only list entries matching this regex
+  #[arg(long)]
+  re: Option<String>,
+  /// Only list files
+  #[arg(long, conflicts_with = "only_dirs")]
+  only_files: bool,
+  /// Only list empty folders. Folders with files or other folders in them aren't stored as
+  /// entries and aren't listed
+  #[arg(long)]
+  only_dirs: bool,
+  /// Try other compressions if the index doesn't decompress with the one in the header
+  #[arg(long)]
+  lenient: bool,
+}
+
+#[derive(Args)]
+struct BlocksArgs {
+  /// Input archive name. Expecting a .bda file
+  #[arg(long, short = 'i')]
+  input_arc: PathBuf,
+  /// Only show this block
+  #[arg(long)]
+  block: Option<u64>,
+}
+
+#[derive(Args)]
+struct TranscodeArgs {
+  /// Input archive name. Expecting a .bda file
+  #[arg(long, short = 'i')]
+  input_arc: PathBuf,
+  /// Output file's name. Expected extention name is .bda
+  #[arg(long, short = 'o')]
+  output_path: PathBuf,
+  /// Compression to re-encode the blocks with
+  /// supported: LZMA, LZ4, ZSTD, SNAPPY, BZIP2, NONE
+  #[arg(long, short = 'c')]
+  compression: String,
+  /// Compression level for blocks. Defaults to 6 for ZSTD and 9 for LZMA (xz) and BZIP2
+  #[arg(long)]
+  level: Option<u32>,
+}
+
+#[derive(Args)]
+struct PlanArgs {
+  /// Input directory name. If a file is provided, the archive contains just that file
+  #[arg(long, short = 'i')]
+  input_path: PathBuf,
+  /// Compression to use. Defaults to ZSTD
+  /// supported: LZMA, LZ4, ZSTD, SNAPPY, BZIP2, NONE
+  #[arg(long, short = 'c', default_value_t = String::from("ZSTD"))]
+  compression: String,
+  /// Compression for entries matching a regex as REGEX=COMPRESSION, as for compress
+  #[arg(long = "compress-with", value_parser = parse_key_value)]
+  compression_overrides: Vec<(String, String)>,
+  /// Block size in bytes, as for compress
+  #[arg(long, short = 'b', default_value_t = 64 * 1024 * 1024)]
+  block_size: u64,
+  /// Prefix to add to every stored entry name
+  #[arg(long)]
+  base: Option<String>,
+  /// Store absolute input paths instead of paths relative to the input directory
+  #[arg(long, conflicts_with = "base")]
+  absolute: bool,
+  /// Order in which files are packed into blocks
+  /// supported: size-sorted, path-grouped, as-found
+  #[arg(long, default_value = "size-sorted")]
+  pack_strategy: bloda_sys::PackStrategy,
+  /// Put at most this many files in a block, as for compress
+  #[arg(long)]
+  max_files_per_block: Option<usize>,
+  /// Store hardlinked files once. Unix only
+  #[arg(long)]
+  hardlinks: bool,
+  /// Only archive this many levels below the input directory
+  #[arg(long)]
+  max_depth: Option<usize>,
+  /// Only print the summary, not the files of every block
+  #[arg(long, short = 's')]
+  summary: bool,
+}
+
+#[derive(Args)]
+struct CheckArgs {
+  /// Input archive name. Expecting a .bda file. Can be repeated to check several archives
+  #[arg(long, short = 'i', required = true)]
+  input_arc: Vec<PathBuf>,
+  /// Only check the header, the index and that the blocks fill the archive, without
+  /// decompressing any block
+  #[arg(long)]
+  fast: bool,
+}
+
+#[derive(Args)]
+struct BenchArgs {
+  /// Input directory to take the sample from
+  #[arg(long, short = 'i')]
+  input_path: PathBuf,
+  /// Size of the sample in bytes
+  #[arg(long, short = 's', default_value_t = 16 * 1024 * 1024)]
+  sample_size: u64,
 }
 
 #[derive(Subcommand)]
 enum AppCommands {
   Compress(CompressArgs),
   Decompress(DecompressArgs),
+  /// Check files against the hashes stored at creation
+  Verify(VerifyArgs),
+  /// Check that archives are intact, decompressing every block unless --fast is given
+  Check(CheckArgs),
+  /// Compare compression types and levels on a sample of a directory
+  Bench(BenchArgs),
+  /// Print a summary of an archive and its metadata
+  Info(InfoArgs),
+  /// List the files and empty folders of an archive
+  List(ListArgs),
+  /// List blocks with their sizes, compression and the files in them
+  Blocks(BlocksArgs),
+  /// Re-encode the blocks of an archive with another compression, one block at a time
+  Transcode(TranscodeArgs),
+  /// Show how a directory would be packed into blocks, without compressing or writing anything
+  Plan(PlanArgs),
 }
 
 #[derive(Parser)]
@@ -50,26 +377,408 @@ struct AppArgs {
   command: AppCommands,
 }
 
+fn open_archive(path: &Path, lenient: bool) -> Result<bloda_sys::ArchiveReader, String> {
+  let options = bloda_sys::ReaderOptions { lenient, ..Default::default() };
+  bloda_sys::ArchiveReader::with_options(path, &options)
+}
+
+/// Prints the warnings bloda-sys logs to stderr
+struct StderrLogger;
+
+impl log::Log for StderrLogger {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    metadata.level() <= log::Level::Warn
+  }
+
+  fn log(&self, record: &log::Record) {
+    if !self.enabled(record.metadata()) {
+      return;
+    }
+    match record.level() {
+      log::Level::Error => eprintln!("error: {}", record.args()),
+      _ => eprintln!("warning: {}", record.args()),
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+static LOGGER: StderrLogger = StderrLogger;
+
+/// Metadata entries `info` lists, sorted by key. bloda's own keys are left out since `info`
+/// prints them on their own lines
+fn user_metadata(metadata: &HashMap<String, String>) -> Vec<(&String, &String)> {
+  let mut metadata = metadata
+    .iter()
+    .filter(|(key, _)| !key.starts_with("bloda."))
+    .collect::<Vec<_>>();
+  metadata.sort();
+  metadata
+}
+
+/// Table of the blocks `blocks` prints, each followed by its files. Only block `block` if given
+fn blocks_listing(
+  archive: &bloda_sys::ArchiveReader,
+  block: Option<u64>
+) -> Result<String, String> {
+  let blocks = archive
+    .blocks()
+    .into_iter()
+    .filter(|x| block.is_none_or(|id| x.id == id))
+    .collect::<Vec<_>>();
+  if let (Some(id), true) = (block, blocks.is_empty()) {
+    return Err(format!("no block {id}, the archive has {}", archive.block_count()));
+  }
+  let mut listing = format!(
+    "{:>8}{:>14}{:>14}{:>8}{:>7}  COMPRESSION\n",
+    "BLOCK", "STORED", "SIZE", "FILES", "LEVEL"
+  );
+  for block in blocks {
+    let files = archive.files_in_block(block.id);
+    let level = block.compression_level.map(|x| x.to_string()).unwrap_or("-".to_string());
+    listing.push_str(&format!(
+      "{:>8}{:>14}{:>14}{:>8}{level:>7}  {}\n",
+      block.id,
+      block.compressed_size,
+      block.raw_size,
+      files.len(),
+      block.compression
+    ));
+    for name in files {
+      listing.push_str(&format!("          {name}\n"));
+    }
+  }
+  Ok(listing)
+}
+
 fn main() -> Result<(), Box<dyn Error>>{
+  log::set_logger(&LOGGER).map_err(|e| e.to_string())?;
+  log::set_max_level(log::LevelFilter::Warn);
   let args = AppArgs::parse();
   match args.command {
     AppCommands::Compress(compress_args) => {
-      let _ = bloda_sys::create_archive(
-        &compress_args.input_path,
-        &compress_args.output_path,
-        &compress_args.compression,
-        compress_args.thread_count,
-        Some(compress_args.block_size)
+      let options = bloda_sys::CreateOptions {
+        base_name: compress_args.base,
+        absolute_paths: compress_args.absolute,
+        hash_files: compress_args.hash,
+        hash_algorithm: compress_args.hash_algo,
+        pack_strategy: compress_args.pack_strategy,
+        index_order: compress_args.index_order,
+        index_compression: Some(compress_args.index_compression),
+        metadata: compress_args.metadata.into_iter().collect(),
+        reproducible: compress_args.reproducible,
+        recoverable: compress_args.recoverable,
+        compression_level: compress_args.level,
+        verify_after_write: compress_args.verify,
+        retry: bloda_sys::RetryPolicy::new(
+          compress_args.retries,
+          Duration::from_millis(compress_args.retry_backoff_ms)
+        ),
+        compression_overrides: compress_args.compression_overrides,
+        store_ownership: compress_args.owner,
+        detect_hardlinks: compress_args.hardlinks,
+        store_file_flags: compress_args.file_flags,
+        largest_blocks_first: compress_args.largest_first,
+        keep_going: compress_args.keep_going,
+        max_depth: compress_args.max_depth,
+        prefix_inputs: compress_args.prefix_inputs,
+        max_files_per_block: compress_args.max_files_per_block,
+      };
+      let inputs = compress_args.input_path.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+      let report = match &compress_args.files_from {
+        Some(files_from) => {
+          let separator = if compress_args.null { b'\0' } else { b'\n' };
+          let base_dir = match inputs[..] {
+            [] => Ok(Path::new(".")),
+            [base_dir] => Ok(base_dir),
+            _ => Err("only one input directory can be given with --files-from".to_string()),
+          };
+          base_dir.and_then(|base_dir| {
+            bloda_sys::create_archive_from_list(
+              &read_path_list(files_from, separator)?,
+              base_dir,
+              &compress_args.output_path,
+              &compress_args.compression,
+              compress_args.thread_count,
+              Some(compress_args.block_size),
+              &options
+            )
+          })
+        }
+        None => bloda_sys::create_archive_from_inputs(
+          &inputs,
+          &compress_args.output_path,
+          &compress_args.compression,
+          compress_args.thread_count,
+          Some(compress_args.block_size),
+          &options
+        ),
+      }
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      for diagnostic in &report.diagnostics {
+        eprintln!("warning: {diagnostic}");
+      }
+      println!(
+        "{} files, {} folders in {} blocks: {} -> {} bytes ({:.2}x) in {:.2}s",
+        report.files,
+        report.dirs,
+        report.blocks,
+        report.input_bytes,
+        report.output_bytes,
+        report.ratio(),
+        report.duration.as_secs_f64()
+      );
+    },
+    AppCommands::Decompress(mut decompress_args) => {
+      let ext_patterns = decompress_args.ext.iter().map(|x| bloda_sys::extension_pattern(x));
+      let under_patterns = decompress_args.under.iter().map(|x| bloda_sys::under_pattern(x));
+      decompress_args.include.extend(ext_patterns.chain(under_patterns).collect::<Vec<_>>());
+      let options = bloda_sys::ExtractOptions {
+        ignore_errors: false,
+        conflict_policy: decompress_args.on_conflict,
+        manifest: decompress_args.manifest.clone(),
+        restore_ownership: decompress_args.numeric_owner,
+        restore_file_flags: decompress_args.file_flags,
+        threads: decompress_args.thread_count as usize,
+        repair_permissions: decompress_args.repair_permissions,
+        rename: decompress_args.rename.clone(),
+      };
+      let archive = open_archive(&decompress_args.input_arc, decompress_args.lenient)
+        .map_err(|e| format!("invalid archive: {e}"))
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      if decompress_args.include.is_empty() && decompress_args.exclude.is_empty() {
+        // Creates the output dir itself, with repaired permissions if asked to
+        archive
+          .extract_all(&decompress_args.output_dir, &options)
+          .inspect_err(|e| eprintln!("error: {e}"))?;
+      } else {
+        archive
+          .extract_files_multi(
+            &decompress_args.include,
+            &decompress_args.exclude,
+            &decompress_args.output_dir,
+            &options
+          )
+          .inspect_err(|e| eprintln!("error: {e}"))?;
+      }
+    },
+    AppCommands::Verify(VerifyArgs { input_arc, against: Some(dir), lenient }) => {
+      let report = open_archive(&input_arc, lenient)
+        .and_then(|archive| archive.verify_against_dir(&dir))
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      for name in &report.missing {
+        println!("missing: {name}");
+      }
+      for name in &report.extra {
+        println!("extra: {name}");
+      }
+      for name in &report.changed {
+        println!("changed: {name}");
+      }
+      if !report.is_match() {
+        return Err(format!(
+          "archive and {dir:?} differ: {} missing, {} extra, {} changed",
+          report.missing.len(),
+          report.extra.len(),
+          report.changed.len()
+        ).into());
+      }
+    },
+    AppCommands::Verify(verify_args) => {
+      let mismatched = open_archive(&verify_args.input_arc, verify_args.lenient)
+        .and_then(|archive| archive.verify())
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      for name in &mismatched {
+        println!("hash mismatch: {name}");
+      }
+      if !mismatched.is_empty() {
+        return Err(format!("{} files don't match their stored hash", mismatched.len()).into());
+      }
+    },
+    AppCommands::Check(check_args) => {
+      let mut failed = 0;
+      for input_arc in &check_args.input_arc {
+        let res = if check_args.fast {
+          bloda_sys::quick_check(input_arc)
+        } else {
+          open_archive(input_arc, false).and_then(|archive| archive.check())
+        };
+        match res {
+          Ok(()) => println!("ok: {}", input_arc.display()),
+          Err(e) => {
+            println!("failed: {}: {e}", input_arc.display());
+            failed += 1;
+          }
+        }
+      }
+      if failed > 0 {
+        let total = check_args.input_arc.len();
+        return Err(format!("{failed} of {total} archives failed the check").into());
+      }
+    },
+    AppCommands::List(list_args) => {
+      let archive = open_archive(&list_args.input_arc, list_args.lenient)
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      let entries = match (&list_args.re, list_args.only_files, list_args.only_dirs) {
+        (None, true, _) => Ok(archive.list_files()),
+        (None, _, true) => Ok(archive.list_dirs()),
+        (None, _, _) => Ok(archive.list_all_entries()),
+        (Some(re), true, _) => archive.list_files_re(re),
+        (Some(re), _, true) => archive.list_dirs_re(re),
+        (Some(re), _, _) => archive.list_entries_re(re),
+      }
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      for entry in entries {
+        println!("{entry}");
+      }
+    },
+    AppCommands::Info(info_args) => {
+      let archive = open_archive(&info_args.input_arc, info_args.lenient)
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      println!("files: {}", archive.file_count());
+      println!("blocks: {}", archive.block_count());
+      println!("format version: {}", archive.format_version());
+      println!("features: {}", archive.features().join(", "));
+      println!("index compression: {}", archive.index_compression());
+      if let Some(created_by) = archive.created_by() {
+        println!("created by: {created_by}");
+      }
+      if let Some(created_at) = archive.created_at() {
+        println!("created at: {created_at}");
+      }
+      let metadata = user_metadata(archive.metadata());
+      if !metadata.is_empty() {
+        println!("metadata:");
+      }
+      for (key, value) in metadata {
+        println!("  {key}={value}");
+      }
+      if info_args.long {
+        println!("{:>14}{:>14}  NAME", "SIZE", "EST. STORED");
+        for name in (0..archive.file_count()).filter_map(|n| archive.entry_name(n)) {
+          println!(
+            "{:>14}{:>14}  {name}",
+            archive.file_size(name).unwrap_or(0),
+            archive.estimated_compressed_size(name).unwrap_or(0)
+          );
+        }
+      }
+    },
+    AppCommands::Blocks(blocks_args) => {
+      let archive = bloda_sys::ArchiveReader::new(&blocks_args.input_arc, None)
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      print!("{}", blocks_listing(&archive, blocks_args.block)?);
+    },
+    AppCommands::Transcode(transcode_args) => {
+      bloda_sys::transcode_archive(
+        &transcode_args.input_arc,
+        &transcode_args.output_path,
+        &transcode_args.compression,
+        transcode_args.level
       )
         .inspect_err(|e| eprintln!("error: {e}"))?;
     },
-    AppCommands::Decompress(decompress_args) => {
-      let _ = bloda_sys::decompress_archive(
-        &decompress_args.input_arc,
-        &decompress_args.output_dir
+    AppCommands::Plan(plan_args) => {
+      let options = bloda_sys::CreateOptions {
+        base_name: plan_args.base,
+        absolute_paths: plan_args.absolute,
+        pack_strategy: plan_args.pack_strategy,
+        compression_overrides: plan_args.compression_overrides,
+        detect_hardlinks: plan_args.hardlinks,
+        max_depth: plan_args.max_depth,
+        max_files_per_block: plan_args.max_files_per_block,
+        ..Default::default()
+      };
+      let plan = bloda_sys::plan_archive(
+        &plan_args.input_path,
+        &plan_args.compression,
+        Some(plan_args.block_size),
+        &options
       )
         .inspect_err(|e| eprintln!("error: {e}"))?;
+      for diagnostic in &plan.diagnostics {
+        eprintln!("warning: {diagnostic}");
+      }
+      println!("{:>8}{:>14}{:>8}  COMPRESSION", "BLOCK", "SIZE", "FILES");
+      for (i, block) in plan.blocks.iter().enumerate() {
+        println!("{i:>8}{:>14}{:>8}  {}", block.size, block.files.len(), block.compression);
+        if !plan_args.summary {
+          for (name, _) in &block.files {
+            println!("          {name}");
+          }
+        }
+      }
+      println!(
+        "blocks: {}, files: {}, empty folders: {}, total size: {}",
+        plan.blocks.len(),
+        plan.blocks.iter().map(|x| x.files.len()).sum::<usize>(),
+        plan.folder_leaves.len(),
+        plan.total_size
+      );
+    },
+    AppCommands::Bench(bench_args) => {
+      let results = bloda_sys::bench_compressions(&bench_args.input_path, bench_args.sample_size)
+        .inspect_err(|e| eprintln!("error: {e}"))?;
+      println!(
+        "{:<12}{:<8}{:>14}{:>14}{:>10}{:>12}",
+        "COMPRESSION", "LEVEL", "INPUT", "OUTPUT", "RATIO", "MB/S"
+      );
+      for result in results {
+        let level = result.level.map(|x| x.to_string()).unwrap_or("-".to_string());
+        println!(
+          "{:<12}{:<8}{:>14}{:>14}{:>10.3}{:>12.2}",
+          result.compression,
+          level,
+          result.input_size,
+          result.output_size,
+          result.ratio,
+          result.mb_per_sec
+        );
+      }
     },
   }
   Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn user_metadata_leaves_out_bloda_keys(){
+    let metadata = HashMap::from([
+      ("bloda.created_by".to_string(), "bloda 0.1.0".to_string()),
+      ("bloda.created_at".to_string(), "1700000000".to_string()),
+      ("team".to_string(), "infra".to_string()),
+      ("host".to_string(), "ci".to_string()),
+    ]);
+    let keys = user_metadata(&metadata).into_iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+    assert_eq!(keys, ["host", "team"]);
+  }
+
+  #[test]
+  fn blocks_listing_maps_files_to_their_blocks(){
+    let entries: [(&str, &[u8]); 3] = [("a.txt", b"aaaa"), ("b.txt", b"bbbb"), ("c.txt", b"cc")];
+    let options = bloda_sys::CreateOptions::default();
+    let data = bloda_sys::create_archive_to_vec(&entries, "ZSTD", Some(8), &options).unwrap();
+    let archive = bloda_sys::ArchiveReader::from_bytes(&data).unwrap();
+
+    let listing = blocks_listing(&archive, None).unwrap();
+    let mut lines = listing.lines().skip(1);
+    for block in archive.blocks() {
+      let header = lines.next().unwrap().split_whitespace().collect::<Vec<_>>();
+      let files = archive.files_in_block(block.id);
+      assert_eq!(header[0], block.id.to_string());
+      assert_eq!(header[3], files.len().to_string());
+      for name in files {
+        assert_eq!(lines.next().unwrap().trim(), name);
+      }
+    }
+    assert_eq!(lines.next(), None);
+
+    let listing = blocks_listing(&archive, Some(1)).unwrap();
+    let names = listing.lines().skip(2).map(|x| x.trim()).collect::<Vec<_>>();
+    assert_eq!(names, archive.files_in_block(1));
+    assert!(blocks_listing(&archive, Some(9)).is_err());
+  }
+}
@@ -1,6 +1,17 @@
-use std::path::PathBuf;
+use std::{
+    collections::{BTreeMap, HashMap},
+    path::PathBuf,
+    time::Duration,
+};
 
-use pyo3::{exceptions::PyException, prelude::*};
+use pyo3::{
+    exceptions::PyException,
+    prelude::*,
+    types::{PyBytes, PyDict, PyTuple},
+};
+
+/// Id, compressed size, raw size, compression and compression level of a block
+type BlockTuple = (u64, u64, u64, String, Option<u32>);
 
 #[pyclass]
 struct ArchiveReader{
@@ -13,6 +24,122 @@ impl ArchiveReader{
         PyResult::Ok(self.inner.entry_type(&name))
     }
 
+    fn refresh(&mut self) -> PyResult<()>{
+        self.inner.refresh().map_err(PyException::new_err)
+    }
+
+    fn format_version(&self) -> PyResult<u32>{
+        Ok(self.inner.format_version())
+    }
+
+    fn features(&self) -> PyResult<Vec<String>>{
+        Ok(self.inner.features().into_iter().map(|x| x.to_string()).collect())
+    }
+
+    fn index_compression(&self) -> PyResult<String>{
+        Ok(self.inner.index_compression().to_string())
+    }
+
+    fn metadata(&self) -> PyResult<HashMap<String, String>>{
+        Ok(self.inner.metadata().clone())
+    }
+
+    fn created_by(&self) -> PyResult<Option<String>>{
+        Ok(self.inner.created_by().map(|x| x.to_string()))
+    }
+
+    fn created_at(&self) -> PyResult<Option<u64>>{
+        Ok(self.inner.created_at())
+    }
+
+    fn block_count(&self) -> PyResult<usize>{
+        Ok(self.inner.block_count())
+    }
+
+    fn blocks(&self) -> PyResult<Vec<BlockTuple>>{
+        Ok(self
+            .inner
+            .blocks()
+            .into_iter()
+            .map(|x| (x.id, x.compressed_size, x.raw_size, x.compression, x.compression_level))
+            .collect())
+    }
+
+    fn files_in_block(&self, block_id: u64) -> PyResult<Vec<String>>{
+        Ok(self.inner.files_in_block(block_id).into_iter().map(|x| x.to_string()).collect())
+    }
+
+    fn read_block_raw<'py>(
+        &self,
+        py: Python<'py>,
+        block_id: u64,
+    ) -> PyResult<Bound<'py, PyBytes>>{
+        let data = self.inner.read_block_raw(block_id).map_err(PyException::new_err)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    fn decompress_block<'py>(
+        &self,
+        py: Python<'py>,
+        block_id: u64,
+    ) -> PyResult<Bound<'py, PyBytes>>{
+        let data = self.inner.decompress_block(block_id).map_err(PyException::new_err)?;
+        Ok(PyBytes::new(py, &data))
+    }
+
+    fn file_count(&self) -> PyResult<usize>{
+        Ok(self.inner.file_count())
+    }
+
+    fn entry_name(&self, n: usize) -> PyResult<Option<String>>{
+        Ok(self.inner.entry_name(n).map(|x| x.to_string()))
+    }
+
+    fn extract_nth(&self, n: usize, output: PathBuf) -> PyResult<()>{
+        self.inner.extract_nth(n, &output).map_err(PyException::new_err)
+    }
+
+    fn file_hash(&self, name: String) -> PyResult<Option<(String, String)>>{
+        Ok(self
+            .inner
+            .file_hash(&name)
+            .map(|(hash, algorithm)| (hash.to_string(), algorithm.as_str().to_string())))
+    }
+
+    fn owner(&self, name: String) -> PyResult<Option<(u32, u32)>>{
+        Ok(self.inner.owner(&name))
+    }
+
+    fn hardlink_target(&self, name: String) -> PyResult<Option<String>>{
+        Ok(self.inner.hardlink_target(&name).map(|x| x.to_string()))
+    }
+
+    fn file_size(&self, name: String) -> PyResult<Option<u64>>{
+        Ok(self.inner.file_size(&name))
+    }
+
+    fn estimated_compressed_size(&self, name: String) -> PyResult<Option<u64>>{
+        Ok(self.inner.estimated_compressed_size(&name))
+    }
+
+    fn verify(&self) -> PyResult<Vec<String>>{
+        self.inner.verify().map_err(PyException::new_err)
+    }
+
+    fn verify_against_dir(
+        &self,
+        dir: PathBuf,
+    ) -> PyResult<(Vec<String>, Vec<String>, Vec<String>)>{
+        self.inner
+            .verify_against_dir(&dir)
+            .map(|x| (x.missing, x.extra, x.changed))
+            .map_err(PyException::new_err)
+    }
+
+    fn check(&self) -> PyResult<()>{
+        self.inner.check().map_err(PyException::new_err)
+    }
+
     fn list_all_entries(&self) -> PyResult<Vec<String>>{
         Ok(self.inner.list_all_entries())
     }
@@ -21,43 +148,253 @@ impl ArchiveReader{
         self.inner.list_entries_re(&re_pattern).map_err(PyException::new_err)
     }
 
-    fn list_dir(&self, dir_name: String) -> PyResult<Vec<(String, String)>>{
-        self.inner.list_dir(&dir_name).map_err(PyException::new_err)
+    fn list_files(&self) -> PyResult<Vec<String>>{
+        Ok(self.inner.list_files())
+    }
+
+    fn list_files_re(&self, re_pattern: String) -> PyResult<Vec<String>>{
+        self.inner.list_files_re(&re_pattern).map_err(PyException::new_err)
+    }
+
+    fn list_dirs(&self) -> PyResult<Vec<String>>{
+        Ok(self.inner.list_dirs())
+    }
+
+    fn list_dirs_re(&self, re_pattern: String) -> PyResult<Vec<String>>{
+        self.inner.list_dirs_re(&re_pattern).map_err(PyException::new_err)
+    }
+
+    fn list_dir(&self, dir_name: String) -> PyResult<Vec<(String, String, u64)>>{
+        self.inner
+            .list_dir(&dir_name)
+            .map(|entries| entries.into_iter().map(|x| (x.name, x.kind, x.size)).collect())
+            .map_err(PyException::new_err)
     }
 
     fn extract_file(&self, name: String, output: PathBuf) -> PyResult<()>{
         self.inner.extract_file(&name, &output).map_err(PyException::new_err)
     }
 
-    fn extract_files(&self, re_pattern: String, output_dir: PathBuf) -> PyResult<()>{
-        self.inner.extract_files(&re_pattern, &output_dir, false).map_err(PyException::new_err)
+    #[pyo3(signature = (re_pattern, output_dir, on_conflict="overwrite".to_string(), manifest=None, restore_ownership=false, restore_file_flags=false, threads=1, repair_permissions=false))]
+    #[allow(clippy::too_many_arguments)]
+    fn extract_files(
+        &self,
+        py: Python<'_>,
+        re_pattern: String,
+        output_dir: PathBuf,
+        on_conflict: String,
+        manifest: Option<PathBuf>,
+        restore_ownership: bool,
+        restore_file_flags: bool,
+        threads: usize,
+        repair_permissions: bool,
+    ) -> PyResult<()>{
+        let options = bloda_sys::ExtractOptions {
+            conflict_policy: on_conflict.parse().map_err(PyException::new_err)?,
+            manifest,
+            restore_ownership,
+            restore_file_flags,
+            threads,
+            repair_permissions,
+            ..Default::default()
+        };
+        py.allow_threads(|| {
+            self.inner.extract_files_with_options(&re_pattern, &output_dir, &options)
+        })
+            .map_err(PyException::new_err)
+    }
+
+    /// `extract_files` as an awaitable, run on a separate thread
+    #[pyo3(signature = (*args, **kwargs))]
+    fn extract_files_async<'py>(
+        slf: &Bound<'py, Self>,
+        args: &Bound<'py, PyTuple>,
+        kwargs: Option<&Bound<'py, PyDict>>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        call_in_thread(slf.py(), slf.getattr("extract_files")?, args, kwargs)
+    }
+
+    fn extract_by_extension(&self, ext: String, output_dir: PathBuf) -> PyResult<()>{
+        self.inner.extract_by_extension(&ext, &output_dir).map_err(PyException::new_err)
+    }
+
+    fn extract_under(&self, component: String, output_dir: PathBuf) -> PyResult<()>{
+        self.inner.extract_under(&component, &output_dir).map_err(PyException::new_err)
+    }
+
+    #[pyo3(signature = (includes, excludes, output_dir, on_conflict="overwrite".to_string(), manifest=None, restore_ownership=false, restore_file_flags=false, threads=1, repair_permissions=false))]
+    #[allow(clippy::too_many_arguments)]
+    fn extract_files_multi(
+        &self,
+        py: Python<'_>,
+        includes: Vec<String>,
+        excludes: Vec<String>,
+        output_dir: PathBuf,
+        on_conflict: String,
+        manifest: Option<PathBuf>,
+        restore_ownership: bool,
+        restore_file_flags: bool,
+        threads: usize,
+        repair_permissions: bool,
+    ) -> PyResult<()>{
+        let options = bloda_sys::ExtractOptions {
+            conflict_policy: on_conflict.parse().map_err(PyException::new_err)?,
+            manifest,
+            restore_ownership,
+            restore_file_flags,
+            threads,
+            repair_permissions,
+            ..Default::default()
+        };
+        py.allow_threads(|| {
+            self.inner.extract_files_multi(&includes, &excludes, &output_dir, &options)
+        })
+            .map_err(PyException::new_err)
+    }
+
+    /// `extract_files_multi` as an awaitable, run on a separate thread
+    #[pyo3(signature = (*args, **kwargs))]
+    fn extract_files_multi_async<'py>(
+        slf: &Bound<'py, Self>,
+        args: &Bound<'py, PyTuple>,
+        kwargs: Option<&Bound<'py, PyDict>>,
+    ) -> PyResult<Bound<'py, PyAny>> {
+        call_in_thread(slf.py(), slf.getattr("extract_files_multi")?, args, kwargs)
     }
 }
 
 #[pyfunction]
-fn open_archive(archive_path: PathBuf) -> PyResult<ArchiveReader> {
-    bloda_sys::ArchiveReader::new(&archive_path, None)
+#[pyo3(signature = (archive_path, lenient=false))]
+fn open_archive(archive_path: PathBuf, lenient: bool) -> PyResult<ArchiveReader> {
+    let options = bloda_sys::ReaderOptions { lenient, ..Default::default() };
+    bloda_sys::ArchiveReader::with_options(&archive_path, &options)
         .map(|x| ArchiveReader {inner: x})
         .map_err(PyException::new_err)
 }
 
+/// Create an archive of `input_dir`. Returns the report as a dict of files, dirs, blocks,
+/// input_bytes, output_bytes, ratio, duration, skipped and diagnostics
 #[pyfunction]
-#[pyo3(signature = (input_dir, output_file_name, /, compression_type="ZSTD".to_string(), threads=1, block_size=None))]
-fn create_archive(
+#[pyo3(signature = (input_dir, output_file_name, /, compression_type="ZSTD".to_string(), threads=1, block_size=None, base_name=None, absolute_paths=false, hash_files=false, hash_algorithm="blake3".to_string(), pack_strategy="size-sorted".to_string(), index_order="name".to_string(), index_compression="ZSTD".to_string(), metadata=None, reproducible=false, recoverable=false, retries=0, retry_backoff_ms=100, compression_level=None, verify_after_write=false, compression_overrides=None, store_ownership=false, detect_hardlinks=false, max_depth=None, store_file_flags=false, largest_blocks_first=false, keep_going=false, max_files_per_block=None))]
+#[allow(clippy::too_many_arguments)]
+fn create_archive<'py>(
+    py: Python<'py>,
     input_dir: PathBuf,
     output_file_name: PathBuf,
     compression_type: String,
     threads: u32,
-    block_size: Option<u64>
-) -> PyResult<()> {
-    bloda_sys::create_archive(
-        &input_dir,
-        &output_file_name,
-        &compression_type,
-        threads as _,
-        block_size
-    )
-        .map_err(PyException::new_err)
+    block_size: Option<u64>,
+    base_name: Option<String>,
+    absolute_paths: bool,
+    hash_files: bool,
+    hash_algorithm: String,
+    pack_strategy: String,
+    index_order: String,
+    index_compression: String,
+    metadata: Option<BTreeMap<String, String>>,
+    reproducible: bool,
+    recoverable: bool,
+    retries: u32,
+    retry_backoff_ms: u64,
+    compression_level: Option<u32>,
+    verify_after_write: bool,
+    compression_overrides: Option<Vec<(String, String)>>,
+    store_ownership: bool,
+    detect_hardlinks: bool,
+    max_depth: Option<usize>,
+    store_file_flags: bool,
+    largest_blocks_first: bool,
+    keep_going: bool,
+    max_files_per_block: Option<usize>,
+) -> PyResult<Bound<'py, PyDict>> {
+    let hash_algorithm = hash_algorithm.parse().map_err(PyException::new_err)?;
+    let pack_strategy = pack_strategy.parse().map_err(PyException::new_err)?;
+    let index_order = index_order.parse().map_err(PyException::new_err)?;
+    let options = bloda_sys::CreateOptions {
+        base_name,
+        absolute_paths,
+        hash_files,
+        hash_algorithm,
+        pack_strategy,
+        index_order,
+        index_compression: Some(index_compression),
+        metadata: metadata.unwrap_or_default(),
+        reproducible,
+        recoverable,
+        compression_level,
+        verify_after_write,
+        retry: bloda_sys::RetryPolicy::new(retries, Duration::from_millis(retry_backoff_ms)),
+        compression_overrides: compression_overrides.unwrap_or_default(),
+        store_ownership,
+        detect_hardlinks,
+        max_depth,
+        store_file_flags,
+        largest_blocks_first,
+        keep_going,
+        max_files_per_block,
+        ..Default::default()
+    };
+    let report = py.allow_threads(|| {
+        bloda_sys::create_archive_with_report(
+            &input_dir,
+            &output_file_name,
+            &compression_type,
+            threads as _,
+            block_size,
+            &options
+        )
+    })
+        .map_err(PyException::new_err)?;
+    let diagnostics = report.diagnostics.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    let report_dict = PyDict::new(py);
+    report_dict.set_item("files", report.files)?;
+    report_dict.set_item("dirs", report.dirs)?;
+    report_dict.set_item("blocks", report.blocks)?;
+    report_dict.set_item("input_bytes", report.input_bytes)?;
+    report_dict.set_item("output_bytes", report.output_bytes)?;
+    report_dict.set_item("ratio", report.ratio())?;
+    report_dict.set_item("duration", report.duration.as_secs_f64())?;
+    report_dict.set_item("skipped", report.skipped)?;
+    report_dict.set_item("diagnostics", diagnostics)?;
+    Ok(report_dict)
+}
+
+/// `create_archive` as an awaitable, run on a separate thread
+#[pyfunction]
+#[pyo3(signature = (*args, **kwargs))]
+fn create_archive_async<'py>(
+    py: Python<'py>,
+    args: &Bound<'py, PyTuple>,
+    kwargs: Option<&Bound<'py, PyDict>>,
+) -> PyResult<Bound<'py, PyAny>> {
+    call_in_thread(py, wrap_pyfunction!(create_archive, py)?.into_any(), args, kwargs)
+}
+
+/// Call `func(*args, **kwargs)` on the blocking pool of pyo3-async-runtimes' tokio runtime and
+/// return an asyncio future of its result on the running event loop. The pool is capped at one
+/// thread per core, see `bloda_pyo3`, so extra calls wait their turn instead of each getting a
+/// thread of its own. `func` has to release the GIL for its long running part, or the loop is
+/// blocked all the same
+fn call_in_thread<'py>(
+    py: Python<'py>,
+    func: Bound<'py, PyAny>,
+    args: &Bound<'py, PyTuple>,
+    kwargs: Option<&Bound<'py, PyDict>>,
+) -> PyResult<Bound<'py, PyAny>> {
+    let func = func.unbind();
+    let args = args.clone().unbind();
+    let kwargs = kwargs.map(|x| x.clone().unbind());
+    pyo3_async_runtimes::tokio::future_into_py(py, async move {
+        tokio::task::spawn_blocking(move || {
+            Python::with_gil(|py| {
+                func.bind(py)
+                    .call(args.bind(py), kwargs.as_ref().map(|x| x.bind(py)))
+                    .map(Bound::unbind)
+            })
+        })
+            .await
+            .map_err(|e| PyException::new_err(format!("at running in thread pool: {e}")))?
+    })
 }
 
 #[pyfunction]
@@ -69,10 +406,30 @@ fn decompress_archive(
         .map_err(PyException::new_err)
 }
 
+#[pyfunction]
+#[pyo3(signature = (input_path, output_path, compression_type, level=None))]
+fn transcode_archive(
+    input_path: PathBuf,
+    output_path: PathBuf,
+    compression_type: String,
+    level: Option<u32>,
+) -> PyResult<()> {
+    bloda_sys::transcode_archive(&input_path, &output_path, &compression_type, level)
+        .map_err(PyException::new_err)
+}
+
 #[pymodule]
 fn bloda_pyo3(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    // Archive work runs on the blocking pool, see `call_in_thread`. The runtime itself only waits
+    // on it, so one worker is enough
+    let threads = std::thread::available_parallelism().map_or(4, |x| x.get());
+    let mut builder = tokio::runtime::Builder::new_multi_thread();
+    builder.worker_threads(1).max_blocking_threads(threads).enable_all();
+    pyo3_async_runtimes::tokio::init(builder);
     m.add_function(wrap_pyfunction!(open_archive, m)?)?;
     m.add_function(wrap_pyfunction!(create_archive, m)?)?;
+    m.add_function(wrap_pyfunction!(create_archive_async, m)?)?;
     m.add_function(wrap_pyfunction!(decompress_archive, m)?)?;
+    m.add_function(wrap_pyfunction!(transcode_archive, m)?)?;
     Ok(())
 }
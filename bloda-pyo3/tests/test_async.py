@@ -0,0 +1,63 @@
+import asyncio
+import os
+
+import pytest
+
+import bloda_pyo3
+
+
+def make_tree(root, n_files):
+    os.makedirs(root)
+    for i in range(n_files):
+        with open(os.path.join(root, f"file_{i}.txt"), "w") as f:
+            f.write(f"contents of file {i}\n" * 100)
+
+
+def test_concurrent_create_and_extract(tmp_path):
+    make_tree(tmp_path / "a", 50)
+    make_tree(tmp_path / "b", 50)
+
+    async def run():
+        reports = await asyncio.gather(
+            bloda_pyo3.create_archive_async(str(tmp_path / "a"), str(tmp_path / "a.bda")),
+            bloda_pyo3.create_archive_async(str(tmp_path / "b"), str(tmp_path / "b.bda")),
+        )
+        reader_a = bloda_pyo3.open_archive(str(tmp_path / "a.bda"))
+        reader_b = bloda_pyo3.open_archive(str(tmp_path / "b.bda"))
+        await asyncio.gather(
+            reader_a.extract_files_async(".*", str(tmp_path / "out_a")),
+            reader_b.extract_files_async(".*", str(tmp_path / "out_b")),
+        )
+        return reports
+
+    reports = asyncio.run(run())
+    assert all(r["files"] == 50 for r in reports)
+    for name in ("a", "b"):
+        extracted = sorted(os.listdir(tmp_path / f"out_{name}"))
+        assert extracted == sorted(os.listdir(tmp_path / name))
+
+
+def test_async_errors_are_raised(tmp_path):
+    make_tree(tmp_path / "a", 1)
+
+    async def run():
+        await bloda_pyo3.create_archive_async(
+            str(tmp_path / "a"), str(tmp_path / "a.bda"), hash_algorithm="nope"
+        )
+
+    with pytest.raises(Exception):
+        asyncio.run(run())
+
+
+def test_more_calls_than_pool_threads_all_finish(tmp_path):
+    n_calls = (os.cpu_count() or 4) * 2 + 1
+    make_tree(tmp_path / "a", 3)
+
+    async def run():
+        return await asyncio.gather(*(
+            bloda_pyo3.create_archive_async(str(tmp_path / "a"), str(tmp_path / f"{i}.bda"))
+            for i in range(n_calls)
+        ))
+
+    reports = asyncio.run(run())
+    assert [r["files"] for r in reports] == [3] * n_calls
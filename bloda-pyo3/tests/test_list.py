@@ -0,0 +1,26 @@
+import os
+
+import bloda_pyo3
+
+
+def test_files_and_dirs_partition_entries(tmp_path):
+    root = tmp_path / "a"
+    os.makedirs(root / "empty")
+    os.makedirs(root / "sub" / "also_empty")
+    for name in ("x.txt", "sub/y.txt"):
+        with open(root / name, "w") as f:
+            f.write(name)
+    bloda_pyo3.create_archive(str(root), str(tmp_path / "a.bda"))
+    reader = bloda_pyo3.open_archive(str(tmp_path / "a.bda"))
+
+    files = reader.list_files()
+    dirs = reader.list_dirs()
+    assert sorted(files) == ["sub/y.txt", "x.txt"]
+    assert sorted(dirs) == ["empty", "sub/also_empty"]
+    assert sorted(files + dirs) == sorted(reader.list_all_entries())
+
+    files_re = reader.list_files_re("^sub/")
+    dirs_re = reader.list_dirs_re("^sub/")
+    assert files_re == ["sub/y.txt"]
+    assert dirs_re == ["sub/also_empty"]
+    assert sorted(files_re + dirs_re) == sorted(reader.list_entries_re("^sub/"))
@@ -1,11 +1,15 @@
 use std::io::{self, Read, Write};
 
+/// Every compression type blocks and indexes can be stored with
+pub const SUPPORTED_COMPRESSIONS: [&str; 6] = ["LZ4", "ZSTD", "LZMA", "SNAPPY", "BZIP2", "NONE"];
+
 pub fn decompress_data<R: Read, W: Write>(
   input_stream: R,
   output_stream: &mut W,
   compression: &str
 ) -> Result<u64, String>{
   match compression {
+    // Auto detects .xz and legacy .lzma streams
     "LZMA" => {
       let mut reader = lzma::LzmaReader::new_decompressor(input_stream)
         .map_err(|e| format!("at starting lzma reader: {e}"))?;
@@ -29,20 +33,71 @@ pub fn decompress_data<R: Read, W: Write>(
       output_stream.flush().map_err(|e| format!("at flushing: {e}"))?;
       Ok(size)
     },
-    _ => {
-      return Err("unknown compression type".to_string());
-    }
+    "BZIP2" => {
+      let mut reader = bzip2::read::BzDecoder::new(input_stream);
+      let size = io::copy(&mut reader, output_stream)
+        .map_err(|e| format!("at decompressing: {e}"))?;
+      output_stream.flush().map_err(|e| format!("at flushing: {e}"))?;
+      Ok(size)
+    },
+    "SNAPPY" => {
+      let mut reader = snap::read::FrameDecoder::new(input_stream);
+      let size = io::copy(&mut reader, output_stream)
+        .map_err(|e| format!("at decompressing: {e}"))?;
+      output_stream.flush().map_err(|e| format!("at flushing: {e}"))?;
+      Ok(size)
+    },
+    "NONE" => {
+      let mut reader = input_stream;
+      let size = io::copy(&mut reader, output_stream)
+        .map_err(|e| format!("at copying: {e}"))?;
+      output_stream.flush().map_err(|e| format!("at flushing: {e}"))?;
+      Ok(size)
+    },
+    _ => Err("unknown compression type".to_string()),
   }
 }
 
+/// Level `compress_data` compresses with for `level`, None for the types without levels
+pub fn effective_level(compression: &str, level: Option<u32>) -> Option<u32> {
+  match compression {
+    "LZMA" | "BZIP2" => Some(level.unwrap_or(9)),
+    "ZSTD" => Some(level.unwrap_or(6)),
+    _ => None,
+  }
+}
+
+/// Check that `level` is one `compression` accepts. Types without levels take any
+pub fn check_level(compression: &str, level: Option<u32>) -> Result<(), String> {
+  let range = match compression {
+    "LZMA" => 0..=9,
+    "BZIP2" => 1..=9,
+    "ZSTD" => 1..=22,
+    _ => return Ok(()),
+  };
+  match level {
+    Some(level) if !range.contains(&level) => Err(format!(
+      "{compression} level must be within {}..={}, got {level}",
+      range.start(),
+      range.end()
+    )),
+    _ => Ok(()),
+  }
+}
+
+/// Compress `input_data` into `output_stream`. `level` falls back to the per type default when
+/// not given and is ignored by LZ4, SNAPPY and NONE
 pub fn compress_data<R: Read, W: Write>(
   mut input_data: R,
   output_stream: &mut W,
-  compression: &str
+  compression: &str,
+  level: Option<u32>,
 ) -> Result<u64, String> {
   match compression {
+    // liblzma's easy encoder, which writes standard .xz streams rather than legacy .lzma ones
     "LZMA" => {
-      let mut writer = lzma::LzmaWriter::new_compressor(output_stream, 9)
+      let level = effective_level(compression, level).unwrap_or_default();
+      let mut writer = lzma::LzmaWriter::new_compressor(output_stream, level)
         .map_err(|e| format!("at starting lzma writer: {e}"))?;
       let size = io::copy(&mut input_data, &mut writer)
         .map_err(|e| format!("at compressing: {e}"))?;
@@ -57,15 +112,100 @@ pub fn compress_data<R: Read, W: Write>(
       Ok(size)
     },
     "ZSTD" => {
-      let mut writer = zstd::stream::Encoder::new(output_stream, 6)
+      let level = effective_level(compression, level).unwrap_or_default();
+      let mut writer = zstd::stream::Encoder::new(output_stream, level as i32)
         .map_err(|e| format!("at initializing zstd compressor: {e}"))?;
       let size = io::copy(&mut input_data, &mut writer)
         .map_err(|e| format!("at compressing: {e}"))?;
       writer.finish().map_err(|e| format!("at finishing: {e}"))?;
       Ok(size)
     },
-    _ => {
-      return Err("unknown compression type".to_string());
+    "BZIP2" => {
+      let level = effective_level(compression, level).unwrap_or_default();
+      let level = bzip2::Compression::try_new(level)
+        .ok_or(format!("BZIP2 level must be within 1..=9, got {level}"))?;
+      let mut writer = bzip2::write::BzEncoder::new(output_stream, level);
+      let size = io::copy(&mut input_data, &mut writer)
+        .map_err(|e| format!("at compressing: {e}"))?;
+      writer.finish().map_err(|e| format!("at finishing: {e}"))?;
+      Ok(size)
+    },
+    "SNAPPY" => {
+      let mut writer = snap::write::FrameEncoder::new(output_stream);
+      let size = io::copy(&mut input_data, &mut writer)
+        .map_err(|e| format!("at compressing: {e}"))?;
+      writer.flush().map_err(|e| format!("at flushing: {e}"))?;
+      Ok(size)
+    },
+    // Stored as is, for data that's already compressed
+    "NONE" => {
+      let size = io::copy(&mut input_data, output_stream)
+        .map_err(|e| format!("at copying: {e}"))?;
+      output_stream.flush().map_err(|e| format!("at flushing: {e}"))?;
+      Ok(size)
+    },
+    _ => Err("unknown compression type".to_string()),
+  }
+}
+/// One byte id of a compression type, used where the name doesn't fit the on-disk format
+pub fn compression_id(compression: &str) -> Result<u8, String> {
+  match compression {
+    "LZ4" => Ok(1),
+    "ZSTD" => Ok(2),
+    "LZMA" => Ok(3),
+    "SNAPPY" => Ok(4),
+    "BZIP2" => Ok(5),
+    "NONE" => Ok(6),
+    _ => Err(format!("unknown compression type {compression}")),
+  }
+}
+
+pub fn compression_name(id: u8) -> Result<&'static str, String> {
+  match id {
+    1 => Ok("LZ4"),
+    2 => Ok("ZSTD"),
+    3 => Ok("LZMA"),
+    4 => Ok("SNAPPY"),
+    5 => Ok("BZIP2"),
+    6 => Ok("NONE"),
+    _ => Err(format!("unknown compression id {id}")),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn round_trip(compression: &str, level: Option<u32>) -> Result<Vec<u8>, String> {
+    let data = b"some data that repeats, some data that repeats".repeat(100);
+    let mut compressed = vec![];
+    compress_data(&data[..], &mut compressed, compression, level)?;
+    let mut decompressed = vec![];
+    let size = decompress_data(&compressed[..], &mut decompressed, compression)?;
+    assert_eq!(size, data.len() as u64);
+    assert_eq!(decompressed, data);
+    Ok(compressed)
+  }
+
+  #[test]
+  fn snappy_round_trip(){
+    let compressed = round_trip("SNAPPY", None).unwrap();
+    // Snappy frame streams start with their stream identifier chunk
+    assert!(compressed.starts_with(b"\xff\x06\x00\x00sNaPpY"));
+  }
+
+  #[test]
+  fn bzip2_round_trip(){
+    round_trip("BZIP2", None).unwrap();
+    round_trip("BZIP2", Some(1)).unwrap();
+  }
+
+  #[test]
+  fn out_of_range_levels_fail(){
+    for (compression, level) in [("BZIP2", 0), ("BZIP2", 10), ("LZMA", 10), ("ZSTD", 23)] {
+      assert!(check_level(compression, Some(level)).is_err(), "{compression} {level}");
     }
+    assert!(round_trip("BZIP2", Some(10)).is_err());
+    assert!(check_level("LZ4", Some(100)).is_ok());
   }
-}
\ No newline at end of file
+}
@@ -0,0 +1,108 @@
+use std::{io, path::Path};
+
+/// Flags of `path`, like immutable and append-only. On Linux only the ones chattr can change
+/// are returned, the rest describe how the filesystem stores the file
+pub fn get_flags(path: &Path) -> io::Result<u32>{
+  imp::get_flags(path)
+}
+
+/// Give `path` the flags `flags`, as returned by `get_flags`
+pub fn set_flags(path: &Path, flags: u32) -> io::Result<()>{
+  imp::set_flags(path, flags)
+}
+
+/// Whether `error` from these means flags can't be used here at all, because of the platform,
+/// the filesystem or missing privileges
+pub fn is_unsupported(error: &io::Error) -> bool{
+  matches!(error.kind(), io::ErrorKind::PermissionDenied | io::ErrorKind::Unsupported)
+}
+
+#[cfg(target_os = "linux")]
+mod imp {
+  use std::{fs, io, os::fd::AsRawFd, path::Path};
+
+  /// FS_FL_USER_MODIFIABLE of the kernel
+  const USER_MODIFIABLE: u32 = 0x000380FF;
+
+  fn ioctl_flags(
+    file: &fs::File,
+    request: libc::Ioctl,
+    flags: &mut libc::c_int
+  ) -> io::Result<()>{
+    // SAFETY: the fd stays open for the call and the kernel reads and writes an int at `flags`
+    if unsafe { libc::ioctl(file.as_raw_fd(), request, flags as *mut libc::c_int) } < 0 {
+      let error = io::Error::last_os_error();
+      // Filesystems without flags don't know the ioctl
+      if error.raw_os_error() == Some(libc::ENOTTY) {
+        return Err(io::ErrorKind::Unsupported.into());
+      }
+      return Err(error);
+    }
+    Ok(())
+  }
+
+  pub fn get_flags(path: &Path) -> io::Result<u32>{
+    let file = fs::File::open(path)?;
+    let mut flags = 0;
+    ioctl_flags(&file, libc::FS_IOC_GETFLAGS, &mut flags)?;
+    Ok(flags as u32 & USER_MODIFIABLE)
+  }
+
+  pub fn set_flags(path: &Path, flags: u32) -> io::Result<()>{
+    let file = fs::File::open(path)?;
+    let mut current = 0;
+    ioctl_flags(&file, libc::FS_IOC_GETFLAGS, &mut current)?;
+    let mut new_flags = ((current as u32 & !USER_MODIFIABLE) | (flags & USER_MODIFIABLE)) as _;
+    ioctl_flags(&file, libc::FS_IOC_SETFLAGS, &mut new_flags)
+  }
+}
+
+#[cfg(any(
+  target_os = "macos",
+  target_os = "freebsd",
+  target_os = "netbsd",
+  target_os = "openbsd",
+  target_os = "dragonfly"
+))]
+mod imp {
+  use std::{ffi::CString, io, os::unix::ffi::OsStrExt, path::Path};
+
+  pub fn get_flags(path: &Path) -> io::Result<u32>{
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: stat only writes to `stat`, which is plain data
+    let mut stat = unsafe { std::mem::zeroed::<libc::stat>() };
+    if unsafe { libc::stat(c_path.as_ptr(), &mut stat) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(stat.st_flags as u32)
+  }
+
+  pub fn set_flags(path: &Path, flags: u32) -> io::Result<()>{
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    // SAFETY: `c_path` is a valid nul terminated string for the call
+    if unsafe { libc::chflags(c_path.as_ptr(), flags as _) } < 0 {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+}
+
+#[cfg(not(any(
+  target_os = "linux",
+  target_os = "macos",
+  target_os = "freebsd",
+  target_os = "netbsd",
+  target_os = "openbsd",
+  target_os = "dragonfly"
+)))]
+mod imp {
+  use std::{io, path::Path};
+
+  pub fn get_flags(_path: &Path) -> io::Result<u32>{
+    Err(io::ErrorKind::Unsupported.into())
+  }
+
+  pub fn set_flags(_path: &Path, _flags: u32) -> io::Result<()>{
+    Err(io::ErrorKind::Unsupported.into())
+  }
+}
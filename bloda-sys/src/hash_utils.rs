@@ -0,0 +1,129 @@
+use std::{
+  fs,
+  io::{self, Read, Write},
+  path::Path,
+  str::FromStr,
+};
+
+use sha2::Digest;
+
+/// Algorithm used for stored file hashes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum HashAlgorithm {
+  #[default]
+  Blake3,
+  Sha256,
+  Xxh3,
+}
+
+impl HashAlgorithm {
+  pub fn as_str(&self) -> &'static str {
+    match self {
+      Self::Blake3 => "blake3",
+      Self::Sha256 => "sha256",
+      Self::Xxh3 => "xxh3",
+    }
+  }
+}
+
+impl FromStr for HashAlgorithm {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "blake3" => Ok(Self::Blake3),
+      "sha256" => Ok(Self::Sha256),
+      "xxh3" => Ok(Self::Xxh3),
+      _ => Err(format!("unknown hash algorithm {s}, expected blake3, sha256 or xxh3")),
+    }
+  }
+}
+
+enum Hasher {
+  Blake3(Box<blake3::Hasher>),
+  Sha256(sha2::Sha256),
+  Xxh3(Box<xxhash_rust::xxh3::Xxh3>),
+}
+
+impl Hasher {
+  fn new(algorithm: HashAlgorithm) -> Self {
+    match algorithm {
+      HashAlgorithm::Blake3 => Self::Blake3(Box::new(blake3::Hasher::new())),
+      HashAlgorithm::Sha256 => Self::Sha256(sha2::Sha256::new()),
+      HashAlgorithm::Xxh3 => Self::Xxh3(Box::new(xxhash_rust::xxh3::Xxh3::new())),
+    }
+  }
+
+  fn update(&mut self, data: &[u8]) {
+    match self {
+      Self::Blake3(h) => {
+        h.update(data);
+      }
+      Self::Sha256(h) => h.update(data),
+      Self::Xxh3(h) => h.update(data),
+    }
+  }
+
+  fn finish(self) -> String {
+    match self {
+      Self::Blake3(h) => h.finalize().to_hex().to_string(),
+      Self::Sha256(h) => h.finalize().iter().map(|b| format!("{b:02x}")).collect(),
+      Self::Xxh3(h) => format!("{:032x}", h.digest128()),
+    }
+  }
+}
+
+/// Writer passing everything through to `inner`, hashing it on the way when given an algorithm
+pub struct HashingWriter<W> {
+  inner: W,
+  hasher: Option<Hasher>,
+}
+
+impl<W: Write> HashingWriter<W> {
+  pub fn new(inner: W, algorithm: Option<HashAlgorithm>) -> Self {
+    Self { inner, hasher: algorithm.map(Hasher::new) }
+  }
+
+  /// Hash of everything written, None without an algorithm
+  pub fn finish(self) -> Option<String> {
+    self.hasher.map(Hasher::finish)
+  }
+}
+
+impl<W: Write> Write for HashingWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    let written = self.inner.write(buf)?;
+    if let Some(hasher) = &mut self.hasher {
+      hasher.update(&buf[..written]);
+    }
+    Ok(written)
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+pub fn hash_reader<R: Read>(mut reader: R, algorithm: HashAlgorithm) -> Result<String, String>{
+  let mut hasher = Hasher::new(algorithm);
+  let mut buffer = vec![0u8; 64 * 1024];
+  loop {
+    let read_len = reader.read(&mut buffer).map_err(|e| format!("at reading: {e}"))?;
+    if read_len == 0 {
+      break;
+    }
+    hasher.update(&buffer[..read_len]);
+  }
+  Ok(hasher.finish())
+}
+
+pub fn hash_bytes(data: &[u8], algorithm: HashAlgorithm) -> String{
+  let mut hasher = Hasher::new(algorithm);
+  hasher.update(data);
+  hasher.finish()
+}
+
+pub fn hash_file(path: &Path, algorithm: HashAlgorithm) -> Result<String, String>{
+  let fr = fs::File::open(path).map_err(|e| format!("at opening {path:?}: {e}"))?;
+  hash_reader(fr, algorithm).map_err(|e| format!("at hashing {path:?}: {e}"))
+}
@@ -0,0 +1,167 @@
+use std::io::{Read, Write};
+
+use crate::compress_utils;
+
+pub const MAGIC: [u8; 4] = *b"BLDA";
+pub const FORMAT_VERSION: u32 = 3;
+/// First format version whose blocks start with the one byte id of their compression
+pub const BLOCK_MARKER_VERSION: u32 = 2;
+/// First format version with feature flags in the header
+pub const FEATURE_FLAGS_VERSION: u32 = 3;
+
+/// Feature flags, recording which optional parts of the format an archive uses
+pub const FEATURE_HASHES: u32 = 1 << 0;
+pub const FEATURE_RECOVERABLE: u32 = 1 << 1;
+pub const FEATURE_OWNERS: u32 = 1 << 2;
+pub const FEATURE_HARDLINKS: u32 = 1 << 3;
+pub const FEATURE_FILE_FLAGS: u32 = 1 << 4;
+const FEATURE_NAMES: [(u32, &str); 5] = [
+  (FEATURE_HASHES, "hashes"),
+  (FEATURE_RECOVERABLE, "recoverable"),
+  (FEATURE_OWNERS, "owners"),
+  (FEATURE_HARDLINKS, "hardlinks"),
+  (FEATURE_FILE_FLAGS, "file-flags"),
+];
+
+/// Fixed size header at the start of an archive, followed by the compressed index and the blob.
+///
+/// Layout: magic, big endian u32 format version, index compression id, big endian u64 index
+/// length. Archives from before the header only have the index length and an LZ4 index, they're
+/// read as version 0. From version 2 every block starts with its compression id, see
+/// `BLOCK_MARKER_VERSION`. Version 3 adds big endian u32 feature flags after the index length
+#[derive(Debug, Clone)]
+pub struct ArchiveHeader {
+  pub version: u32,
+  pub index_compression: String,
+  pub index_len: u64,
+  /// `FEATURE_*` bits, 0 for archives from before version 3
+  pub feature_flags: u32,
+}
+
+impl ArchiveHeader {
+  pub fn new(index_compression: &str, index_len: u64, feature_flags: u32) -> Self {
+    Self {
+      version: FORMAT_VERSION,
+      index_compression: index_compression.to_string(),
+      index_len,
+      feature_flags,
+    }
+  }
+
+  /// Names of the features the archive uses
+  pub fn feature_names(&self) -> Vec<&'static str> {
+    FEATURE_NAMES
+      .iter()
+      .filter(|(flag, _)| self.feature_flags & flag != 0)
+      .map(|(_, name)| *name)
+      .collect()
+  }
+
+  pub fn has_block_markers(&self) -> bool {
+    self.version >= BLOCK_MARKER_VERSION
+  }
+
+  /// Size of the header on disk, i.e. the offset of the index
+  pub fn size(&self) -> u64 {
+    match self.version {
+      0 => 8,
+      x if x < FEATURE_FLAGS_VERSION => 17,
+      _ => 21,
+    }
+  }
+
+  /// Only the format version from the start of a header, newer versions included. 0 for
+  /// archives from before the header
+  pub fn read_version<R: Read>(mut reader: R) -> Result<u32, String> {
+    let mut start = [0u8; 8];
+    reader.read_exact(&mut start).map_err(|e| format!("at reading header: {e}"))?;
+    if start[..4] != MAGIC {
+      return Ok(0);
+    }
+    Ok(u32::from_be_bytes([start[4], start[5], start[6], start[7]]))
+  }
+
+  pub fn read<R: Read>(mut reader: R) -> Result<Self, String> {
+    let mut start = [0u8; 8];
+    reader.read_exact(&mut start).map_err(|e| format!("at reading header: {e}"))?;
+    if start[..4] != MAGIC {
+      return Ok(Self {
+        version: 0,
+        index_compression: "LZ4".to_string(),
+        index_len: u64::from_be_bytes(start),
+        feature_flags: 0,
+      });
+    }
+    let version = u32::from_be_bytes([start[4], start[5], start[6], start[7]]);
+    if version > FORMAT_VERSION {
+      return Err(format!("unsupported archive format version {version}"));
+    }
+    let mut rest = [0u8; 9];
+    reader.read_exact(&mut rest).map_err(|e| format!("at reading header: {e}"))?;
+    let index_compression = compress_utils::compression_name(rest[0])?.to_string();
+    let mut index_len_bytes = [0u8; 8];
+    index_len_bytes.copy_from_slice(&rest[1..]);
+    let mut feature_flags = 0;
+    if version >= FEATURE_FLAGS_VERSION {
+      let mut flag_bytes = [0u8; 4];
+      reader.read_exact(&mut flag_bytes).map_err(|e| format!("at reading header: {e}"))?;
+      feature_flags = u32::from_be_bytes(flag_bytes);
+    }
+    let known_flags = FEATURE_NAMES.iter().fold(0, |acc, (flag, _)| acc | flag);
+    if feature_flags & !known_flags != 0 {
+      return Err(format!(
+        "archive uses features this version of bloda doesn't support (flags {:#x})",
+        feature_flags & !known_flags
+      ));
+    }
+    Ok(Self {
+      version,
+      index_compression,
+      index_len: u64::from_be_bytes(index_len_bytes),
+      feature_flags,
+    })
+  }
+
+  pub fn write<W: Write>(&self, mut writer: W) -> Result<(), String> {
+    let mut header = Vec::with_capacity(self.size() as usize);
+    header.extend_from_slice(&MAGIC);
+    header.extend_from_slice(&self.version.to_be_bytes());
+    header.push(compress_utils::compression_id(&self.index_compression)?);
+    header.extend_from_slice(&self.index_len.to_be_bytes());
+    if self.version >= FEATURE_FLAGS_VERSION {
+      header.extend_from_slice(&self.feature_flags.to_be_bytes());
+    }
+    writer.write_all(&header).map_err(|e| format!("at writing header: {e}"))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn written(header: &ArchiveHeader) -> Vec<u8> {
+    let mut data = vec![];
+    header.write(&mut data).unwrap();
+    data
+  }
+
+  #[test]
+  fn feature_flags_round_trip(){
+    let header = ArchiveHeader::new("ZSTD", 42, FEATURE_HASHES | FEATURE_HARDLINKS);
+    let data = written(&header);
+    assert_eq!(data.len() as u64, header.size());
+
+    let read = ArchiveHeader::read(&data[..]).unwrap();
+    assert_eq!(read.feature_flags, FEATURE_HASHES | FEATURE_HARDLINKS);
+    assert_eq!(read.feature_names(), ["hashes", "hardlinks"]);
+    assert_eq!(read.index_len, 42);
+  }
+
+  #[test]
+  fn unknown_features_fail_to_open(){
+    // A feature from a newer version, or one this build doesn't have
+    let header = ArchiveHeader::new("ZSTD", 42, FEATURE_HASHES | 1 << 20);
+    let err = ArchiveHeader::read(&written(&header)[..]).unwrap_err();
+    assert_eq!(err, "archive uses features this version of bloda doesn't support (flags 0x100000)");
+  }
+}
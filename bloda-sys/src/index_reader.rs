@@ -0,0 +1,503 @@
+use std::path::Path;
+
+use crate::sql_structs::{
+  ArchiveBlockInfo,
+  ArchiveBlockLevel,
+  ArchiveEntryOrder,
+  ArchiveFileEntry,
+  ArchiveFileFlags,
+  ArchiveFileHash,
+  ArchiveFileHashV1,
+  ArchiveFolderLeafEntry,
+  ArchiveHashAlgorithm,
+  ArchiveHardlink,
+  ArchiveIndexSchema,
+  ArchiveMetadataEntry,
+  ArchiveOwner,
+  INDEX_SCHEMA_VERSION
+};
+
+/// Tables of an archive's index DB
+pub struct IndexTables {
+  pub files: Vec<ArchiveFileEntry>,
+  pub folder_leaves: Vec<ArchiveFolderLeafEntry>,
+  /// Ordered by id
+  pub blocks: Vec<ArchiveBlockInfo>,
+  /// Algorithm of every hash in `file_hashes`, None if there are none
+  pub hash_algorithm: Option<String>,
+  /// Empty for archives created without hashing, which may not have the table at all
+  pub file_hashes: Vec<ArchiveFileHash>,
+  /// Empty for archives from before metadata was supported
+  pub metadata: Vec<ArchiveMetadataEntry>,
+  /// Empty for archives from before the listing order was stored
+  pub entry_order: Vec<ArchiveEntryOrder>,
+  /// Empty for archives created without storing ownership
+  pub owners: Vec<ArchiveOwner>,
+  /// Empty for archives created without hardlink detection
+  pub hardlinks: Vec<ArchiveHardlink>,
+  /// Empty for archives created without storing file flags
+  pub file_flags: Vec<ArchiveFileFlags>,
+  /// Empty for archives from before levels were stored
+  pub block_levels: Vec<ArchiveBlockLevel>,
+}
+
+/// Version of the `index_schema` rows, None for archives from before the schema was versioned.
+/// Fails for versions newer than this build reads
+fn check_schema_version(rows: &[ArchiveIndexSchema]) -> Result<Option<i64>, String>{
+  let version = match rows {
+    [] => None,
+    [row] => Some(row.version),
+    _ => return Err(format!("index schema has {} versions", rows.len())),
+  };
+  if let Some(version) = version.filter(|x| *x > INDEX_SCHEMA_VERSION) {
+    return Err(format!("unsupported index schema v{version}"));
+  }
+  Ok(version)
+}
+
+/// Error for a query on the index that failed, which for a DB that opened fine means its
+/// tables aren't laid out the way this build expects
+fn schema_mismatch(e: String) -> String{
+  format!("unsupported index schema: {e}")
+}
+
+/// Split the per-row algorithms of index schema v1 out of its hashes. They were always all the
+/// same, anything else is a broken index
+fn split_v1_hashes(
+  rows: Vec<ArchiveFileHashV1>
+) -> Result<(Vec<ArchiveHashAlgorithm>, Vec<ArchiveFileHash>), String>{
+  let mut algorithms = rows.iter().map(|x| x.algorithm.as_str()).collect::<Vec<_>>();
+  algorithms.sort();
+  algorithms.dedup();
+  if algorithms.len() > 1 {
+    return Err(format!("file hashes mix the algorithms {}", algorithms.join(", ")));
+  }
+  let algorithms = algorithms
+    .into_iter()
+    .map(|x| ArchiveHashAlgorithm { algorithm: x.to_string() })
+    .collect();
+  let hashes = rows.into_iter().map(|x| ArchiveFileHash { name: x.name, hash: x.hash }).collect();
+  Ok((algorithms, hashes))
+}
+
+/// The single algorithm of `file_hashes`, if there are any
+fn check_hash_algorithm(
+  rows: Vec<ArchiveHashAlgorithm>,
+  file_hashes: &[ArchiveFileHash]
+) -> Result<Option<String>, String>{
+  match (rows.as_slice(), file_hashes.is_empty()) {
+    (_, true) => Ok(None),
+    ([row], false) => Ok(Some(row.algorithm.clone())),
+    ([], false) => Err("file hashes without a hash algorithm".to_string()),
+    (_, false) => Err(format!("file hashes with {} hash algorithms", rows.len())),
+  }
+}
+
+// Without either backend the crate fails to build on a compile_error!, these only keep that
+// from being buried under errors about the missing backend
+#[cfg(not(any(feature = "writer", feature = "lite-reader")))]
+pub fn load_index(_db_path: &Path) -> Result<IndexTables, String>{
+  unreachable!("no index backend enabled")
+}
+
+#[cfg(not(any(feature = "writer", feature = "lite-reader")))]
+pub fn load_index_from_bytes(_data: &[u8]) -> Result<IndexTables, String>{
+  unreachable!("no index backend enabled")
+}
+
+#[cfg(all(feature = "writer", not(feature = "lite-reader")))]
+pub fn load_index(db_path: &Path) -> Result<IndexTables, String>{
+  use diesel::Connection;
+
+  let mut conn = diesel::SqliteConnection::establish(&db_path.to_string_lossy())
+    .map_err(|e| format!("at opening {db_path:?}: {e}"))?;
+  load_tables(&mut conn)
+}
+
+/// Load the index DB `data` without writing it anywhere
+#[cfg(all(feature = "writer", not(feature = "lite-reader")))]
+pub fn load_index_from_bytes(data: &[u8]) -> Result<IndexTables, String>{
+  use diesel::Connection;
+
+  let mut conn = diesel::SqliteConnection::establish(":memory:")
+    .map_err(|e| format!("at opening in-memory index db: {e}"))?;
+  conn
+    .deserialize_readonly_database_from_buffer(data)
+    .map_err(|e| format!("at loading index db into memory: {e}"))?;
+  load_tables(&mut conn)
+}
+
+#[cfg(all(feature = "writer", not(feature = "lite-reader")))]
+fn load_tables(conn: &mut diesel::SqliteConnection) -> Result<IndexTables, String>{
+  use diesel::{ExpressionMethods, QueryDsl, QueryResult, RunQueryDsl, SelectableHelper};
+
+  use crate::sql_structs;
+
+  fn has_table(conn: &mut diesel::SqliteConnection, table: &str) -> Result<bool, String>{
+    sql_structs::sqlite_master::table
+      .filter(sql_structs::sqlite_master::kind.eq("table"))
+      .filter(sql_structs::sqlite_master::name.eq(table))
+      .count()
+      .get_result::<i64>(conn)
+      .map(|x| x > 0)
+      .map_err(|e| format!("at looking for table {table}: {e}"))
+  }
+
+  /// Rows of a table older archives may not have, none if it's absent
+  fn load_optional<T>(
+    conn: &mut diesel::SqliteConnection,
+    table: &str,
+    load: impl FnOnce(&mut diesel::SqliteConnection) -> QueryResult<Vec<T>>,
+  ) -> Result<Vec<T>, String>{
+    if !has_table(conn, table)? {
+      return Ok(vec![]);
+    }
+    load(conn).map_err(|e| schema_mismatch(format!("at getting {table}: {e}")))
+  }
+
+  let schema_version = load_optional(conn, "index_schema", |conn| {
+    sql_structs::index_schema::table.select(ArchiveIndexSchema::as_select()).load(conn)
+  })?;
+  let schema_version = check_schema_version(&schema_version)?;
+  let files = sql_structs::files::table
+    .select(ArchiveFileEntry::as_select())
+    .load(conn)
+    .map_err(|e| schema_mismatch(format!("at getting file infos: {e}")))?;
+  let folder_leaves = sql_structs::folder_leaves::table
+    .select(ArchiveFolderLeafEntry::as_select())
+    .load(conn)
+    .map_err(|e| schema_mismatch(format!("at getting folder leaf infos: {e}")))?;
+  let blocks = sql_structs::blocks::table
+    .select(ArchiveBlockInfo::as_select())
+    .order(sql_structs::blocks::id.asc())
+    .load(conn)
+    .map_err(|e| schema_mismatch(format!("at getting block infos: {e}")))?;
+  let (hash_algorithm, file_hashes) = if schema_version.is_some_and(|x| x >= 2) {
+    let hash_algorithm = load_optional(conn, "hash_algorithm", |conn| {
+      sql_structs::hash_algorithm::table.select(ArchiveHashAlgorithm::as_select()).load(conn)
+    })?;
+    let file_hashes = load_optional(conn, "file_hashes", |conn| {
+      sql_structs::file_hashes::table.select(ArchiveFileHash::as_select()).load(conn)
+    })?;
+    (hash_algorithm, file_hashes)
+  } else {
+    let file_hashes = load_optional(conn, "file_hashes", |conn| {
+      sql_structs::file_hashes_v1::table.select(ArchiveFileHashV1::as_select()).load(conn)
+    })?;
+    split_v1_hashes(file_hashes)?
+  };
+  let hash_algorithm = check_hash_algorithm(hash_algorithm, &file_hashes)?;
+  let metadata = load_optional(conn, "metadata", |conn| {
+    sql_structs::metadata::table.select(ArchiveMetadataEntry::as_select()).load(conn)
+  })?;
+  let entry_order = load_optional(conn, "entry_order", |conn| {
+    sql_structs::entry_order::table.select(ArchiveEntryOrder::as_select()).load(conn)
+  })?;
+  let owners = load_optional(conn, "owners", |conn| {
+    sql_structs::owners::table.select(ArchiveOwner::as_select()).load(conn)
+  })?;
+  let hardlinks = load_optional(conn, "hardlinks", |conn| {
+    sql_structs::hardlinks::table.select(ArchiveHardlink::as_select()).load(conn)
+  })?;
+  let file_flags = load_optional(conn, "file_flags", |conn| {
+    sql_structs::file_flags::table.select(ArchiveFileFlags::as_select()).load(conn)
+  })?;
+  let block_levels = load_optional(conn, "block_levels", |conn| {
+    sql_structs::block_levels::table.select(ArchiveBlockLevel::as_select()).load(conn)
+  })?;
+  Ok(IndexTables {
+    files,
+    folder_leaves,
+    blocks,
+    hash_algorithm,
+    file_hashes,
+    metadata,
+    entry_order,
+    owners,
+    hardlinks,
+    file_flags,
+    block_levels
+  })
+}
+
+#[cfg(feature = "lite-reader")]
+pub fn load_index(db_path: &Path) -> Result<IndexTables, String>{
+  let conn = rusqlite::Connection::open_with_flags(
+    db_path,
+    rusqlite::OpenFlags::SQLITE_OPEN_READ_ONLY
+  )
+    .map_err(|e| format!("at opening {db_path:?}: {e}"))?;
+  load_tables(&conn)
+}
+
+/// Load the index DB `data` without writing it anywhere
+#[cfg(feature = "lite-reader")]
+pub fn load_index_from_bytes(data: &[u8]) -> Result<IndexTables, String>{
+  let mut conn = rusqlite::Connection::open_in_memory()
+    .map_err(|e| format!("at opening in-memory index db: {e}"))?;
+  conn
+    .deserialize_read_exact(rusqlite::MAIN_DB, data, data.len(), true)
+    .map_err(|e| format!("at loading index db into memory: {e}"))?;
+  load_tables(&conn)
+}
+
+#[cfg(feature = "lite-reader")]
+fn load_tables(conn: &rusqlite::Connection) -> Result<IndexTables, String>{
+  fn load_rows<T, F: FnMut(&rusqlite::Row) -> rusqlite::Result<T>>(
+    conn: &rusqlite::Connection,
+    query: &str,
+    map_row: F,
+  ) -> rusqlite::Result<Vec<T>>{
+    let mut statement = conn.prepare(query)?;
+    let rows = statement.query_map([], map_row)?.collect();
+    rows
+  }
+
+  /// Rows of a table older archives may not have, none if it's absent
+  fn load_optional<T, F: FnMut(&rusqlite::Row) -> rusqlite::Result<T>>(
+    conn: &rusqlite::Connection,
+    table: &str,
+    query: &str,
+    map_row: F,
+  ) -> Result<Vec<T>, String>{
+    let has_table = conn
+      .query_row(
+        "SELECT count(*) FROM sqlite_master WHERE type = 'table' AND name = ?1",
+        [table],
+        |row| row.get::<_, i64>(0)
+      )
+      .map_err(|e| format!("at looking for table {table}: {e}"))?;
+    if has_table == 0 {
+      return Ok(vec![]);
+    }
+    load_rows(conn, query, map_row)
+      .map_err(|e| schema_mismatch(format!("at getting {table}: {e}")))
+  }
+
+  let schema_version = load_optional(
+    conn,
+    "index_schema",
+    "SELECT version FROM index_schema",
+    |row| Ok(ArchiveIndexSchema { version: row.get(0)? })
+  )?;
+  let schema_version = check_schema_version(&schema_version)?;
+  let files = load_rows(conn, "SELECT name, block, offset, size FROM files", |row| {
+    Ok(ArchiveFileEntry {
+      name: row.get(0)?,
+      block: row.get(1)?,
+      offset: row.get(2)?,
+      size: row.get(3)?,
+    })
+  })
+    .map_err(|e| schema_mismatch(format!("at getting file infos: {e}")))?;
+  let folder_leaves = load_rows(conn, "SELECT name FROM folder_leaves", |row| {
+    Ok(ArchiveFolderLeafEntry { name: row.get(0)? })
+  })
+    .map_err(|e| schema_mismatch(format!("at getting folder leaf infos: {e}")))?;
+  let blocks = load_rows(
+    conn,
+    "SELECT id, size, offset, compression_type FROM blocks ORDER BY id",
+    |row| {
+      Ok(ArchiveBlockInfo {
+        id: row.get(0)?,
+        size: row.get(1)?,
+        offset: row.get(2)?,
+        compression_type: row.get(3)?,
+      })
+    }
+  )
+    .map_err(|e| schema_mismatch(format!("at getting block infos: {e}")))?;
+  let (hash_algorithm, file_hashes) = if schema_version.is_some_and(|x| x >= 2) {
+    let hash_algorithm = load_optional(
+      conn,
+      "hash_algorithm",
+      "SELECT algorithm FROM hash_algorithm",
+      |row| Ok(ArchiveHashAlgorithm { algorithm: row.get(0)? })
+    )?;
+    let file_hashes = load_optional(
+      conn,
+      "file_hashes",
+      "SELECT name, hash FROM file_hashes",
+      |row| Ok(ArchiveFileHash { name: row.get(0)?, hash: row.get(1)? })
+    )?;
+    (hash_algorithm, file_hashes)
+  } else {
+    let file_hashes = load_optional(
+      conn,
+      "file_hashes",
+      "SELECT name, hash, algorithm FROM file_hashes",
+      |row| Ok(ArchiveFileHashV1 { name: row.get(0)?, hash: row.get(1)?, algorithm: row.get(2)? })
+    )?;
+    split_v1_hashes(file_hashes)?
+  };
+  let hash_algorithm = check_hash_algorithm(hash_algorithm, &file_hashes)?;
+  let metadata = load_optional(conn, "metadata", "SELECT key, value FROM metadata", |row| {
+    Ok(ArchiveMetadataEntry { key: row.get(0)?, value: row.get(1)? })
+  })?;
+  let entry_order = load_optional(conn, "entry_order", "SELECT name, ord FROM entry_order", |row| {
+    Ok(ArchiveEntryOrder { name: row.get(0)?, ord: row.get(1)? })
+  })?;
+  let owners = load_optional(conn, "owners", "SELECT name, uid, gid FROM owners", |row| {
+    Ok(ArchiveOwner { name: row.get(0)?, uid: row.get(1)?, gid: row.get(2)? })
+  })?;
+  let hardlinks = load_optional(conn, "hardlinks", "SELECT name, target FROM hardlinks", |row| {
+    Ok(ArchiveHardlink { name: row.get(0)?, target: row.get(1)? })
+  })?;
+  let file_flags = load_optional(conn, "file_flags", "SELECT name, flags FROM file_flags", |row| {
+    Ok(ArchiveFileFlags { name: row.get(0)?, flags: row.get(1)? })
+  })?;
+  let block_levels = load_optional(
+    conn,
+    "block_levels",
+    "SELECT id, level FROM block_levels",
+    |row| Ok(ArchiveBlockLevel { id: row.get(0)?, level: row.get(1)? })
+  )?;
+  Ok(IndexTables {
+    files,
+    folder_leaves,
+    blocks,
+    hash_algorithm,
+    file_hashes,
+    metadata,
+    entry_order,
+    owners,
+    hardlinks,
+    file_flags,
+    block_levels
+  })
+}
+
+#[cfg(all(test, feature = "writer"))]
+mod tests {
+  use diesel::{Connection, RunQueryDsl};
+
+  use super::*;
+
+  /// Make an index DB at `db_path` with the tables every schema has and then run `statements`
+  fn make_index(db_path: &Path, statements: &[&str]){
+    let mut conn = diesel::SqliteConnection::establish(&db_path.to_string_lossy()).unwrap();
+    let base = [
+      "CREATE TABLE files(name TEXT PRIMARY KEY, block BIGINT, offset BIGINT, size BIGINT)",
+      "CREATE TABLE folder_leaves(name TEXT PRIMARY KEY)",
+      "CREATE TABLE blocks(
+        id BIGINT PRIMARY KEY,
+        size BIGINT,
+        offset BIGINT,
+        compression_type TEXT)",
+      "INSERT INTO files VALUES ('a', 0, 0, 1), ('b', 0, 1, 1)",
+      "INSERT INTO blocks VALUES (0, 10, 0, 'NONE')",
+    ];
+    for statement in base.iter().chain(statements) {
+      diesel::sql_query(*statement).execute(&mut conn).unwrap();
+    }
+  }
+
+  #[test]
+  fn hash_algorithm_is_read_once(){
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("index.db");
+    make_index(&db_path, &[
+      "CREATE TABLE index_schema(version BIGINT PRIMARY KEY)",
+      "INSERT INTO index_schema VALUES (2)",
+      "CREATE TABLE file_hashes(name TEXT PRIMARY KEY, hash TEXT)",
+      "CREATE TABLE hash_algorithm(algorithm TEXT PRIMARY KEY)",
+      "INSERT INTO file_hashes VALUES ('a', 'h1'), ('b', 'h2')",
+      "INSERT INTO hash_algorithm VALUES ('sha256')",
+    ]);
+    let tables = load_index(&db_path).unwrap();
+    assert_eq!(tables.hash_algorithm.as_deref(), Some("sha256"));
+    assert_eq!(tables.file_hashes.len(), 2);
+  }
+
+  #[test]
+  fn hashes_need_an_algorithm(){
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("index.db");
+    make_index(&db_path, &[
+      "CREATE TABLE index_schema(version BIGINT PRIMARY KEY)",
+      "INSERT INTO index_schema VALUES (2)",
+      "CREATE TABLE file_hashes(name TEXT PRIMARY KEY, hash TEXT)",
+      "INSERT INTO file_hashes VALUES ('a', 'h1')",
+    ]);
+    assert!(load_index(&db_path).is_err());
+  }
+
+  #[test]
+  fn v1_hashes_with_per_row_algorithm(){
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("index.db");
+    make_index(&db_path, &[
+      "CREATE TABLE index_schema(version BIGINT PRIMARY KEY)",
+      "INSERT INTO index_schema VALUES (1)",
+      "CREATE TABLE file_hashes(name TEXT PRIMARY KEY, hash TEXT, algorithm TEXT)",
+      "INSERT INTO file_hashes VALUES ('a', 'h1', 'xxh3'), ('b', 'h2', 'xxh3')",
+    ]);
+    let tables = load_index(&db_path).unwrap();
+    assert_eq!(tables.hash_algorithm.as_deref(), Some("xxh3"));
+    assert_eq!(tables.file_hashes.len(), 2);
+
+    let mixed_path = dir.path().join("mixed.db");
+    make_index(&mixed_path, &[
+      "CREATE TABLE file_hashes(name TEXT PRIMARY KEY, hash TEXT, algorithm TEXT)",
+      "INSERT INTO file_hashes VALUES ('a', 'h1', 'xxh3'), ('b', 'h2', 'blake3')",
+    ]);
+    assert!(load_index(&mixed_path).is_err());
+  }
+
+  #[test]
+  fn absent_optional_tables_are_empty(){
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("index.db");
+    make_index(&db_path, &[]);
+    let tables = load_index(&db_path).unwrap();
+    assert_eq!(tables.files.len(), 2);
+    assert!(tables.file_hashes.is_empty());
+    assert!(tables.hash_algorithm.is_none());
+    assert!(tables.owners.is_empty());
+    assert!(tables.block_levels.is_empty());
+  }
+
+  #[test]
+  fn broken_optional_tables_fail(){
+    let dir = tempfile::tempdir().unwrap();
+    for (i, statement) in [
+      "CREATE TABLE owners(name TEXT PRIMARY KEY, uid BIGINT)",
+      "CREATE TABLE hardlinks(name TEXT PRIMARY KEY)",
+      "CREATE TABLE file_flags(name TEXT PRIMARY KEY)",
+      "CREATE TABLE block_levels(id BIGINT PRIMARY KEY)",
+      "CREATE TABLE metadata(key TEXT PRIMARY KEY)",
+    ].iter().enumerate() {
+      let db_path = dir.path().join(format!("index{i}.db"));
+      make_index(&db_path, &[statement]);
+      assert!(load_index(&db_path).is_err(), "{statement}");
+    }
+  }
+
+  #[test]
+  fn newer_schema_is_unsupported(){
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("index.db");
+    make_index(&db_path, &[
+      "CREATE TABLE index_schema(version BIGINT PRIMARY KEY)",
+      &format!("INSERT INTO index_schema VALUES ({})", INDEX_SCHEMA_VERSION + 1),
+    ]);
+    let err = load_index(&db_path).err().unwrap();
+    assert!(err.contains("unsupported index schema"), "{err}");
+  }
+
+  #[test]
+  fn mismatched_columns_are_an_unsupported_schema(){
+    let dir = tempfile::tempdir().unwrap();
+    let db_path = dir.path().join("index.db");
+    let mut conn = diesel::SqliteConnection::establish(&db_path.to_string_lossy()).unwrap();
+    for statement in [
+      "CREATE TABLE files(name TEXT PRIMARY KEY, block_id BIGINT, start BIGINT)",
+      "CREATE TABLE folder_leaves(name TEXT PRIMARY KEY)",
+      "CREATE TABLE blocks(id BIGINT PRIMARY KEY, size BIGINT)",
+    ] {
+      diesel::sql_query(statement).execute(&mut conn).unwrap();
+    }
+    let err = load_index(&db_path).err().unwrap();
+    assert!(err.starts_with("unsupported index schema"), "{err}");
+  }
+}
@@ -1,79 +1,700 @@
-use std::{collections::{HashMap, HashSet}, fs, io::{self, Read, Seek, Write}, path::{Path, PathBuf}, sync::Arc};
+use std::{
+  collections::{BTreeMap, HashMap, HashSet},
+  fs,
+  io::{self, Read, Seek, Write},
+  path::{Component, Path, PathBuf},
+  str::FromStr,
+  sync::{Arc, OnceLock},
+  time::Instant,
+};
 
-use diesel::{Connection, QueryDsl, RunQueryDsl, SelectableHelper};
-use rayon::iter::{IndexedParallelIterator, IntoParallelIterator, ParallelIterator};
-use sql_structs::{ArchiveBlockInfo, ArchiveFileEntry, ArchiveFolderLeafEntry};
-use tokio::io::AsyncReadExt;
+use header_utils::ArchiveHeader;
+use rayon::iter::{IntoParallelRefIterator, ParallelIterator};
+use sql_structs::ArchiveFileEntry;
 
-const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024; // 64MB
 const DEFAULT_MAX_MEM_EXTRACT_SIZE: u64 = 16 * 1024 * 1024; // 16MB
+const DEFAULT_MAX_INDEX_LEN: u64 = 1024 * 1024 * 1024; // 1GB
+const MAX_BLOCK_PREALLOC_SIZE: u64 = 1024 * 1024 * 1024; // 1GB
+const CREATED_BY_KEY: &str = "bloda.created_by";
+const CREATED_AT_KEY: &str = "bloda.created_at";
+const BLOCK_FRAME_MAGIC: [u8; 4] = *b"BDBK";
+// magic + big endian u64 block size
+const BLOCK_FRAME_LEN: usize = BLOCK_FRAME_MAGIC.len() + 8;
 
+#[cfg(not(any(feature = "writer", feature = "lite-reader")))]
+compile_error!("enable the writer or lite-reader feature to read archive indexes");
+
+// Parts of these are only used for writing
+#[cfg_attr(not(feature = "writer"), allow(dead_code))]
 mod compress_utils;
+#[cfg_attr(not(feature = "writer"), allow(dead_code))]
+mod flag_utils;
+#[cfg_attr(not(feature = "writer"), allow(dead_code))]
+mod hash_utils;
+#[cfg_attr(not(feature = "writer"), allow(dead_code))]
+mod header_utils;
+mod index_reader;
+#[cfg(feature = "writer")]
+mod retry_utils;
 mod sql_structs;
+#[cfg(all(test, feature = "writer"))]
+mod test_utils;
+#[cfg(feature = "writer")]
+mod writer;
+
+pub use hash_utils::HashAlgorithm;
+/// Newest on-disk format version this build reads and the one it writes
+pub use header_utils::FORMAT_VERSION;
+#[cfg(feature = "writer")]
+pub use retry_utils::RetryPolicy;
+#[cfg(feature = "writer")]
+pub use writer::{
+  create_archive,
+  create_archive_from_inputs,
+  create_archive_from_list,
+  create_archive_from_streams,
+  create_archive_to_vec,
+  create_archive_to_vec_with_diagnostics,
+  create_archive_with_diagnostics,
+  create_archive_with_options,
+  create_archive_with_report,
+  plan_archive,
+  transcode_archive,
+  ArchivePlan,
+  CreateOptions,
+  CreateReport,
+  Diagnostic,
+  IndexOrder,
+  PackStrategy,
+  PlannedBlock
+};
+
+/// Format version of the archive at `path`, read from its header without loading the index.
+/// Archives newer than `FORMAT_VERSION` report their version instead of failing, so callers
+/// can tell them apart before opening. 0 for archives from before the header
+pub fn archive_format_version(path: &Path) -> Result<u32, String>{
+  let fr = fs::File::open(path).map_err(|e| format!("at opening {path:?}: {e}"))?;
+  ArchiveHeader::read_version(fr)
+}
+
+/// Cheap structural check of the archive at `path`: the header is valid, the index decompresses
+/// and loads, and the recorded blocks fill the rest of the archive exactly, without overlapping
+/// or leaving anything over. No block is decompressed, see `ArchiveReader::check` for that
+pub fn quick_check(path: &Path) -> Result<(), String>{
+  ArchiveReader::new(path, None)?.check_layout()
+}
+
+/// Names of the compression types archives can be created with
+pub fn supported_compressions() -> &'static [&'static str]{
+  &compress_utils::SUPPORTED_COMPRESSIONS
+}
+
+/// Regex matching entry names with extension `ext`, given with or without the leading dot
+pub fn extension_pattern(ext: &str) -> String{
+  format!(r"\.{}$", regex::escape(ext.trim_start_matches('.')))
+}
+
+/// Regex matching entry names under a directory named `component`, at any depth
+pub fn under_pattern(component: &str) -> String{
+  format!(r"(^|/){}/", regex::escape(component.trim_matches(['/', '\\'])))
+}
+
+/// Output path of an archive entry under `output_dir`. Root and drive prefixes of absolute
+/// entry names are dropped, and `..` and folders in `output_dir` that are symlinks are
+/// rejected so entries can't escape `output_dir`
+fn entry_out_path(output_dir: &Path, name: &str) -> Result<PathBuf, String>{
+  let mut out_path = output_dir.to_path_buf();
+  let mut components = Path::new(name).components().peekable();
+  while let Some(component) = components.next(){
+    match component {
+      Component::Normal(x) => {
+        out_path.push(x);
+        let is_symlink = fs::symlink_metadata(&out_path).is_ok_and(|x| x.file_type().is_symlink());
+        if is_symlink && components.peek().is_some() {
+          return Err(format!("{out_path:?} is a symlink, refusing to extract through it"));
+        }
+      }
+      Component::ParentDir => return Err(format!("{name} points outside the output dir")),
+      Component::RootDir | Component::Prefix(_) | Component::CurDir => {}
+    }
+  }
+  Ok(long_path(out_path))
+}
+
+/// Output path of entry `name` in `output_dir`, after `options.rename`
+fn renamed_out_path(
+  output_dir: &Path,
+  name: &str,
+  options: &ExtractOptions
+) -> Result<PathBuf, String>{
+  let Some(rename) = &options.rename else {
+    return entry_out_path(output_dir, name);
+  };
+  let renamed = rename.pattern.replace(name, rename.replacement.as_str());
+  if normalized_name(Path::new(renamed.as_ref())).is_empty() {
+    return Err(format!("{name} is renamed to an empty path"));
+  }
+  entry_out_path(output_dir, &renamed)
+}
+
+/// `path` with only its normal components, joined by `/`. Lets archive names be compared to
+/// paths on disk
+fn normalized_name(path: &Path) -> String{
+  path
+    .components()
+    .filter_map(|x| match x {
+      Component::Normal(x) => Some(x.to_string_lossy()),
+      _ => None,
+    })
+    .collect::<Vec<_>>()
+    .join("/")
+}
+
+/// Windows refuses paths longer than MAX_PATH unless they're in verbatim (`\\?\`) form, which
+/// only absolute paths can take
+#[cfg(windows)]
+fn long_path(path: PathBuf) -> PathBuf{
+  use std::{ffi::OsString, path::Prefix};
+
+  // MAX_PATH is 260, but creating a directory leaves room for an 8.3 file name in it
+  const MAX_DIR_PATH: usize = 248;
+  if path.as_os_str().len() < MAX_DIR_PATH {
+    return path;
+  }
+  let Ok(abs_path) = std::path::absolute(&path) else {
+    return path;
+  };
+  let mut components = abs_path.components();
+  let mut verbatim = OsString::new();
+  match components.next() {
+    Some(Component::Prefix(prefix)) => match prefix.kind() {
+      Prefix::Disk(_) => {
+        verbatim.push(r"\\?\");
+        verbatim.push(prefix.as_os_str());
+      }
+      Prefix::UNC(server, share) => {
+        verbatim.push(r"\\?\UNC\");
+        verbatim.push(server);
+        verbatim.push(r"\");
+        verbatim.push(share);
+      }
+      // Already verbatim, or a device path
+      _ => return abs_path,
+    },
+    _ => return abs_path,
+  }
+  // The rest starts at the root, after the prefix
+  verbatim.push(components.as_path().as_os_str());
+  PathBuf::from(verbatim)
+}
+
+#[cfg(not(windows))]
+fn long_path(path: PathBuf) -> PathBuf{
+  path
+}
+
+/// What to do when an extracted file already exists in the output directory
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+  #[default]
+  Overwrite,
+  /// Keep the existing file
+  Skip,
+  /// Fail the file's extraction
+  Error,
+}
+
+impl FromStr for ConflictPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "overwrite" => Ok(Self::Overwrite),
+      "skip" => Ok(Self::Skip),
+      "error" => Ok(Self::Error),
+      _ => Err(format!("unknown conflict policy {s}, expected overwrite, skip or error")),
+    }
+  }
+}
+
+/// Regex substitution on entry names giving their paths in the output directory, e.g.
+/// `^build/` to `` to drop a leading folder. Only the first match in a name is replaced
+#[derive(Debug, Clone)]
+pub struct Rename {
+  pub pattern: regex::Regex,
+  /// Replacement in `Regex::replace` syntax, `$1` or `${1}` refer to capture groups
+  pub replacement: String,
+}
+
+impl FromStr for Rename {
+  type Err = String;
+
+  /// Parse `PATTERN=>REPLACEMENT`
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let (pattern, replacement) = s
+      .split_once("=>")
+      .ok_or(format!("expected PATTERN=>REPLACEMENT, got {s}"))?;
+    let pattern = regex::Regex::new(pattern).map_err(|e| format!("invalid regex: {e}"))?;
+    Ok(Self { pattern, replacement: replacement.to_string() })
+  }
+}
+
+/// Knobs for extraction.
+///
+/// Extraction merges into the output directory: missing directories are created, existing
+/// directories are kept as they are (including their permissions), files that exist in both
+/// places are handled by `conflict_policy`, and nothing that isn't in the archive is ever
+/// removed. Symlinks already in the output directory are never written through: one in place
+/// of a file is replaced like a file, one in place of a folder fails the entry
+#[derive(Debug, Clone, Default)]
+pub struct ExtractOptions {
+  /// Log files that fail to extract and carry on instead of stopping
+  pub ignore_errors: bool,
+  pub conflict_policy: ConflictPolicy,
+  /// Write a CSV listing every extracted file with its size and stored hash to this path
+  pub manifest: Option<PathBuf>,
+  /// Give extracted files and empty folders their stored uid and gid, see
+  /// `ArchiveReader::owner`. Unix only. Skipped silently without the privileges to do it
+  pub restore_ownership: bool,
+  /// Give extracted files and empty folders their stored flags, see `ArchiveReader::file_flags`.
+  /// Skipped silently where the platform, filesystem or privileges don't allow it
+  pub restore_file_flags: bool,
+  /// Threads writing out the files of a block decompressed to memory. 0 and 1 write them one
+  /// after another. Blocks themselves are still decompressed one at a time
+  pub threads: usize,
+  /// Make the directories extraction creates readable and traversable by everyone (at least
+  /// 0755), whatever the umask left them with. Directories that already existed are untouched.
+  /// Unix only
+  pub repair_permissions: bool,
+  /// Output paths of entries come from their names with this substitution applied. Renamed
+  /// paths are sanitized like stored names, and ones left empty fail. Extraction fails before
+  /// writing anything if two entries would get the same path
+  pub rename: Option<Rename>,
+}
+
+/// Thread pool for the per-file writes of `options.threads`, None if they're serial
+fn write_pool(options: &ExtractOptions) -> Result<Option<rayon::ThreadPool>, String>{
+  if options.threads <= 1 {
+    return Ok(None);
+  }
+  rayon::ThreadPoolBuilder::new()
+    .num_threads(options.threads)
+    .build()
+    .map(Some)
+    .map_err(|e| format!("at creating thread pool: {e}"))
+}
+
+/// Create the output file of an entry along with its parent directories. None if the entry
+/// should be skipped
+fn create_entry_file(
+  output_dir: &Path,
+  name: &str,
+  options: &ExtractOptions
+) -> Result<Option<fs::File>, String>{
+  let file_out_path = renamed_out_path(output_dir, name, options)?;
+  if let Some(file_out_dir) = file_out_path.parent(){
+    // Parallel writes can race to create the same directories. create_dirs treats a
+    // directory another thread made in the meantime as success
+    create_dirs(file_out_dir, options)
+      .map_err(|e| format!("at creating parent dir {file_out_dir:?}: {e}"))?;
+  }
+  let fw = match options.conflict_policy {
+    ConflictPolicy::Overwrite => open_overwrite(&file_out_path),
+    ConflictPolicy::Skip if fs::symlink_metadata(&file_out_path).is_ok() => return Ok(None),
+    ConflictPolicy::Skip | ConflictPolicy::Error => {
+      fs::OpenOptions::new().write(true).create_new(true).open(&file_out_path)
+    }
+  };
+  fw.map(Some).map_err(|e| format!("at opening {:?}: {e}", &file_out_path))
+}
+
+/// Open `path` for writing, truncating it if it exists. A symlink at `path` is replaced rather
+/// than written through, which would change whatever it points at
+fn open_overwrite(path: &Path) -> io::Result<fs::File>{
+  if fs::symlink_metadata(path).is_ok_and(|x| x.file_type().is_symlink()) {
+    fs::remove_file(path)?;
+  }
+  let mut open_options = fs::OpenOptions::new();
+  open_options.write(true).create(true).truncate(true);
+  // Fails instead of following a symlink made after the check above
+  #[cfg(unix)]
+  std::os::unix::fs::OpenOptionsExt::custom_flags(&mut open_options, libc::O_NOFOLLOW);
+  open_options.open(path)
+}
+
+/// Create `dir` and the missing directories above it. With `options.repair_permissions`, the
+/// ones created get at least 0755 right away, before anything is created in them, so a
+/// restrictive umask can't lock extraction out of them
+#[cfg(unix)]
+fn create_dirs(dir: &Path, options: &ExtractOptions) -> io::Result<()>{
+  use std::os::unix::fs::PermissionsExt;
+  use std::sync::Mutex;
+
+  if !options.repair_permissions {
+    return fs::create_dir_all(dir);
+  }
+  // Parallel writes create the same directories. Without this, one could try to create
+  // something in a directory another made but hasn't repaired yet
+  static CREATING: Mutex<()> = Mutex::new(());
+  let _creating = CREATING.lock().unwrap_or_else(|e| e.into_inner());
+  let mut missing = vec![];
+  let mut next = Some(dir);
+  while let Some(x) = next.filter(|x| !x.as_os_str().is_empty() && !x.exists()){
+    missing.push(x);
+    next = x.parent();
+  }
+  for x in missing.into_iter().rev(){
+    match fs::create_dir(x) {
+      // Made by someone else, so not ours to repair
+      Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+      res => res?,
+    }
+    let mode = fs::metadata(x)?.permissions().mode();
+    if mode & 0o755 == 0o755 {
+      continue;
+    }
+    match fs::set_permissions(x, fs::Permissions::from_mode(mode | 0o755)) {
+      Ok(()) => {}
+      Err(e) if options.ignore_errors => log::warn!("at repairing permissions of {x:?}: {e}"),
+      Err(e) => return Err(e),
+    }
+  }
+  Ok(())
+}
+
+#[cfg(not(unix))]
+fn create_dirs(dir: &Path, _options: &ExtractOptions) -> io::Result<()>{
+  fs::create_dir_all(dir)
+}
+
+/// Where the decompressed index DB is kept while an archive is opened
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IndexStore {
+  /// A temp file in this directory, the system temp directory if None
+  TempFile(Option<PathBuf>),
+  /// Loaded into SQLite straight from memory. Needs no writable directory and is faster for
+  /// small indexes, but holds the whole index in memory while it's read
+  Memory,
+}
+
+impl Default for IndexStore {
+  fn default() -> Self {
+    Self::TempFile(None)
+  }
+}
+
+/// Knobs for opening an archive. `Default` matches `ArchiveReader::new(path, None)`
+#[derive(Debug, Clone, Default)]
+pub struct ReaderOptions {
+  /// Blocks bigger than this are decompressed to a temp file instead of memory
+  pub max_mem_extract_size: Option<u64>,
+  /// Largest compressed index accepted. Guards against archives claiming huge indexes
+  pub max_index_len: Option<u64>,
+  /// If the index doesn't decompress with the compression in the header, try the others before
+  /// giving up. Recovers archives whose header names the wrong one, but can hide corruption.
+  /// The compression that worked is logged as a warning
+  pub lenient: bool,
+  /// Where the index is decompressed to while it's loaded. A temp file by default
+  pub index_store: IndexStore,
+}
+
+/// First bytes of every SQLite database, which the index is
+const SQLITE_MAGIC: &[u8] = b"SQLite format 3\0";
+
+/// Decompress the index with the compression `header` names. With `lenient`, every other
+/// compression is tried after it and the header is corrected to the one that worked
+fn decompress_index(
+  data: &[u8],
+  header: &mut ArchiveHeader,
+  lenient: bool
+) -> Result<Vec<u8>, String>{
+  let decompress = |compression: &str| {
+    let mut index_data = vec![];
+    compress_utils::decompress_data(data, &mut index_data, compression)?;
+    if !index_data.starts_with(SQLITE_MAGIC) {
+      return Err("not an index database".to_string());
+    }
+    Ok(index_data)
+  };
+  let err = match decompress(&header.index_compression) {
+    Ok(index_data) => return Ok(index_data),
+    Err(e) if !lenient => return Err(format!("at decompressing index data: {e}")),
+    Err(e) => e,
+  };
+  for compression in compress_utils::SUPPORTED_COMPRESSIONS{
+    if let Ok(index_data) = decompress(compression) {
+      log::warn!(
+        "index is compressed with {compression}, not {} as the header says",
+        &header.index_compression
+      );
+      header.index_compression = compression.to_string();
+      return Ok(index_data);
+    }
+  }
+  Err(format!("at decompressing index data: {err}, and no other compression worked"))
+}
+
+fn file_slice<'a>(block_data: &'a [u8], file_info: &ArchiveFileEntry) -> Result<&'a [u8], String>{
+  let start = file_info.offset as usize;
+  let end = start.saturating_add(file_info.size as usize);
+  block_data
+    .get(start..end)
+    .ok_or(format!("{} lies outside its block", &file_info.name))
+}
+
+/// An entry of `ArchiveReader::list_dir`. Folder sizes are the total size of the files under them
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+  pub name: String,
+  /// "FILE" or "FOLDER"
+  pub kind: String,
+  pub size: u64,
+}
+
+/// Differences between an archive and a directory, see `ArchiveReader::verify_against_dir`.
+/// Names are relative to the directory, with `/` separators
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+  /// Files in the archive that aren't in the directory
+  pub missing: Vec<String>,
+  /// Files in the directory that aren't in the archive
+  pub extra: Vec<String>,
+  /// Files in both whose size or content differs
+  pub changed: Vec<String>,
+}
+
+impl VerifyReport {
+  /// Whether the directory holds exactly the archive's files
+  pub fn is_match(&self) -> bool {
+    self.missing.is_empty() && self.extra.is_empty() && self.changed.is_empty()
+  }
+}
+
+/// A block of an archive as returned by `ArchiveReader::blocks`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockInfo {
+  pub id: u64,
+  /// Bytes the block takes up in the archive
+  pub compressed_size: u64,
+  /// Bytes of the files in it once decompressed
+  pub raw_size: u64,
+  pub compression: String,
+  /// Level it was compressed with. None for compression types without levels and archives from
+  /// before levels were stored
+  pub compression_level: Option<u32>,
+}
+
+trait ReadSeek: Read + Seek {}
+
+impl<T: Read + Seek> ReadSeek for T {}
+
+/// Where an `ArchiveReader` reads its archive from
+#[derive(Debug, Clone)]
+enum ArchiveSource {
+  File(PathBuf),
+  Bytes(Arc<[u8]>),
+}
+
+impl ArchiveSource {
+  fn open(&self) -> Result<Box<dyn ReadSeek + '_>, String>{
+    match self {
+      Self::File(path) => fs::File::open(path)
+        .map(|x| Box::new(x) as Box<dyn ReadSeek>)
+        .map_err(|e| format!("at opening archive {path:?}: {e}")),
+      Self::Bytes(data) => Ok(Box::new(io::Cursor::new(&data[..]))),
+    }
+  }
+
+  fn len(&self) -> Result<u64, String>{
+    match self {
+      Self::File(path) => fs::metadata(path)
+        .map(|x| x.len())
+        .map_err(|e| format!("at reading size of {path:?}: {e}")),
+      Self::Bytes(data) => Ok(data.len() as u64),
+    }
+  }
+}
 
 pub struct ArchiveReader{
-  archive_path: PathBuf,
+  source: ArchiveSource,
+  options: ReaderOptions,
+  header: ArchiveHeader,
   max_mem_extract_size: i64,
-  files: HashMap<String, sql_structs::ArchiveFileEntry>,
-  folder_leaves: HashMap<String, sql_structs::ArchiveFolderLeafEntry>,
+  files: Vec<sql_structs::ArchiveFileEntry>,
+  file_ids: HashMap<String, usize>,
+  block_files: HashMap<i64, Vec<usize>>,
+  // By name, so empty folders are listed the same way every time
+  folder_leaves: BTreeMap<String, sql_structs::ArchiveFolderLeafEntry>,
   block_infos: Vec<sql_structs::ArchiveBlockInfo>,
+  file_hashes: HashMap<String, (String, HashAlgorithm)>,
+  owners: HashMap<String, (u32, u32)>,
+  file_flags: HashMap<String, u32>,
+  block_levels: HashMap<i64, u32>,
+  /// Sorted by name
+  hardlinks: Vec<sql_structs::ArchiveHardlink>,
+  dir_sizes: OnceLock<HashMap<String, u64>>,
+  metadata: HashMap<String, String>,
 }
 
 impl ArchiveReader{
   pub fn new(archive_path: &Path, max_mem_extract_size: Option<u64>) -> Result<Self, String>{
-    let max_mem_extract_size = max_mem_extract_size.unwrap_or(DEFAULT_MAX_MEM_EXTRACT_SIZE) as i64;
+    Self::with_options(archive_path, &ReaderOptions { max_mem_extract_size, ..Default::default() })
+  }
+
+  pub fn with_options(archive_path: &Path, options: &ReaderOptions) -> Result<Self, String>{
+    Self::from_source(ArchiveSource::File(archive_path.to_owned()), options)
+  }
+
+  /// Read an archive held in memory, such as one made by `create_archive_to_vec`
+  pub fn from_bytes(data: &[u8]) -> Result<Self, String>{
+    Self::from_source(ArchiveSource::Bytes(data.into()), &ReaderOptions::default())
+  }
+
+  fn from_source(source: ArchiveSource, options: &ReaderOptions) -> Result<Self, String>{
+    let max_mem_extract_size =
+      options.max_mem_extract_size.unwrap_or(DEFAULT_MAX_MEM_EXTRACT_SIZE) as i64;
+    let max_index_len = options.max_index_len.unwrap_or(DEFAULT_MAX_INDEX_LEN);
     // Extract index DB
-    let mut fr = fs::File::open(archive_path)
-      .map_err(|e| format!("at opening {archive_path:?}: {e}"))?;
-    let mut index_len_bytes = [0u8; 8];
-    fr.read(&mut index_len_bytes).map_err(|e| format!("at reading header size: {e}"))?;
-    let index_len = u64::from_be_bytes(index_len_bytes);
+    let mut fr = source.open()?;
+    let mut header = ArchiveHeader::read(&mut fr)?;
+    let index_len = header.index_len;
+    let archive_len = source.len()?;
+    if index_len > max_index_len {
+      return Err(format!("index length implausible: {index_len} is over {max_index_len}"));
+    }
+    // Also guards against reading an archive that's still being written
+    if index_len > archive_len - header.size() {
+      return Err(format!("index length implausible: {index_len} with an archive of {archive_len}"));
+    }
     let mut index_compresses_data = vec![0u8; index_len as usize];
-    let temp_file = tempfile::NamedTempFile::with_suffix(".db")
-      .map_err(|e| format!("at creating temp index db file: {e}"))?;
-    fr.read(&mut index_compresses_data).map_err(|e| format!("at reading header: {e}"))?;
-    let mut index_data = vec![];
-    compress_utils::decompress_data(&index_compresses_data[..], &mut index_data, "LZ4")
-      .map_err(|e| format!("at decompressing index data: {e}"))?;
-    fs::write(temp_file.path(), &index_data)
-      .map_err(|e| format!("at writing header temp file: {e}"))?;
+    fr.read_exact(&mut index_compresses_data).map_err(|e| format!("at reading header: {e}"))?;
+    drop(fr);
+    let index_data = decompress_index(&index_compresses_data, &mut header, options.lenient)?;
 
-    let blob_offset = index_len + 8;
+    let blob_offset = header.size() + index_len;
     // Load header DB
-    let mut conn =
-      diesel::SqliteConnection::establish(&temp_file.path().to_string_lossy().to_string())
-      .map_err(|e| format!("at opening {:?}: {e}", temp_file.path()))?;
-    
-    let file_infos = sql_structs::files::table
-      .select(sql_structs::ArchiveFileEntry::as_select())
-      .load(&mut conn)
-      .map_err(|e| format!("at getting file infos: {e}"))?
+    let index_reader::IndexTables {
+      files: mut file_infos,
+      folder_leaves,
+      mut blocks,
+      hash_algorithm,
+      file_hashes,
+      metadata,
+      entry_order,
+      owners,
+      mut hardlinks,
+      file_flags,
+      block_levels
+    } = match &options.index_store {
+      IndexStore::TempFile(dir) => {
+        let mut temp_file = match dir {
+          Some(dir) => tempfile::Builder::new().suffix(".db").tempfile_in(dir),
+          None => tempfile::NamedTempFile::with_suffix(".db"),
+        }
+          .map_err(|e| format!("at creating temp index db file: {e}"))?;
+        // Through the open handle, as a restrictive umask can leave the file unwritable by path
+        temp_file
+          .as_file_mut()
+          .write_all(&index_data)
+          .map_err(|e| format!("at writing header temp file: {e}"))?;
+        index_reader::load_index(temp_file.path())?
+      }
+      IndexStore::Memory => index_reader::load_index_from_bytes(&index_data)?,
+    };
+    // Files keep the order stored at creation, older archives are listed by name
+    let entry_order = entry_order
+      .into_iter()
+      .map(|x| (x.name, x.ord))
+      .collect::<HashMap<_, _>>();
+    file_infos.sort_by(|a, b| {
+      let a_ord = entry_order.get(&a.name).copied().unwrap_or(i64::MAX);
+      let b_ord = entry_order.get(&b.name).copied().unwrap_or(i64::MAX);
+      a_ord.cmp(&b_ord).then_with(|| a.name.cmp(&b.name))
+    });
+    let file_ids = file_infos
       .iter()
-      .cloned()
+      .enumerate()
+      .map(|(i, x)| (x.name.clone(), i))
+      .collect::<HashMap<_, _>>();
+    // Reverse index from blocks to the files they contain, kept as indices into `files`
+    let mut block_files = HashMap::new();
+    for (i, file_info) in file_infos.iter().enumerate(){
+      block_files.entry(file_info.block).or_insert(vec![]).push(i);
+    }
+    let folder_leaf_infos = folder_leaves
+      .into_iter()
       .map(|x| (x.name.clone(), x))
       .collect();
-    let folder_leaf_infos = sql_structs::folder_leaves::table
-      .select(sql_structs::ArchiveFolderLeafEntry::as_select())
-      .load(&mut conn)
-      .map_err(|e| format!("at getting folder leaf infos: {e}"))?
-      .iter()
-      .cloned()
-      .map(|x| (x.name.clone(), x))
+    let file_hashes = match hash_algorithm {
+      Some(algorithm) => {
+        let algorithm = algorithm
+          .parse::<HashAlgorithm>()
+          .map_err(|e| format!("at getting file hashes: {e}"))?;
+        file_hashes.into_iter().map(|x| (x.name, (x.hash, algorithm))).collect()
+      }
+      None => HashMap::new(),
+    };
+    let owners = owners
+      .into_iter()
+      .map(|x| match (u32::try_from(x.uid), u32::try_from(x.gid)) {
+        (Ok(uid), Ok(gid)) => Ok((x.name, (uid, gid))),
+        _ => Err(format!("invalid owner {}:{} of {}", x.uid, x.gid, &x.name)),
+      })
+      .collect::<Result<_, String>>()?;
+    let file_flags = file_flags
+      .into_iter()
+      .map(|x| {
+        u32::try_from(x.flags)
+          .map(|flags| (x.name.clone(), flags))
+          .map_err(|_| format!("invalid flags {} of {}", x.flags, &x.name))
+      })
+      .collect::<Result<_, String>>()?;
+    // Levels that don't fit are dropped, they're only informational
+    let block_levels = block_levels
+      .into_iter()
+      .filter_map(|x| Some((x.id, u32::try_from(x.level).ok()?)))
       .collect();
-    let mut blocks = sql_structs::blocks::table
-      .select(sql_structs::ArchiveBlockInfo::as_select())
-      .load(&mut conn)
-      .map_err(|e| format!("at getting block infos: {e}"))?;
-    for block in blocks.iter_mut(){
+    for (i, block) in blocks.iter_mut().enumerate(){
+      if block.id != i as i64 {
+        return Err(format!("block ids not contiguous, expected {i}, got {}", block.id));
+      }
+      if block.offset < 0 || block.size < 0 {
+        return Err(format!("block {} has a negative offset or size", block.id));
+      }
+      let offset = block.offset.checked_add(blob_offset as i64);
+      let block_end = offset.and_then(|x| (x as u64).checked_add(block.size as u64));
+      if block_end.is_none_or(|x| x > archive_len) {
+        return Err(format!("block {} ends past the end of the archive", block.id));
+      }
       block.offset += blob_offset as i64;
     }
+    if let Some(x) = file_infos
+      .iter()
+      .find(|x| x.block < 0 || x.block as usize >= blocks.len() || x.offset < 0 || x.size < 0){
+      return Err(format!("file {} has an invalid block, offset or size", &x.name));
+    }
+    if let Some(x) = hardlinks.iter().find(|x| !file_ids.contains_key(&x.target)) {
+      return Err(format!("hardlink {} points at missing file {}", &x.name, &x.target));
+    }
+    hardlinks.sort_by(|a, b| a.name.cmp(&b.name));
 
     Ok(Self {
-      archive_path: archive_path.to_owned(),
+      source,
+      options: options.clone(),
+      header,
       max_mem_extract_size,
       files: file_infos,
+      file_ids,
+      block_files,
       folder_leaves: folder_leaf_infos,
       block_infos: blocks,
+      file_hashes,
+      owners,
+      file_flags,
+      block_levels,
+      hardlinks,
+      dir_sizes: OnceLock::new(),
+      metadata: metadata.into_iter().map(|x| (x.key, x.value)).collect(),
     })
   }
 
@@ -88,487 +709,2132 @@ impl ArchiveReader{
     let folder_re = regex::Regex::new(&folder_pattern)
       .inspect_err(|e| eprintln!("invalid folder re pattern: {e}"))
       .ok()?;
-    if let Some(_) = self.files.iter().find(|x| file_re.is_match(x.0)){
+    if self.files.iter().any(|x| file_re.is_match(&x.name)){
       return Some("FILE".to_string());
     }
-    if let Some(_) = self.files.iter().find(|x| folder_re.is_match(x.0)){
+    if self.files.iter().any(|x| folder_re.is_match(&x.name)){
       return Some("FOLDER".to_string());
     }
-    if let Some(_) = self
-      .folder_leaves
-      .iter()
-      .find(|x| folder_re.is_match(x.0) || file_re.is_match(x.0)){
+    if self.folder_leaves.keys().any(|x| folder_re.is_match(x) || file_re.is_match(x)){
       return Some("FOLDER".to_string());
     }
     None
   }
 
-  pub fn list_all_entries(&self) -> Vec<String>{
-    let mut  dir_leaves = self
-      .folder_leaves
-      .values()
-      .map(|x| x.name.clone())
-      .collect::<Vec<_>>();
-    let mut files = self
-      .files
-      .values()
-      .map(|x| x.name.clone())
-      .collect::<Vec<_>>();
-    files.append(&mut dir_leaves);
-    files
+  /// Reload the index from disk to pick up changes made to the archive since it was opened.
+  /// The reader is left untouched if the archive can't be read completely
+  pub fn refresh(&mut self) -> Result<(), String>{
+    *self = Self::from_source(self.source.clone(), &self.options)?;
+    Ok(())
   }
 
-  pub fn list_entries_re(&self, regex_pattern: &str) -> Result<Vec<String>, String>{
-    let re = regex::Regex::new(regex_pattern).map_err(|e| format!("invalid re pattern: {e}"))?;
-    let mut  dir_leaves = self
-      .folder_leaves
-      .values()
-      .filter(|x| re.is_match(&x.name))
-      .map(|x| x.name.clone())
-      .collect::<Vec<_>>();
-    let mut files = self
-      .files
-      .values()
-      .filter(|x| re.is_match(&x.name))
-      .map(|x| x.name.clone())
-      .collect::<Vec<_>>();
-    files.append(&mut dir_leaves);
-    Ok(files)
+  /// Version of the on-disk format the archive was written in, 0 for archives from before the
+  /// header
+  pub fn format_version(&self) -> u32 {
+    self.header.version
   }
 
-  pub fn list_dir(&self, dir_name: &str) -> Result<Vec<(String, String)>, String>{
-    let re_pattern = dir_name.strip_suffix("/").unwrap_or(dir_name);
-    let re_pattern = re_pattern.strip_suffix("\\").unwrap_or(re_pattern);
-    let re_pattern = re_pattern.replace("*", r#"[^/\\]*"#);
-    let file_pattern = if re_pattern != "" {
-      format!(r#"^{}[/\\][^/\\]*$"#, &re_pattern)
-    } else {
-      format!(r#"^[^/\\]*$"#)
-    };
-    let folder_pattern = if re_pattern != "" {
-      format!(r#"^({}[/\\][^/\\]*)[/\\].*$"#, &re_pattern)
-    } else {
-      format!(r#"^([^/\\]*)[/\\].*$"#)
-    };
-    let folder_leaf_pattern = format!(r#"^{}[/\\][^/\\]*$"#, &re_pattern);
-    let file_re = regex::Regex::new(&file_pattern)
-      .map_err(|e| format!("invalid file re pattern: {e}"))?;
-    let folder_re = regex::Regex::new(&folder_pattern)
-      .map_err(|e| format!("invalid folder re pattern: {e}"))?;
-    let folder_leaf_re = regex::Regex::new(&folder_leaf_pattern)
-      .map_err(|e| format!("invalid folder leaf re pattern: {e}"))?;
-    let mut  dir_leaves = self
-      .folder_leaves
-      .values()
-      .filter(|x| folder_leaf_re.is_match(&x.name))
-      .map(|x| (x.name.clone(), "FOLDER".to_string()))
-      .collect::<Vec<_>>();
-    let mut files = self
-      .files
-      .values()
-      .filter(|x| file_re.is_match(&x.name))
-      .map(|x| (x.name.clone(), "FILE".to_string()))
-      .collect::<Vec<_>>();
-    let mut dirs = self
-      .files
-      .values()
-      .filter_map(|x| folder_re.captures(&x.name).map(|c| c[1].to_string()))
-      .collect::<HashSet::<_>>()
-      .iter()
-      .map(|x| (x.clone(), "FOLDER".to_string()))
-      .collect::<Vec<_>>();
-    files.append(&mut dirs);
-    files.append(&mut dir_leaves);
-    Ok(files)
+  /// Optional parts of the format the archive uses, as recorded in its header. Archives from
+  /// before format version 3 report none
+  pub fn features(&self) -> Vec<&'static str> {
+    self.header.feature_names()
   }
 
-  fn extract_block_mem(&self, block_id: i64) -> Result<Vec<u8>, String>{
-    let block_info = &self.block_infos[block_id as usize];
-    let block_offset =  block_info.offset as u64;
-    let block_size = block_info.size;
-    let compression = &block_info.compression_type;
-    let mut comp_data = vec![0u8; block_size as usize];
-    let mut fr = fs::File::open(&self.archive_path)
-      .map_err(|e| format!("at opening archive {:?}: {e}", &self.archive_path))?;
-    fr
-      .seek(io::SeekFrom::Start(block_offset))
-      .map_err(|e| format!("at seeking to {block_offset}: {e}"))?;
-    fr
-      .read(&mut comp_data)
-      .map_err(|e| format!("at reading blob {:?}: {e}", &self.archive_path))?;
-    let mut raw_block_data = Vec::with_capacity(comp_data.len());
-    compress_utils::decompress_data(&comp_data[..], &mut raw_block_data, compression)?;
-    Ok(raw_block_data)
+  /// Compression the index was stored with, as recorded in the archive header
+  pub fn index_compression(&self) -> &str {
+    &self.header.index_compression
   }
 
-  fn extract_block_file(&self, block_id: i64, out_file: &Path) -> Result<(), String>{
-    let block_info = &self.block_infos[block_id as usize];
-    let block_offset =  block_info.offset as u64;
-    let block_size = block_info.size;
-    let compression = &block_info.compression_type;
-    let mut comp_data = vec![0u8; block_size as usize];
-    let mut fr = fs::File::open(&self.archive_path)
-      .map_err(|e| format!("at opening archive {:?}: {e}", &self.archive_path))?;
-    fr
-      .seek(io::SeekFrom::Start(block_offset))
-      .map_err(|e| format!("at seeking to {block_offset}: {e}"))?;
-    fr
-      .read(&mut comp_data)
-      .map_err(|e| format!("at reading blob {:?}: {e}", &self.archive_path))?;
-    let mut fw = fs::File::create(out_file).map_err(|e| format!("at opening tempfile: {e}"))?;
-    compress_utils::decompress_data(&comp_data[..], &mut fw, compression)?;
-    Ok(())
+  /// Key-value metadata stored at creation
+  pub fn metadata(&self) -> &HashMap<String, String> {
+    &self.metadata
   }
 
-  pub fn extract_file(&self, name: &str, output: &Path) -> Result<(), String>{
-    let file_info = self.files.get(name).ok_or(format!("{name} doesn't exist in archive"))?;
-    if let Some(parent_dir) = output.parent(){
-      fs::create_dir_all(parent_dir)
-        .map_err(|e| format!("at creating dir {parent_dir:?}: {e}"))?;
-    }
-    let mut fw = fs::File::create(output).map_err(|e| format!("at opening {output:?}: {e}"))?;
-    let block_size = &self.block_infos[file_info.block as usize];
-    if block_size.size > self.max_mem_extract_size {
-      let t_file = tempfile::NamedTempFile::new()
-        .map_err(|e| format!("at creating tempfile: {e}"))?;
-      self.extract_block_file(file_info.block, t_file.path())?;
-      let mut fr = fs::File::open(t_file.path())
-        .map_err(|e| format!("at opening temp file: {e}"))?;
-      fr
-        .seek(io::SeekFrom::Start(file_info.offset as u64))
-        .map_err(|e| format!("at seeking in tempfile: {e}"))?;
-      let mut fr = fr.take(file_info.size as u64);
-      io::copy(&mut fr, &mut fw).map_err(|e| format!("at writing :{e}"))?;
-    } else {
-      let block_data = self.extract_block_mem(file_info.block)?;
-      let start = file_info.offset as usize;
-      let end = start + file_info.size as usize;
-      fw.write(&block_data[start..end]).map_err(|e| format!("at writing :{e}"))?;
-    }
-    fw.flush().map_err(|e| format!("at flushing: {e}"))?;
-    Ok(())
+  /// Version of bloda that created the archive
+  pub fn created_by(&self) -> Option<&str> {
+    self.metadata.get(CREATED_BY_KEY).map(|x| x.as_str())
   }
 
-  pub fn extract_files(
-    &self,
-    re_pattern: &str,
-    output_dir: &Path,
-    ignore_errors: bool
-  ) -> Result<(), String>{
-    let re_obj = regex::Regex::new(re_pattern).map_err(|e| format!("invalid regex: {e}"))?;
+  /// Creation time in seconds since the unix epoch. Not stored for reproducible archives
+  pub fn created_at(&self) -> Option<u64> {
+    self.metadata.get(CREATED_AT_KEY).and_then(|x| x.parse().ok())
+  }
+
+  pub fn block_count(&self) -> usize {
+    self.block_infos.len()
+  }
 
+  /// Every block in id order
+  pub fn blocks(&self) -> Vec<BlockInfo> {
     self
-      .folder_leaves
+      .block_infos
       .iter()
-      .filter(|x| re_obj.is_match(x.0))
-      .map(|x| output_dir.join(&x.0))
-      .map(|x| fs::create_dir_all(&x).map_err(|e| format!("at creating leaf dir {:?}: {e}", &x)))
-      .collect::<Result<(), String>>()?;
+      .map(|x| BlockInfo {
+        id: x.id as u64,
+        compressed_size: x.size as u64,
+        raw_size: self.block_raw_size(x.id),
+        compression: x.compression_type.clone(),
+        compression_level: self.block_levels.get(&x.id).copied(),
+      })
+      .collect()
+  }
 
-    let files_to_extract = self
-      .files
-      .iter()
-      .filter(|x| re_obj.is_match(x.0))
-      .map(|x| x.1)
+  /// Names of the files stored in block `block_id`, in the order they are laid out in it
+  pub fn files_in_block(&self, block_id: u64) -> Vec<&str> {
+    let mut files = self
+      .block_files
+      .get(&(block_id as i64))
+      .into_iter()
+      .flatten()
+      .map(|i| &self.files[*i])
       .collect::<Vec<_>>();
+    files.sort_by_key(|x| x.offset);
+    files.into_iter().map(|x| x.name.as_str()).collect()
+  }
+
+  /// Number of files in the archive. Files are indexed in the order chosen at creation (see
+  /// `IndexOrder`), which is the same every time the archive is opened
+  pub fn file_count(&self) -> usize {
+    self.files.len()
+  }
+
+  /// Name of the file at position `n` of the archive's `IndexOrder`, see `file_count`
+  pub fn entry_name(&self, n: usize) -> Option<&str> {
+    self.files.get(n).map(|x| x.name.as_str())
+  }
+
+  /// Extract the file at position `n` of the archive's `IndexOrder`, see `file_count`
+  pub fn extract_nth(&self, n: usize, output: &Path) -> Result<(), String>{
+    let name = self.entry_name(n).ok_or(format!("file index {n} out of range"))?;
+    self.extract_file(name, output)
+  }
+
+  /// Uncompressed size of file `name`
+  pub fn file_size(&self, name: &str) -> Option<u64> {
+    self.file_ids.get(name).map(|i| self.files[*i].size as u64)
+  }
+
+  /// Estimate of how much of the archive file `name` takes up. Files share blocks, so this is the
+  /// file's share of its block's compressed size by uncompressed bytes. The estimates of a
+  /// block's files add up to its compressed size
+  pub fn estimated_compressed_size(&self, name: &str) -> Option<u64> {
+    let file_info = &self.files[*self.file_ids.get(name)?];
+    let block_size = self.block_infos[file_info.block as usize].size as u128;
+    let raw_size = self.block_raw_size(file_info.block) as u128;
+    if raw_size == 0 {
+      return Some(0);
+    }
+    // Scaling both ends of the file keeps the rounding from adding up across the block
+    let scale = |x: i64| x as u128 * block_size / raw_size;
+    let start = scale(file_info.offset);
+    let end = scale(file_info.offset.saturating_add(file_info.size));
+    Some((end - start) as u64)
+  }
+
+  /// Stored content hash of a file and the algorithm used, if the archive was created with
+  /// hashing
+  pub fn file_hash(&self, name: &str) -> Option<(&str, HashAlgorithm)> {
+    self.file_hashes.get(name).map(|(hash, algorithm)| (hash.as_str(), *algorithm))
+  }
+
+  /// Stored uid and gid of a file or empty folder, if the archive was created with
+  /// `CreateOptions::store_ownership`
+  pub fn owner(&self, name: &str) -> Option<(u32, u32)> {
+    self.owners.get(name).copied()
+  }
+
+  /// Stored flags, like immutable and append-only, of a file or empty folder if the archive was
+  /// created with `CreateOptions::store_file_flags`. Only entries with flags set are stored
+  pub fn file_flags(&self, name: &str) -> Option<u32> {
+    self.file_flags.get(name).copied()
+  }
+
+  /// File that `name` is a hardlink to, if the archive was created with
+  /// `CreateOptions::detect_hardlinks`
+  pub fn hardlink_target(&self, name: &str) -> Option<&str> {
+    self
+      .hardlinks
+      .binary_search_by(|x| x.name.as_str().cmp(name))
+      .ok()
+      .map(|i| self.hardlinks[i].target.as_str())
+  }
+
+  /// Compare the archive's files to the files under `dir`, like a dry run sync between the two.
+  /// Files in both are compared by size, and by content when the archive stores their hash.
+  /// Folders aren't compared
+  pub fn verify_against_dir(&self, dir: &Path) -> Result<VerifyReport, String>{
+    let mut on_disk = HashMap::new();
+    for entry in walkdir::WalkDir::new(dir){
+      let entry = entry.map_err(|e| format!("at listing {dir:?}: {e}"))?;
+      // Following symlinks like creation does, which stores a linked file's content
+      if entry.path().is_file() {
+        let rel_path = entry.path().strip_prefix(dir).unwrap_or(entry.path());
+        on_disk.insert(normalized_name(rel_path), entry.path().to_owned());
+      }
+    }
+    let mut report = VerifyReport::default();
+    let names = self
+      .files
+      .iter()
+      .map(|x| x.name.as_str())
+      .chain(self.hardlinks.iter().map(|x| x.name.as_str()));
+    for name in names{
+      let key = normalized_name(Path::new(name));
+      let Some(path) = on_disk.remove(&key) else {
+        report.missing.push(key);
+        continue;
+      };
+      let file = self.hardlink_target(name).unwrap_or(name);
+      let size = fs::metadata(&path).map_err(|e| format!("at reading {path:?}: {e}"))?.len();
+      let changed = match self.file_hash(file) {
+        _ if self.file_size(file) != Some(size) => true,
+        Some((hash, algorithm)) => hash_utils::hash_file(&path, algorithm)? != hash,
+        None => false,
+      };
+      if changed {
+        report.changed.push(key);
+      }
+    }
+    report.extra = on_disk.into_keys().collect();
+    report.missing.sort();
+    report.extra.sort();
+    report.changed.sort();
+    Ok(report)
+  }
+
+  /// Check every hashed file against its stored hash, decompressing each block once.
+  /// Returns the names of files that don't match
+  pub fn verify(&self) -> Result<Vec<String>, String>{
+    let mut mismatched = vec![];
+    for (block_id, file_ids) in &self.block_files{
+      let hashed_files = file_ids
+        .iter()
+        .map(|i| &self.files[*i])
+        .filter_map(|x| self.file_hashes.get(&x.name).map(|h| (x, h)))
+        .collect::<Vec<_>>();
+      if hashed_files.is_empty(){
+        continue;
+      }
+      let block_data = self.extract_block_mem(*block_id)?;
+      for (file_info, (hash, algorithm)) in hashed_files{
+        if hash_utils::hash_bytes(file_slice(&block_data, file_info)?, *algorithm) != *hash {
+          mismatched.push(file_info.name.clone());
+        }
+      }
+    }
+    mismatched.sort();
+    Ok(mismatched)
+  }
 
-    let mut files_per_block = HashMap::new();
-    for file_info in files_to_extract{
-      files_per_block.entry(file_info.block).or_insert(vec![]).push(file_info);
+  /// Check that the blocks follow each other from the end of the index to the end of the
+  /// archive, with only block frames between them in recoverable archives
+  fn check_layout(&self) -> Result<(), String>{
+    let frame_len = BLOCK_FRAME_LEN as u64;
+    // Older headers don't record whether blocks are framed, so either is accepted
+    let allowed_gaps = if self.header.version < header_utils::FEATURE_FLAGS_VERSION {
+      vec![0, frame_len]
+    } else if self.header.feature_flags & header_utils::FEATURE_RECOVERABLE != 0 {
+      vec![frame_len]
+    } else {
+      vec![0]
+    };
+    let mut blob_end = self.header.size() + self.header.index_len;
+    for block in &self.block_infos{
+      let gap = (block.offset as u64).checked_sub(blob_end);
+      if !gap.is_some_and(|x| allowed_gaps.contains(&x)) {
+        return Err(format!(
+          "block {} starts at {}, expected it right after the previous one at {blob_end}",
+          block.id,
+          block.offset
+        ));
+      }
+      blob_end = block.offset as u64 + block.size as u64;
     }
+    let archive_len = self.source.len()?;
+    if archive_len != blob_end {
+      return Err(format!("blocks end at {blob_end}, but the archive is {archive_len} bytes"));
+    }
+    Ok(())
+  }
 
-    for (block_id, file_infos) in files_per_block{
-      let block_size = &self.block_infos[block_id as usize];
-      if block_size.size > self.max_mem_extract_size {
+  /// Decompress every block and check that each file lies within its block and matches its
+  /// stored hash, if it has one. Fails on the first problem found
+  pub fn check(&self) -> Result<(), String>{
+    let no_files = vec![];
+    for block_id in 0..self.block_infos.len() as i64 {
+      let file_infos = self
+        .block_files
+        .get(&block_id)
+        .unwrap_or(&no_files)
+        .iter()
+        .map(|i| &self.files[*i]);
+      if self.block_infos[block_id as usize].size > self.max_mem_extract_size {
         let t_file = tempfile::NamedTempFile::new()
           .map_err(|e| format!("at creating tempfile: {e}"))?;
         self.extract_block_file(block_id, t_file.path())?;
+        let block_len = fs::metadata(t_file.path())
+          .map_err(|e| format!("at reading size of tempfile: {e}"))?
+          .len();
         for file_info in file_infos{
+          if (file_info.offset as u64).saturating_add(file_info.size as u64) > block_len {
+            return Err(format!("{} lies outside its block", &file_info.name));
+          }
+          let Some((hash, algorithm)) = self.file_hashes.get(&file_info.name) else {
+            continue;
+          };
           let mut fr = fs::File::open(t_file.path())
             .map_err(|e| format!("at opening temp file: {e}"))?;
-          let file_out_path = output_dir.join(&file_info.name);
-          if let Some(file_out_dir) = file_out_path.parent(){
-            fs::create_dir_all(file_out_dir)
-              .map_err(|e| format!("at creating parent dir {file_out_dir:?}: {e}"))?;
-          }
-          let mut fw = fs::File::create(&file_out_path)
-            .map_err(|e| format!("at opening {:?}: {e}", &file_out_path))?;
           fr
             .seek(io::SeekFrom::Start(file_info.offset as u64))
             .map_err(|e| format!("at seeking in tempfile: {e}"))?;
-          let mut fr = fr.take(file_info.size as u64);
-          io::copy(&mut fr, &mut fw).map_err(|e| format!("at writing: {e}"))?;
-          fw.flush().map_err(|e| format!("at flushing: {e}"))?;
+          if hash_utils::hash_reader(fr.take(file_info.size as u64), *algorithm)? != *hash {
+            return Err(format!("{} doesn't match its hash", &file_info.name));
+          }
         }
       } else {
         let block_data = self.extract_block_mem(block_id)?;
         for file_info in file_infos{
-          let file_out_path = output_dir.join(&file_info.name);
-          if let Some(file_out_dir) = file_out_path.parent(){
-            fs::create_dir_all(file_out_dir)
-              .map_err(|e| format!("at creating parent dir {file_out_dir:?}: {e}"))?;
+          let data = file_slice(&block_data, file_info)?;
+          let Some((hash, algorithm)) = self.file_hashes.get(&file_info.name) else {
+            continue;
+          };
+          if hash_utils::hash_bytes(data, *algorithm) != *hash {
+            return Err(format!("{} doesn't match its hash", &file_info.name));
           }
-          let mut fw = fs::File::create(&file_out_path)
-            .map_err(|e| format!("at opening {:?}: {e}", &file_out_path))?;
-          let start = file_info.offset as usize;
-          let end = start + file_info.size as usize;
-          fw.write(&block_data[start..end]).map_err(|e| format!("at writing :{e}"))?;
-          fw.flush().map_err(|e| format!("at flushing: {e}"))?;
         }
       }
     }
     Ok(())
   }
-}
 
-fn write_index_data(
-  db_path: &str,
-  files: Vec<ArchiveFileEntry>,
-  folder_leaves: Vec<ArchiveFolderLeafEntry>,
-  block_infos: Vec<ArchiveBlockInfo>,
-) -> Result<(), String>{
-  if Path::new(db_path).is_file(){
-    fs::remove_file(&db_path).map_err(|e| format!("at deleting existing db: {e}"))?;
-  }
-  let mut conn = diesel::SqliteConnection::establish(&db_path)
-    .map_err(|e| format!("at opening {db_path}: {e}"))?;
-  diesel::sql_query("CREATE TABLE files(
-    name TEXT PRIMARY KEY,
-    block BIGINT,
-    offset BIGINT,
-    size BIGINT)"
-  )
-    .execute(&mut conn)
-    .map_err(|e| format!("at creating files table: {e}"))?;
-  diesel::sql_query("CREATE TABLE folder_leaves(name TEXT PRIMARY KEY)")
-    .execute(&mut conn)
-    .map_err(|e| format!("at creating folder_leaves table: {e}"))?;
-  diesel::sql_query("CREATE TABLE blocks(
-    id BIGINT PRIMARY KEY,
-    size BIGINT,
-    offset BIGINT,
-    compression_type TEXT)"
-  )
-    .execute(&mut conn)
-    .map_err(|e| format!("at creating blocks table: {e}"))?;
-  diesel::insert_into(sql_structs::files::table)
-    .values(&files)
-    .execute(&mut conn)
-    .map_err(|e| format!("at writing files info: {e}"))?;
-  diesel::insert_into(sql_structs::folder_leaves::table)
-    .values(&folder_leaves)
-    .execute(&mut conn)
-    .map_err(|e| format!("at writing folder leaves info: {e}"))?;
-  diesel::insert_into(sql_structs::blocks::table)
-    .values(&block_infos)
-    .execute(&mut conn)
-    .map_err(|e| format!("at writing archive info: {e}"))?;
-  Ok(())
-}
+  /// Files in the order chosen at creation (see `IndexOrder`), followed by empty folders by name
+  pub fn list_all_entries(&self) -> Vec<String>{
+    let mut files = self.list_files();
+    files.append(&mut self.list_dirs());
+    files
+  }
 
-fn distribute_files_to_blocks(
-  inp_dir: &Path,
-  max_multi_block_size: i64
-) -> (Vec<Vec<(PathBuf, i64, i64)>>, Vec<PathBuf>) {
-  let entries = walkdir::WalkDir::new(inp_dir)
-    .into_iter()
-    .filter_map(|x| x.ok())
-    .map(|x| x.path().to_owned())
-    .collect::<Vec<_>>();
-  let mut files_w_sizes = entries
-    .iter()
-    .filter(|x| x.is_file())
-    .filter_map(|x| x.metadata().map(|m| (x, m.len() as i64)).ok())
-    .collect::<Vec<_>>();
-  files_w_sizes.sort_by_key(|x| x.1);
-  let folder_leaves = entries
-    .iter()
-    .filter(|x| x.is_dir() && fs::read_dir(*x).map(|mut y| y.next().is_none()).unwrap_or(false))
-    .cloned()
-    .collect::<Vec<_>>();
+  pub fn list_entries_re(&self, regex_pattern: &str) -> Result<Vec<String>, String>{
+    let mut files = self.list_files_re(regex_pattern)?;
+    files.append(&mut self.list_dirs_re(regex_pattern)?);
+    Ok(files)
+  }
 
-  let mut block_infos = vec![];
+  /// Files, hardlinks included, in the order chosen at creation. See `list_all_entries`
+  pub fn list_files(&self) -> Vec<String>{
+    self
+      .files
+      .iter()
+      .map(|x| x.name.clone())
+      .chain(self.hardlinks.iter().map(|x| x.name.clone()))
+      .collect()
+  }
+
+  pub fn list_files_re(&self, regex_pattern: &str) -> Result<Vec<String>, String>{
+    let re = regex::Regex::new(regex_pattern).map_err(|e| format!("invalid re pattern: {e}"))?;
+    Ok(self.list_files().into_iter().filter(|x| re.is_match(x)).collect())
+  }
+
+  /// Only the empty-folder leaves, by name. Folders with files or other folders in them aren't
+  /// stored as entries and aren't returned, see `list_dir` to browse those
+  pub fn list_dirs(&self) -> Vec<String>{
+    self.folder_leaves.values().map(|x| x.name.clone()).collect()
+  }
+
+  pub fn list_dirs_re(&self, regex_pattern: &str) -> Result<Vec<String>, String>{
+    let re = regex::Regex::new(regex_pattern).map_err(|e| format!("invalid re pattern: {e}"))?;
+    Ok(self.list_dirs().into_iter().filter(|x| re.is_match(x)).collect())
+  }
+
+  /// Total size of the files under every directory of the archive, computed on first use
+  fn dir_sizes(&self) -> &HashMap<String, u64>{
+    self.dir_sizes.get_or_init(|| {
+      let mut dir_sizes = HashMap::new();
+      for file_info in &self.files{
+        let mut dir_name = file_info.name.as_str();
+        while let Some((parent, _)) = dir_name.rsplit_once(['/', '\\']){
+          *dir_sizes.entry(parent.to_string()).or_insert(0) += file_info.size as u64;
+          dir_name = parent;
+        }
+      }
+      dir_sizes
+    })
+  }
+
+  pub fn list_dir(&self, dir_name: &str) -> Result<Vec<DirEntry>, String>{
+    let re_pattern = dir_name.strip_suffix("/").unwrap_or(dir_name);
+    let re_pattern = re_pattern.strip_suffix("\\").unwrap_or(re_pattern);
+    let re_pattern = re_pattern.replace("*", r#"[^/\\]*"#);
+    let file_pattern = if !re_pattern.is_empty() {
+      format!(r#"^{}[/\\][^/\\]*$"#, &re_pattern)
+    } else {
+      r#"^[^/\\]*$"#.to_string()
+    };
+    let folder_pattern = if !re_pattern.is_empty() {
+      format!(r#"^({}[/\\][^/\\]*)[/\\].*$"#, &re_pattern)
+    } else {
+      r#"^([^/\\]*)[/\\].*$"#.to_string()
+    };
+    let folder_leaf_pattern = file_pattern.clone();
+    let file_re = regex::Regex::new(&file_pattern)
+      .map_err(|e| format!("invalid file re pattern: {e}"))?;
+    let folder_re = regex::Regex::new(&folder_pattern)
+      .map_err(|e| format!("invalid folder re pattern: {e}"))?;
+    let folder_leaf_re = regex::Regex::new(&folder_leaf_pattern)
+      .map_err(|e| format!("invalid folder leaf re pattern: {e}"))?;
+    let mut  dir_leaves = self
+      .folder_leaves
+      .values()
+      .filter(|x| folder_leaf_re.is_match(&x.name))
+      .map(|x| DirEntry{ name: x.name.clone(), kind: "FOLDER".to_string(), size: 0 })
+      .collect::<Vec<_>>();
+    let mut files = self
+      .files
+      .iter()
+      .filter(|x| file_re.is_match(&x.name))
+      .map(|x| DirEntry{ name: x.name.clone(), kind: "FILE".to_string(), size: x.size as u64 })
+      .collect::<Vec<_>>();
+    let dir_sizes = self.dir_sizes();
+    let mut dirs = self
+      .files
+      .iter()
+      .filter_map(|x| folder_re.captures(&x.name).map(|c| c[1].to_string()))
+      .collect::<HashSet::<_>>()
+      .into_iter()
+      .map(|x| {
+        let size = dir_sizes.get(&x).copied().unwrap_or(0);
+        DirEntry{ name: x, kind: "FOLDER".to_string(), size }
+      })
+      .collect::<Vec<_>>();
+    files.append(&mut dirs);
+    files.append(&mut dir_leaves);
+    Ok(files)
+  }
+
+  /// Decompressed size of a block. Its files are stored back to back, so the last one ends where
+  /// the block does
+  fn block_raw_size(&self, block_id: i64) -> u64 {
+    self
+      .block_files
+      .get(&block_id)
+      .into_iter()
+      .flatten()
+      .map(|i| self.files[*i].offset.saturating_add(self.files[*i].size) as u64)
+      .max()
+      .unwrap_or(0)
+  }
+
+  /// Compressed data of a block without its compression marker, which has to agree with the
+  /// index. Archives from before the marker are passed through
+  fn strip_block_marker<'a>(
+    &self,
+    block_info: &sql_structs::ArchiveBlockInfo,
+    comp_data: &'a [u8]
+  ) -> Result<&'a [u8], String>{
+    if !self.header.has_block_markers() {
+      return Ok(comp_data);
+    }
+    let (marker, data) = comp_data
+      .split_first()
+      .ok_or(format!("block {} has no compression marker", block_info.id))?;
+    if compress_utils::compression_id(&block_info.compression_type)? != *marker {
+      return Err(format!(
+        "block {}: index says {}, marker says {}",
+        block_info.id,
+        &block_info.compression_type,
+        compress_utils::compression_name(*marker).unwrap_or("unknown")
+      ));
+    }
+    Ok(data)
+  }
+
+  /// Low level and unstable: the block's bytes as stored, compression marker included and
+  /// without decompressing. For tooling and debugging, the layout may change between versions
+  pub fn read_block_raw(&self, block_id: u64) -> Result<Vec<u8>, String>{
+    let block_info = self
+      .block_infos
+      .get(block_id as usize)
+      .ok_or(format!("no block {block_id}, the archive has {}", self.block_infos.len()))?;
+    let block_offset =  block_info.offset as u64;
+    let mut comp_data = vec![0u8; block_info.size as usize];
+    let mut fr = self.source.open()?;
+    fr
+      .seek(io::SeekFrom::Start(block_offset))
+      .map_err(|e| format!("at seeking to {block_offset}: {e}"))?;
+    fr.read_exact(&mut comp_data).map_err(|e| format!("at reading block {block_id}: {e}"))?;
+    Ok(comp_data)
+  }
 
-  let mut curr_block_files = vec![];
-  let mut curr_block_offset = 0;
-  for (path, size) in files_w_sizes{
-    if (curr_block_offset + size > max_multi_block_size) && curr_block_files.len() > 0{
-      block_infos.push(curr_block_files);
-      curr_block_files = vec![];
-      curr_block_offset = 0;
+  /// Low level and unstable: the block decompressed, its files back to back at the offsets in
+  /// the index. See `read_block_raw`
+  pub fn decompress_block(&self, block_id: u64) -> Result<Vec<u8>, String>{
+    if block_id as usize >= self.block_infos.len() {
+      return Err(format!("no block {block_id}, the archive has {}", self.block_infos.len()));
     }
-    curr_block_files.push((path.clone(), curr_block_offset, size));
-    curr_block_offset += size;
+    self.extract_block_mem(block_id as i64)
   }
-  if curr_block_files.len() > 0{
-    block_infos.push(curr_block_files);
+
+  fn extract_block_mem(&self, block_id: i64) -> Result<Vec<u8>, String>{
+    let block_info = &self.block_infos[block_id as usize];
+    let compression = &block_info.compression_type;
+    let comp_data = self.read_block_raw(block_id as u64)?;
+    // Capped since the index isn't trusted
+    let capacity = self
+      .block_raw_size(block_id)
+      .min(MAX_BLOCK_PREALLOC_SIZE)
+      .max(comp_data.len() as u64);
+    let mut raw_block_data = Vec::with_capacity(capacity as usize);
+    let comp_data = self.strip_block_marker(block_info, &comp_data)?;
+    compress_utils::decompress_data(comp_data, &mut raw_block_data, compression)?;
+    Ok(raw_block_data)
   }
-  (block_infos, folder_leaves)
-}
 
-async fn write_file_to_buffer(
-  buffer: Arc<tokio::sync::Mutex<Vec<u8>>>,
-  file_path: PathBuf,
-  offset: i64,
-  size: i64,
-) -> Result<(), String>{
-  let mut fr = tokio::fs::File::open(&file_path)
-    .await
-    .map_err(|e| format!("at opening {:?}: {e}", &file_path))?;
-  let mut buffer_lock = buffer.lock().await;
-  fr
-    .read(&mut buffer_lock[offset as usize..(offset + size) as usize])
-    .await
-    .map_err(|e| format!("at loading file to buffer: {e}"))?;
-  Ok(())
-}
+  fn extract_block_file(&self, block_id: i64, out_file: &Path) -> Result<(), String>{
+    let block_info = &self.block_infos[block_id as usize];
+    let block_offset =  block_info.offset as u64;
+    let block_size = block_info.size;
+    let compression = &block_info.compression_type;
+    let mut comp_data = vec![0u8; block_size as usize];
+    let mut fr = self.source.open()?;
+    fr
+      .seek(io::SeekFrom::Start(block_offset))
+      .map_err(|e| format!("at seeking to {block_offset}: {e}"))?;
+    fr.read_exact(&mut comp_data).map_err(|e| format!("at reading block {block_id}: {e}"))?;
+    let mut fw = fs::File::create(out_file).map_err(|e| format!("at opening tempfile: {e}"))?;
+    let comp_data = self.strip_block_marker(block_info, &comp_data)?;
+    compress_utils::decompress_data(comp_data, &mut fw, compression)?;
+    Ok(())
+  }
+
+  /// Contents of file `name`, read into memory without writing anything out
+  pub fn read_file(&self, name: &str) -> Result<Vec<u8>, String>{
+    let file_info = self
+      .file_ids
+      .get(name)
+      .map(|i| &self.files[*i])
+      .ok_or(format!("{name} doesn't exist in archive"))?;
+    let block_data = self.extract_block_mem(file_info.block)?;
+    Ok(file_slice(&block_data, file_info)?.to_vec())
+  }
+
+  /// Contents of every file by name, decompressing each block once instead of once per file
+  /// like `read_file`. Hardlinks get a copy of their target's content. Fails before reading
+  /// anything if the files add up to more than `max_total_size` bytes
+  pub fn extract_all_to_bytes(
+    &self,
+    max_total_size: u64
+  ) -> Result<HashMap<String, Vec<u8>>, String>{
+    let total_size = self
+      .files
+      .iter()
+      .map(|x| x.size as u64)
+      .chain(self.hardlinks.iter().filter_map(|x| self.file_size(&x.target)))
+      .sum::<u64>();
+    if total_size > max_total_size {
+      return Err(format!("files add up to {total_size} bytes, over the limit of {max_total_size}"));
+    }
+    let mut contents = HashMap::with_capacity(self.files.len() + self.hardlinks.len());
+    for (block_id, file_ids) in &self.block_files{
+      let block_data = self.extract_block_mem(*block_id)?;
+      for file_info in file_ids.iter().map(|i| &self.files[*i]){
+        contents.insert(file_info.name.clone(), file_slice(&block_data, file_info)?.to_vec());
+      }
+    }
+    for link in &self.hardlinks{
+      if let Some(data) = contents.get(&link.target) {
+        contents.insert(link.name.clone(), data.clone());
+      }
+    }
+    Ok(contents)
+  }
+
+  pub fn extract_file(&self, name: &str, output: &Path) -> Result<(), String>{
+    let output = &long_path(output.to_path_buf());
+    if let Some(parent_dir) = output.parent(){
+      fs::create_dir_all(parent_dir)
+        .map_err(|e| format!("at creating dir {parent_dir:?}: {e}"))?;
+    }
+    let mut fw = fs::File::create(output).map_err(|e| format!("at opening {output:?}: {e}"))?;
+    self.extract_to_writer(name, &mut fw, false)?;
+    fw.flush().map_err(|e| format!("at flushing: {e}"))?;
+    Ok(())
+  }
 
-async fn compress_block(
-  output: &Path,
-  block_files: &[(PathBuf, i64, i64)],
-  compression_type: &str
-) -> Result<u64, String>{
-  if block_files.len() == 1{
-    if let Some((path, _, _)) = block_files.last(){
-      let fr = fs::File::open(&path).map_err(|e| format!("at opening {:?}: {e}", &path))?;
-      let mut fw = fs::File::create(output).map_err(|e| format!("at creating {output:?}: {e}"))?;
-      return compress_utils::compress_data(fr, &mut fw, compression_type);
+  /// Stream file `name` into `writer`, holding at most its block in memory. With `verify`, the
+  /// file is hashed as it's written and an error is returned once it's all written if it doesn't
+  /// match its stored hash. Files stored without a hash aren't verified
+  pub fn extract_to_writer<W: Write>(
+    &self,
+    name: &str,
+    writer: &mut W,
+    verify: bool
+  ) -> Result<(), String>{
+    let name = self.hardlink_target(name).unwrap_or(name);
+    let file_info = self
+      .file_ids
+      .get(name)
+      .map(|i| &self.files[*i])
+      .ok_or(format!("{name} doesn't exist in archive"))?;
+    let stored_hash = self.file_hashes.get(name).filter(|_| verify);
+    let mut fw = hash_utils::HashingWriter::new(writer, stored_hash.map(|x| x.1));
+    let block_size = &self.block_infos[file_info.block as usize];
+    if block_size.size > self.max_mem_extract_size {
+      let t_file = tempfile::NamedTempFile::new()
+        .map_err(|e| format!("at creating tempfile: {e}"))?;
+      self.extract_block_file(file_info.block, t_file.path())?;
+      let mut fr = fs::File::open(t_file.path())
+        .map_err(|e| format!("at opening temp file: {e}"))?;
+      fr
+        .seek(io::SeekFrom::Start(file_info.offset as u64))
+        .map_err(|e| format!("at seeking in tempfile: {e}"))?;
+      let mut fr = fr.take(file_info.size as u64);
+      io::copy(&mut fr, &mut fw).map_err(|e| format!("at writing :{e}"))?;
     } else {
-      return Err("should not occur".to_string())
+      let block_data = self.extract_block_mem(file_info.block)?;
+      fw
+        .write_all(file_slice(&block_data, file_info)?)
+        .map_err(|e| format!("at writing :{e}"))?;
+    }
+    fw.flush().map_err(|e| format!("at flushing: {e}"))?;
+    match (fw.finish(), stored_hash) {
+      (Some(hash), Some((stored, _))) if hash != *stored => {
+        Err(format!("{name} doesn't match its hash"))
+      }
+      _ => Ok(()),
     }
   }
-  let total_size = block_files.iter().map(|x| x.2).sum::<i64>();
-  let block_data = Arc::new(tokio::sync::Mutex::new(vec![0u8; total_size as usize]));
 
-  let mut join_set = tokio::task::JoinSet::new();
-  for (path, offset, size) in block_files{
-    let block_data_clone = block_data.clone();
-    let path = path.to_owned();
-    let offset = *offset;
-    let size = *size;
-    join_set.spawn(async move{
-      write_file_to_buffer(block_data_clone, path, offset, size).await
-    });
+  /// Create the folder leaves matching `filter`, returning their names
+  fn extract_folder_leaves<F: Fn(&str) -> bool>(
+    &self,
+    filter: F,
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<Vec<String>, String>{
+    let names = self.folder_leaves.keys().filter(|x| filter(x)).cloned().collect::<Vec<_>>();
+    for name in &names{
+      let leaf_path = renamed_out_path(output_dir, name, options)?;
+      create_dirs(&leaf_path, options)
+        .map_err(|e| format!("at creating leaf dir {:?}: {e}", &leaf_path))?;
+    }
+    Ok(names)
   }
-  join_set.join_all().await;
-  let mut compressed_block_data = Vec::<u8>::new();
-  let block_data_lock = block_data.lock().await;
-  compress_utils::compress_data(&block_data_lock[..], &mut compressed_block_data, compression_type)?;
-  fs::write(output, &compressed_block_data).map_err(|e| format!("at writing: {e}"))?;
-  Ok(compressed_block_data.len() as _)
-} 
-
-fn create_archive_inner(
-  dir: &Path,
-  output: &Path,
-  compression_type: &str,
-  max_multi_block_size: Option<u64>
-) -> Result<(), String>{
-  let max_multi_block_size = max_multi_block_size.unwrap_or(DEFAULT_BLOCK_SIZE) as i64;
-  let (block_files, folder_leaves) = distribute_files_to_blocks(dir, max_multi_block_size);
-
-  let folder_leaf_infos = folder_leaves
-    .iter()
-    .map(|x| ArchiveFolderLeafEntry{
-      name: x
-        .strip_prefix(dir)
-        .unwrap_or(x)
-        .to_string_lossy()
-        .to_string()
-        .replace("\\", "/")
-    })
-    .collect::<Vec<_>>();
-  let mut file_infos = vec![];
-  for (i, in_files) in block_files.iter().enumerate(){
-    for (path, offset, size) in in_files{
-      let entry_name = path
-        .strip_prefix(dir)
-        .unwrap_or(path)
-        .to_string_lossy()
-        .to_string()
-        .replace("\\", "/");
-      file_infos.push(ArchiveFileEntry{
-        name: entry_name,
-        block: i as _,
-        offset: *offset,
-        size: *size
-      });
-    }
-  }
-
-  let async_rt = tokio::runtime::Builder::new_multi_thread()
-    .max_blocking_threads(128)
-    .enable_all()
-    .build()
-    .map_err(|e| format!("at building async runtime: {e}"))?;
-  let block_sizes = block_files
-    .into_par_iter()
-    .enumerate()
-    .map(|(i, x)| {
-      let block_path = output.with_extension(format!("temp.{i}"));
-      async_rt.block_on(compress_block(&block_path, &x, compression_type))
-    })
-    .collect::<Result<Vec<u64>, String>>()?;
-
-  let mut block_infos = vec![];
-  let mut curr_offset = 0;
-  for (i, size) in block_sizes.iter().enumerate(){
-    block_infos.push(ArchiveBlockInfo{
-      id: i as _,
-      size: *size as _,
-      offset: curr_offset,
-      compression_type: compression_type.to_string()
-    });
-    curr_offset += *size as i64;
-  }
-  let blob_path = output.with_extension("bdablob");
-  let mut fw = fs::File::create(&blob_path).map_err(|e| format!("at creating blob: {e}"))?;
-  for i in 0..block_infos.len(){
-    let block_path = output.with_extension(format!("temp.{i}"));
-    let mut fr = fs::File::open(&block_path).map_err(|e| format!("at reading block: {e}"))?;
-    io::copy(&mut fr, &mut fw).map_err(|e| format!("at writing blob: {e}"))?;
-    let _ = fs::remove_file(&block_path)
-      .inspect_err(|e| eprintln!("at removing temp file {:?}: {e}", &block_path));
-  }
-  fw.flush().map_err(|e| format!("at flushing blob: {e}"))?;
-
-  let db_path_name = output.with_extension("bdadb").to_string_lossy().to_string();
-  write_index_data(&db_path_name, file_infos, folder_leaf_infos, block_infos)
-    .map_err(|e| format!("at making index db: {e}"))?;
-
-  let mut fw = fs::File::create(output)
-    .map_err(|e| format!("at opening output file {output:?}: {e}"))?;
-  let mut compressed_index = Vec::<u8>::new();
-  let fr = fs::File::open(&db_path_name).map_err(|e| format!("at reading index db: {e}"))?;
-  compress_utils::compress_data(fr, &mut compressed_index, "LZ4")?;
-  fw
-    .write(&compressed_index.len().to_be_bytes())
-    .map_err(|e| format!("at writing index len: {e}"))?;
-  fw.write(&compressed_index).map_err(|e| format!("at writing index: {e}"))?;
-  let mut fr = fs::File::open(&blob_path).map_err(|e| format!("at reading blob: {e}"))?;
-  io::copy(&mut fr, &mut fw).map_err(|e| format!("at writing blob: {e}"))?;
-  fw.flush().map_err(|e| format!("at flushing to output: {e}"))?;
-  let _ = fs::remove_file(&db_path_name).inspect_err(|e| eprintln!("at removing index file: {e}"));
-  let _ = fs::remove_file(&blob_path).inspect_err(|e| eprintln!("at removing blob file: {e}"));
-  Ok(())
-}
 
-pub fn create_archive(
-  dir: &Path,
-  output: &Path,
-  compression_type: &str,
-  threads: u8,
-  block_size: Option<u64>
-) -> Result<(), String>{
-  let t_pool = rayon::ThreadPoolBuilder::new()
-    .num_threads(threads as _)
-    .build()
-    .map_err(|e| format!("at creating thread pool: {e}"))?;
-  t_pool.install(|| {create_archive_inner(dir, output, compression_type, block_size)})
-}
+  /// Recreate the hardlinks matching `filter`, returning their names. Links to files in
+  /// `extracted` are made against those, other links get a copy of their target's content
+  fn extract_hardlinks<F: Fn(&str) -> bool>(
+    &self,
+    filter: F,
+    extracted: &[String],
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<Vec<String>, String>{
+    let extracted = extracted.iter().map(|x| x.as_str()).collect::<HashSet<_>>();
+    let mut linked = vec![];
+    for link in self.hardlinks.iter().filter(|x| filter(&x.name)){
+      let res = (|| {
+        // Goes through the conflict policy like any other file before being replaced
+        let Some(fw) = create_entry_file(output_dir, &link.name, options)? else {
+          return Ok(false);
+        };
+        drop(fw);
+        let link_path = renamed_out_path(output_dir, &link.name, options)?;
+        if !extracted.contains(link.target.as_str()) {
+          return self.extract_file(&link.target, &link_path).map(|_| true);
+        }
+        let target_path = renamed_out_path(output_dir, &link.target, options)?;
+        fs::remove_file(&link_path).map_err(|e| format!("at replacing {link_path:?}: {e}"))?;
+        fs::hard_link(&target_path, &link_path)
+          .map_err(|e| format!("at linking {link_path:?} to {target_path:?}: {e}"))?;
+        Ok(true)
+      })();
+      match res {
+        Ok(true) => linked.push(link.name.clone()),
+        Ok(false) => {}
+        Err(e) if options.ignore_errors => log::warn!("at extracting {}: {e}", &link.name),
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(linked)
+  }
 
-pub fn decompress_archive(bda_path: &Path, out_dir: &Path) -> Result<(), String>{
-  let archive = ArchiveReader::new(bda_path, None).map_err(|e| format!("invalid archive: {e}"))?;
-  archive.extract_files(".*", out_dir, true).map_err(|e| format!("at extracting: {e}"))?;
-  Ok(())
+  /// Give the extracted entries `names` their stored owners, if `options.restore_ownership`.
+  /// Changing owners needs privileges, entries the process can't give away are silently skipped
+  #[cfg(unix)]
+  fn restore_owners(
+    &self,
+    names: &[String],
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<(), String>{
+    if !options.restore_ownership {
+      return Ok(());
+    }
+    for name in names{
+      let Some((uid, gid)) = self.owner(name) else {
+        continue;
+      };
+      let out_path = renamed_out_path(output_dir, name, options)?;
+      match std::os::unix::fs::chown(&out_path, Some(uid), Some(gid)) {
+        Ok(()) => {}
+        // Only root can give files away, the others keep the extracting user as owner
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied => continue,
+        Err(e) if options.ignore_errors => log::warn!("at restoring owner of {name}: {e}"),
+        Err(e) => return Err(format!("at restoring owner of {name}: {e}")),
+      }
+    }
+    Ok(())
+  }
+
+  #[cfg(not(unix))]
+  fn restore_owners(
+    &self,
+    _names: &[String],
+    _output_dir: &Path,
+    _options: &ExtractOptions
+  ) -> Result<(), String>{
+    Ok(())
+  }
+
+  /// Give the extracted entries `names` their stored flags, if `options.restore_file_flags`.
+  /// Done last, as flags like immutable block any later change to the entry
+  fn restore_file_flags(
+    &self,
+    names: &[String],
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<(), String>{
+    if !options.restore_file_flags {
+      return Ok(());
+    }
+    for name in names{
+      let Some(flags) = self.file_flags(name) else {
+        continue;
+      };
+      let out_path = renamed_out_path(output_dir, name, options)?;
+      match flag_utils::set_flags(&out_path, flags) {
+        Ok(()) => {}
+        Err(e) if flag_utils::is_unsupported(&e) => continue,
+        Err(e) if options.ignore_errors => log::warn!("at restoring flags of {name}: {e}"),
+        Err(e) => return Err(format!("at restoring flags of {name}: {e}")),
+      }
+    }
+    Ok(())
+  }
+
+  /// Extract files that all live in block `block_id`, decompressing the block once. With a
+  /// `pool`, the files of a block held in memory are written in parallel
+  fn extract_block_entries(
+    &self,
+    block_id: i64,
+    file_infos: &[&ArchiveFileEntry],
+    output_dir: &Path,
+    options: &ExtractOptions,
+    pool: Option<&rayon::ThreadPool>
+  ) -> Result<Vec<String>, String>{
+    let mut extracted = vec![];
+    let block_size = &self.block_infos[block_id as usize];
+    if block_size.size > self.max_mem_extract_size {
+      let t_file = tempfile::NamedTempFile::new()
+        .map_err(|e| format!("at creating tempfile: {e}"))?;
+      self.extract_block_file(block_id, t_file.path())?;
+      for file_info in file_infos{
+        let res = (|| {
+          let Some(mut fw) = create_entry_file(output_dir, &file_info.name, options)? else {
+            return Ok(false);
+          };
+          let mut fr = fs::File::open(t_file.path())
+            .map_err(|e| format!("at opening temp file: {e}"))?;
+          fr
+            .seek(io::SeekFrom::Start(file_info.offset as u64))
+            .map_err(|e| format!("at seeking in tempfile: {e}"))?;
+          let mut fr = fr.take(file_info.size as u64);
+          io::copy(&mut fr, &mut fw).map_err(|e| format!("at writing: {e}"))?;
+          fw.flush().map_err(|e| format!("at flushing: {e}"))?;
+          Ok(true)
+        })();
+        match res {
+          Ok(true) => extracted.push(file_info.name.clone()),
+          Ok(false) => {}
+          Err(e) if options.ignore_errors => log::warn!("at extracting {}: {e}", &file_info.name),
+          Err(e) => return Err(e),
+        }
+      }
+    } else {
+      let block_data = self.extract_block_mem(block_id)?;
+      let write_file = |file_info: &&ArchiveFileEntry| -> Result<bool, String> {
+        let Some(mut fw) = create_entry_file(output_dir, &file_info.name, options)? else {
+          return Ok(false);
+        };
+        fw
+          .write_all(file_slice(&block_data, file_info)?)
+          .map_err(|e| format!("at writing :{e}"))?;
+        fw.flush().map_err(|e| format!("at flushing: {e}"))?;
+        Ok(true)
+      };
+      let mut record = |file_info: &ArchiveFileEntry, res| {
+        match res {
+          Ok(true) => extracted.push(file_info.name.clone()),
+          Ok(false) => {}
+          Err(e) if options.ignore_errors => log::warn!("at extracting {}: {e}", &file_info.name),
+          Err(e) => return Err(e),
+        }
+        Ok(())
+      };
+      match pool {
+        Some(pool) => {
+          let results = pool.install(|| file_infos.par_iter().map(write_file).collect::<Vec<_>>());
+          for (file_info, res) in file_infos.iter().zip(results){
+            record(file_info, res)?;
+          }
+        }
+        None => {
+          for file_info in file_infos{
+            record(file_info, write_file(file_info))?;
+          }
+        }
+      }
+    }
+    Ok(extracted)
+  }
+
+  /// Extract the entries matching `re_pattern` into `output_dir`, stopping at the first file
+  /// that fails. `ignore_errors` is only kept for compatibility and changes nothing, see
+  /// `ExtractOptions::ignore_errors` to carry on past failures
+  pub fn extract_files(
+    &self,
+    re_pattern: &str,
+    output_dir: &Path,
+    _ignore_errors: bool
+  ) -> Result<(), String>{
+    self.extract_files_with_options(re_pattern, output_dir, &ExtractOptions::default())
+  }
+
+  /// Extract the entries matching `re_pattern` into `output_dir`. See `ExtractOptions` for how
+  /// existing contents of `output_dir` are treated
+  pub fn extract_files_with_options(
+    &self,
+    re_pattern: &str,
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<(), String>{
+    let re_obj = regex::Regex::new(re_pattern).map_err(|e| format!("invalid regex: {e}"))?;
+    self.extract_matching(|x| re_obj.is_match(x), output_dir, options)
+  }
+
+  /// Extract the entries matching any of the `includes` regexes and none of the `excludes`.
+  /// No includes means every entry is included
+  pub fn extract_files_multi(
+    &self,
+    includes: &[String],
+    excludes: &[String],
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<(), String>{
+    let includes = regex::RegexSet::new(includes).map_err(|e| format!("invalid regex: {e}"))?;
+    let excludes = regex::RegexSet::new(excludes).map_err(|e| format!("invalid regex: {e}"))?;
+    let filter = |x: &str| {
+      (includes.is_empty() || includes.is_match(x)) && !excludes.is_match(x)
+    };
+    self.extract_matching(filter, output_dir, options)
+  }
+
+  /// Extract the files with extension `ext`, given with or without the leading dot
+  pub fn extract_by_extension(&self, ext: &str, output_dir: &Path) -> Result<(), String>{
+    self.extract_files(&extension_pattern(ext), output_dir, false)
+  }
+
+  /// Extract everything under any directory named `component`, at any depth
+  pub fn extract_under(&self, component: &str, output_dir: &Path) -> Result<(), String>{
+    self.extract_files(&under_pattern(component), output_dir, false)
+  }
+
+  /// Extract the entries matching `match_re`, each to the path its name gets with the first
+  /// match of `replace_re` replaced by `replacement`, e.g. `^build/` and `` to drop a leading
+  /// folder or `(\d+)` and `v$1` to prefix numbers. See `ExtractOptions::rename`
+  pub fn extract_files_rename(
+    &self,
+    match_re: &str,
+    replace_re: &str,
+    replacement: &str,
+    output_dir: &Path
+  ) -> Result<(), String>{
+    let pattern = regex::Regex::new(replace_re).map_err(|e| format!("invalid regex: {e}"))?;
+    let rename = Rename { pattern, replacement: replacement.to_string() };
+    let options = ExtractOptions { rename: Some(rename), ..Default::default() };
+    self.extract_files_with_options(match_re, output_dir, &options)
+  }
+
+  /// Fail if `options.rename` gives entries matching `filter` the same output path, listing
+  /// them. Empty folders renamed to the same path are merged instead
+  fn check_rename_collisions<F: Fn(&str) -> bool>(
+    &self,
+    filter: F,
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<(), String>{
+    if options.rename.is_none() {
+      return Ok(());
+    }
+    let files = self.files.iter().map(|x| (x.name.as_str(), false));
+    let leaves = self.folder_leaves.keys().map(|x| (x.as_str(), true));
+    let links = self.hardlinks.iter().map(|x| (x.name.as_str(), false));
+    let mut seen = HashMap::<PathBuf, (&str, bool)>::new();
+    let mut collisions = vec![];
+    for (name, is_leaf) in files.chain(leaves).chain(links).filter(|(x, _)| filter(x)){
+      // Extraction reports the names that don't make a path
+      let Ok(out_path) = renamed_out_path(output_dir, name, options) else {
+        continue;
+      };
+      match seen.get(&out_path) {
+        Some((_, other_is_leaf)) if is_leaf && *other_is_leaf => {}
+        Some((other, _)) => collisions.push(format!("{out_path:?} ({other} and {name})")),
+        None => {
+          seen.insert(out_path, (name, is_leaf));
+        }
+      }
+    }
+    if !collisions.is_empty() {
+      let collisions = collisions.join(", ");
+      return Err(format!("entries are renamed to the same path: {collisions}"));
+    }
+    Ok(())
+  }
+
+  fn extract_matching<F: Fn(&str) -> bool>(
+    &self,
+    filter: F,
+    output_dir: &Path,
+    options: &ExtractOptions
+  ) -> Result<(), String>{
+    self.check_rename_collisions(&filter, output_dir, options)?;
+    let leaves = self.extract_folder_leaves(&filter, output_dir, options)?;
+    let pool = write_pool(options)?;
+    let mut extracted = vec![];
+    for (block_id, file_ids) in &self.block_files{
+      let file_infos = file_ids
+        .iter()
+        .map(|i| &self.files[*i])
+        .filter(|x| filter(&x.name))
+        .collect::<Vec<_>>();
+      if file_infos.is_empty(){
+        continue;
+      }
+      extracted.extend(
+        self.extract_block_entries(*block_id, &file_infos, output_dir, options, pool.as_ref())?
+      );
+    }
+    extracted.extend(self.extract_hardlinks(&filter, &extracted, output_dir, options)?);
+    self.restore_owners(&leaves, output_dir, options)?;
+    self.restore_owners(&extracted, output_dir, options)?;
+    self.restore_file_flags(&leaves, output_dir, options)?;
+    self.restore_file_flags(&extracted, output_dir, options)?;
+    self.write_manifest(extracted, options)
+  }
+
+  /// Extract every entry of the archive, going through the blocks in order so only one block
+  /// is held at a time
+  pub fn extract_all(&self, output_dir: &Path, options: &ExtractOptions) -> Result<(), String>{
+    self.check_rename_collisions(|_| true, output_dir, options)?;
+    // Made even if there's nothing to put in it
+    create_dirs(output_dir, options).map_err(|e| format!("at creating {output_dir:?}: {e}"))?;
+    let leaves = self.extract_folder_leaves(|_| true, output_dir, options)?;
+    let pool = write_pool(options)?;
+    let mut extracted = vec![];
+    for block_id in 0..self.block_infos.len() as i64{
+      let Some(file_ids) = self.block_files.get(&block_id) else {
+        continue;
+      };
+      let file_infos = file_ids.iter().map(|i| &self.files[*i]).collect::<Vec<_>>();
+      extracted.extend(
+        self.extract_block_entries(block_id, &file_infos, output_dir, options, pool.as_ref())?
+      );
+    }
+    extracted.extend(self.extract_hardlinks(|_| true, &extracted, output_dir, options)?);
+    self.restore_owners(&leaves, output_dir, options)?;
+    self.restore_owners(&extracted, output_dir, options)?;
+    self.restore_file_flags(&leaves, output_dir, options)?;
+    self.restore_file_flags(&extracted, output_dir, options)?;
+    self.write_manifest(extracted, options)
+  }
+
+  /// Write the CSV manifest of `options.manifest`, if set, for the extracted files `names`.
+  /// Columns are name, size, hash and hash algorithm, the last two empty for unhashed files
+  fn write_manifest(&self, mut names: Vec<String>, options: &ExtractOptions) -> Result<(), String>{
+    let Some(manifest_path) = &options.manifest else {
+      return Ok(());
+    };
+    // Names can hold commas or quotes, so every text field is quoted
+    let quote = |x: &str| format!("\"{}\"", x.replace('"', "\"\""));
+    names.sort();
+    let mut manifest = String::from("name,size,hash,hash_algorithm\n");
+    for name in &names {
+      // Hardlinks are listed with their target's content
+      let file = self.hardlink_target(name).unwrap_or(name);
+      let size = self.file_size(file).unwrap_or(0);
+      let (hash, algorithm) = self
+        .file_hash(file)
+        .map(|(hash, algorithm)| (hash, algorithm.as_str()))
+        .unwrap_or_default();
+      manifest.push_str(&format!("{},{size},{hash},{algorithm}\n", quote(name)));
+    }
+    fs::write(manifest_path, manifest)
+      .map_err(|e| format!("at writing manifest {manifest_path:?}: {e}"))
+  }
+
+}
+
+/// Extract everything in the archive at `bda_path` into `out_dir`, stopping at the first file
+/// that fails
+pub fn decompress_archive(bda_path: &Path, out_dir: &Path) -> Result<(), String>{
+  decompress_archive_with_options(bda_path, out_dir, &ExtractOptions::default())
+}
+
+pub fn decompress_archive_with_options(
+  bda_path: &Path,
+  out_dir: &Path,
+  options: &ExtractOptions
+) -> Result<(), String>{
+  let archive = ArchiveReader::new(bda_path, None).map_err(|e| format!("invalid archive: {e}"))?;
+  archive.extract_all(out_dir, options).map_err(|e| format!("at extracting: {e}"))?;
+  Ok(())
+}
+
+/// Find the blocks of a blob written with `CreateOptions::recoverable`, without the index.
+/// Returns the offset and size of every block's data, relative to the start of the blob
+/// In archives of format version 2 and later the data starts with the block's compression id
+pub fn scan_blocks<R: Read>(mut blob: R) -> Result<Vec<(u64, u64)>, String>{
+  let mut blocks = vec![];
+  let mut offset = 0u64;
+  loop {
+    let mut frame = [0u8; BLOCK_FRAME_LEN];
+    let read_len = blob
+      .read(&mut frame[..1])
+      .map_err(|e| format!("at reading block frame at {offset}: {e}"))?;
+    if read_len == 0 {
+      break;
+    }
+    blob
+      .read_exact(&mut frame[1..])
+      .map_err(|e| format!("at reading block frame at {offset}: {e}"))?;
+    if frame[..BLOCK_FRAME_MAGIC.len()] != BLOCK_FRAME_MAGIC {
+      return Err(format!("no block frame at {offset}"));
+    }
+    let mut size_bytes = [0u8; 8];
+    size_bytes.copy_from_slice(&frame[BLOCK_FRAME_MAGIC.len()..]);
+    let size = u64::from_be_bytes(size_bytes);
+    offset += BLOCK_FRAME_LEN as u64;
+    let skipped = io::copy(&mut (&mut blob).take(size), &mut io::sink())
+      .map_err(|e| format!("at skipping block at {offset}: {e}"))?;
+    if skipped != size {
+      return Err(format!("block at {offset} truncated, expected {size} bytes, got {skipped}"));
+    }
+    blocks.push((offset, size));
+    offset += size;
+  }
+  Ok(blocks)
+}
+
+/// Result of compressing a sample with one compression type and level
+#[derive(Debug, Clone)]
+pub struct BenchResult {
+  pub compression: String,
+  /// None for the type's default level
+  pub level: Option<u32>,
+  pub input_size: u64,
+  pub output_size: u64,
+  pub ratio: f64,
+  /// Uncompressed megabytes processed per second
+  pub mb_per_sec: f64,
+}
+
+const BENCH_LEVELS: [(&str, Option<u32>); 12] = [
+  // Baseline for the others
+  ("NONE", None),
+  ("SNAPPY", None),
+  ("LZ4", None),
+  ("ZSTD", Some(1)),
+  ("ZSTD", Some(3)),
+  ("ZSTD", Some(6)),
+  ("ZSTD", Some(12)),
+  ("LZMA", Some(1)),
+  ("LZMA", Some(6)),
+  ("LZMA", Some(9)),
+  ("BZIP2", Some(1)),
+  ("BZIP2", Some(9)),
+];
+
+/// Compress the first `sample_size` bytes of the files under `dir` (in path order) with every
+/// supported compression type and a few levels, `NONE` included as the baseline. Nothing is
+/// written to disk. Results are sorted by compression ratio, best first
+pub fn bench_compressions(dir: &Path, sample_size: u64) -> Result<Vec<BenchResult>, String>{
+  let mut file_paths = walkdir::WalkDir::new(dir)
+    .into_iter()
+    .filter_map(|x| x.ok())
+    .filter(|x| x.file_type().is_file())
+    .map(|x| x.path().to_owned())
+    .collect::<Vec<_>>();
+  file_paths.sort();
+  let mut sample = vec![];
+  for path in file_paths{
+    let remaining = sample_size.saturating_sub(sample.len() as u64);
+    if remaining == 0 {
+      break;
+    }
+    let fr = fs::File::open(&path).map_err(|e| format!("at opening {path:?}: {e}"))?;
+    fr.take(remaining).read_to_end(&mut sample).map_err(|e| format!("at reading {path:?}: {e}"))?;
+  }
+
+  let mut results = vec![];
+  for (compression, level) in BENCH_LEVELS{
+    let mut compressed = vec![];
+    let start = Instant::now();
+    compress_utils::compress_data(&sample[..], &mut compressed, compression, level)
+      .map_err(|e| format!("at compressing sample with {compression}: {e}"))?;
+    let elapsed = start.elapsed().as_secs_f64().max(f64::EPSILON);
+    results.push(BenchResult {
+      compression: compression.to_string(),
+      level,
+      input_size: sample.len() as u64,
+      output_size: compressed.len() as u64,
+      ratio: sample.len() as f64 / compressed.len().max(1) as f64,
+      mb_per_sec: sample.len() as f64 / (1024.0 * 1024.0) / elapsed,
+    });
+  }
+  results.sort_by(|a, b| b.ratio.total_cmp(&a.ratio));
+  Ok(results)
+}
+
+#[cfg(all(test, feature = "writer"))]
+mod tests {
+  use super::*;
+  use crate::test_utils::{archive_dir, capture_warnings, list_tree, warnings_about, write_tree};
+
+  #[test]
+  fn block_files_cover_every_file_once(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc"), ("b/d.rs", b"dddddd")]);
+    // Tiny blocks so the files are spread over several
+    let (_, reader) = archive_dir(&input, work.path(), Some(4), &CreateOptions::default());
+
+    let mut ids = reader.block_files.values().flatten().copied().collect::<Vec<_>>();
+    ids.sort();
+    assert_eq!(ids, (0..reader.files.len()).collect::<Vec<_>>());
+    for (block_id, file_ids) in &reader.block_files {
+      assert!(file_ids.iter().all(|i| reader.files[*i].block == *block_id));
+    }
+  }
+
+  #[test]
+  fn repeated_extractions_reuse_block_index(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc"), ("b/d.rs", b"dddddd")]);
+    let (_, reader) = archive_dir(&input, work.path(), Some(4), &CreateOptions::default());
+
+    let out_txt = work.path().join("txt");
+    reader.extract_files(r"\.txt$", &out_txt, false).unwrap();
+    let out_rs = work.path().join("rs");
+    reader.extract_files(r"\.rs$", &out_rs, false).unwrap();
+    assert_eq!(fs::read(out_txt.join("a.txt")).unwrap(), b"aaaa");
+    assert_eq!(fs::read(out_txt.join("b/c.txt")).unwrap(), b"cc");
+    assert!(!out_txt.join("b/d.rs").exists());
+    assert_eq!(fs::read(out_rs.join("b/d.rs")).unwrap(), b"dddddd");
+    assert!(!out_rs.join("a.txt").exists());
+  }
+
+  #[test]
+  fn extract_files_stops_at_first_failure(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b.txt", b"bb")]);
+    let (archive_path, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    // A folder where a file should go can't be overwritten
+    let out = work.path().join("out");
+    fs::create_dir_all(out.join("a.txt")).unwrap();
+    assert!(reader.extract_files(".*", &out, true).is_err());
+    assert!(decompress_archive(&archive_path, &out).is_err());
+
+    let options = ExtractOptions { ignore_errors: true, ..Default::default() };
+    reader.extract_files_with_options(".*", &out, &options).unwrap();
+    assert_eq!(fs::read(out.join("b.txt")).unwrap(), b"bb");
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn extraction_replaces_symlinked_files(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"from archive")]);
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    let outside = work.path().join("outside.txt");
+    fs::write(&outside, b"untouched").unwrap();
+    let out = work.path().join("out");
+    fs::create_dir_all(&out).unwrap();
+    std::os::unix::fs::symlink(&outside, out.join("a.txt")).unwrap();
+
+    reader.extract_all(&out, &ExtractOptions::default()).unwrap();
+    assert_eq!(fs::read(&outside).unwrap(), b"untouched");
+    assert!(!fs::symlink_metadata(out.join("a.txt")).unwrap().file_type().is_symlink());
+    assert_eq!(fs::read(out.join("a.txt")).unwrap(), b"from archive");
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn extraction_refuses_symlinked_folders(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("sub/x.txt", b"from archive")]);
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    let outside = work.path().join("outside");
+    fs::create_dir_all(&outside).unwrap();
+    let out = work.path().join("out");
+    fs::create_dir_all(&out).unwrap();
+    std::os::unix::fs::symlink(&outside, out.join("sub")).unwrap();
+
+    let err = reader.extract_all(&out, &ExtractOptions::default()).err().unwrap();
+    assert!(err.contains("symlink"), "{err}");
+    assert!(!outside.join("x.txt").exists());
+  }
+
+  #[test]
+  fn decompressed_blocks_are_preallocated_to_their_size(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let big = b"compressible ".repeat(10_000);
+    write_tree(&input, &[("a.txt", &big), ("b/c.txt", b"cc"), ("b/d.rs", &big[..999])]);
+    let (_, reader) = archive_dir(&input, work.path(), Some(64 * 1024), &CreateOptions::default());
+
+    for block in reader.blocks() {
+      let data = reader.extract_block_mem(block.id as i64).unwrap();
+      assert!(block.compressed_size < block.raw_size);
+      assert_eq!(data.len() as u64, block.raw_size);
+      // Filled without ever growing the buffer
+      assert_eq!(data.capacity(), data.len());
+    }
+  }
+
+  #[test]
+  fn block_marker_catches_a_mismatched_index(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    let (_, mut reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    reader.block_infos[0].compression_type = "LZ4".to_string();
+
+    let err = reader.read_file("a.txt").unwrap_err();
+    assert_eq!(err, "block 0: index says LZ4, marker says ZSTD");
+  }
+
+  #[test]
+  fn estimated_sizes_add_up_to_their_block(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (1..40).map(|i| (format!("f{i}"), "x7".repeat(i * 37))).collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content.as_bytes())]);
+    }
+    let (_, reader) = archive_dir(&input, work.path(), Some(8 * 1024), &CreateOptions::default());
+
+    assert!(reader.block_count() > 1);
+    for block in reader.blocks() {
+      let estimates = reader
+        .files_in_block(block.id)
+        .iter()
+        .map(|x| reader.estimated_compressed_size(x).unwrap())
+        .sum::<u64>();
+      assert_eq!(estimates, block.compressed_size);
+    }
+    assert_eq!(reader.estimated_compressed_size("missing"), None);
+  }
+
+  #[test]
+  fn includes_apply_before_excludes(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[
+      ("src/lib.rs", b"lib"),
+      ("tests/it.rs", b"it"),
+      ("Cargo.toml", b"toml"),
+      ("README.md", b"readme"),
+    ]);
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    let extract = |includes: &[&str], excludes: &[&str]| {
+      let out = tempfile::tempdir().unwrap();
+      let includes = includes.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+      let excludes = excludes.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+      let options = ExtractOptions::default();
+      reader.extract_files_multi(&includes, &excludes, out.path(), &options).unwrap();
+      list_tree(out.path())
+    };
+
+    assert_eq!(extract(&[r"\.rs$", r"\.toml$"], &["^tests/"]), ["Cargo.toml", "src/lib.rs"]);
+    // An exclude wins over an include of the same entry
+    assert_eq!(extract(&[r"\.rs$"], &[r"\.rs$"]), Vec::<String>::new());
+    assert_eq!(extract(&[], &[r"\.rs$"]), ["Cargo.toml", "README.md"]);
+    assert_eq!(extract(&[], &[]), ["Cargo.toml", "README.md", "src/lib.rs", "tests/it.rs"]);
+  }
+
+  #[test]
+  fn extension_and_folder_helpers(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[
+      ("logo.png", b"png"),
+      ("img/icon.PNG", b"upper"),
+      ("img/photo.png.bak", b"bak"),
+      ("web/node_modules/left-pad/index.js", b"pad"),
+      ("node_modules/x.png", b"x"),
+      ("my_node_modules/y.js", b"y"),
+    ]);
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    let out = tempfile::tempdir().unwrap();
+    reader.extract_by_extension(".png", out.path()).unwrap();
+    assert_eq!(list_tree(out.path()), ["logo.png", "node_modules/x.png"]);
+    let out = tempfile::tempdir().unwrap();
+    reader.extract_under("node_modules", out.path()).unwrap();
+    assert_eq!(list_tree(out.path()), ["node_modules/x.png", "web/node_modules/left-pad/index.js"]);
+  }
+
+  #[test]
+  fn manifest_lists_the_extracted_files(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c, \"d\".txt", b"cc"), ("e.rs", b"eeeeee")]);
+    let options = CreateOptions { hash_files: true, ..Default::default() };
+    let (_, reader) = archive_dir(&input, work.path(), None, &options);
+
+    let out = tempfile::tempdir().unwrap();
+    let manifest_path = work.path().join("manifest.csv");
+    let options = ExtractOptions { manifest: Some(manifest_path.clone()), ..Default::default() };
+    reader.extract_files_with_options(r"\.txt$", out.path(), &options).unwrap();
+
+    let manifest = fs::read_to_string(manifest_path).unwrap();
+    let hash = |name: &str| reader.file_hash(name).unwrap().0.to_string();
+    let algorithm = reader.file_hash("a.txt").unwrap().1.as_str();
+    let expected = [
+      "name,size,hash,hash_algorithm".to_string(),
+      format!("\"a.txt\",4,{},{algorithm}", hash("a.txt")),
+      format!("\"b/c, \"\"d\"\".txt\",2,{},{algorithm}", hash("b/c, \"d\".txt")),
+    ];
+    let expected = expected.map(|x| x + "\n").concat();
+    assert_eq!(manifest, expected);
+    assert_eq!(list_tree(out.path()), ["a.txt", "b/c, \"d\".txt"]);
+  }
+
+  #[cfg(windows)]
+  #[test]
+  fn deep_paths_extract_on_windows(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    // Folders past the 248 characters directory creation allows, and a file past MAX_PATH
+    let deep_dir = ["a-fairly-long-folder-name-to-get-deep-quickly"; 7].join("/");
+    let name = format!("{deep_dir}/{}.txt", "f".repeat(40));
+    let long_input = long_path(input.join(&name));
+    fs::create_dir_all(long_input.parent().unwrap()).unwrap();
+    fs::write(&long_input, b"deep").unwrap();
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    let out = work.path().join("out");
+    assert!(out.join(&name).as_os_str().len() > 260);
+    reader.extract_all(&out, &ExtractOptions::default()).unwrap();
+    assert_eq!(fs::read(long_path(out.join(&name))).unwrap(), b"deep");
+    assert!(long_path(out.join(&name)).to_string_lossy().starts_with(r"\\?\"));
+  }
+
+  #[test]
+  fn low_level_block_reads_match_extraction(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", &b"aaaa".repeat(300)), ("b/c.txt", b"cc"), ("d.rs", b"dddddd")]);
+    let (_, reader) = archive_dir(&input, work.path(), Some(1024), &CreateOptions::default());
+
+    assert!(reader.block_count() > 1);
+    for block in reader.blocks() {
+      let decompressed = reader.decompress_block(block.id).unwrap();
+      assert_eq!(decompressed, reader.extract_block_mem(block.id as i64).unwrap());
+      let raw = reader.read_block_raw(block.id).unwrap();
+      assert_eq!(raw.len() as u64, block.compressed_size);
+      assert_eq!(raw[0], compress_utils::compression_id("ZSTD").unwrap());
+      let mut from_raw = vec![];
+      compress_utils::decompress_data(&raw[1..], &mut from_raw, "ZSTD").unwrap();
+      assert_eq!(from_raw, decompressed);
+    }
+    let missing = reader.block_count() as u64;
+    assert!(reader.decompress_block(missing).is_err());
+    assert!(reader.read_block_raw(missing).is_err());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn owners_round_trip(){
+    use std::os::unix::fs::MetadataExt;
+
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    let options = CreateOptions { store_ownership: true, ..Default::default() };
+    let (_, mut reader) = archive_dir(&input, work.path(), None, &options);
+
+    let metadata = fs::metadata(input.join("b/c.txt")).unwrap();
+    assert_eq!(reader.owner("b/c.txt"), Some((metadata.uid(), metadata.gid())));
+    assert!(reader.header.feature_names().contains(&"owners"));
+
+    // Owners only root can hand out. Without root each entry is skipped on its own
+    for owner in reader.owners.values_mut() {
+      *owner = (4242, 4343);
+    }
+    let out = tempfile::tempdir().unwrap();
+    let options = ExtractOptions { restore_ownership: true, ..Default::default() };
+    reader.extract_all(out.path(), &options).unwrap();
+    let is_root = unsafe { libc::geteuid() } == 0;
+    for name in ["a.txt", "b/c.txt"] {
+      let metadata = fs::metadata(out.path().join(name)).unwrap();
+      let expected = if is_root { (4242, 4343) } else { (metadata.uid(), metadata.gid()) };
+      assert_eq!((metadata.uid(), metadata.gid()), expected, "{name}");
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn hardlinks_are_stored_once_and_relinked(){
+    use std::os::unix::fs::MetadataExt;
+
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a/original.bin", &b"shared content".repeat(100)), ("b.txt", b"b")]);
+    fs::hard_link(input.join("a/original.bin"), input.join("link.bin")).unwrap();
+    let options = CreateOptions { detect_hardlinks: true, ..Default::default() };
+    let (_, reader) = archive_dir(&input, work.path(), None, &options);
+
+    assert_eq!(reader.files.len(), 2);
+    assert_eq!(reader.hardlink_target("link.bin"), Some("a/original.bin"));
+    let stored = reader.blocks().iter().map(|x| x.raw_size).sum::<u64>();
+    assert_eq!(stored, 1401);
+    assert!(reader.list_files().contains(&"link.bin".to_string()));
+
+    let out = tempfile::tempdir().unwrap();
+    reader.extract_all(out.path(), &ExtractOptions::default()).unwrap();
+    let original = fs::metadata(out.path().join("a/original.bin")).unwrap();
+    let link = fs::metadata(out.path().join("link.bin")).unwrap();
+    assert_eq!(original.ino(), link.ino());
+    assert_eq!(fs::read(out.path().join("link.bin")).unwrap(), b"shared content".repeat(100));
+  }
+
+  #[test]
+  fn verify_against_dir_finds_differences(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc"), ("d.txt", b"dddd")]);
+    let options = CreateOptions { hash_files: true, ..Default::default() };
+    let (_, reader) = archive_dir(&input, work.path(), None, &options);
+    assert!(reader.verify_against_dir(&input).unwrap().is_match());
+
+    fs::remove_file(input.join("b/c.txt")).unwrap();
+    write_tree(&input, &[("a.txt", b"aaaaa"), ("d.txt", b"DDDD"), ("new.txt", b"new")]);
+    let report = reader.verify_against_dir(&input).unwrap();
+    assert_eq!(report.missing, ["b/c.txt"]);
+    assert_eq!(report.extra, ["new.txt"]);
+    // d.txt kept its size and is told apart by its hash
+    assert_eq!(report.changed, ["a.txt", "d.txt"]);
+    assert!(!report.is_match());
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn symlinked_files_verify_like_they_were_stored(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    write_tree(work.path(), &[("outside.txt", b"linked")]);
+    std::os::unix::fs::symlink(work.path().join("outside.txt"), input.join("link.txt")).unwrap();
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    assert_eq!(reader.read_file("link.txt").unwrap(), b"linked");
+    let report = reader.verify_against_dir(&input).unwrap();
+    assert!(report.is_match(), "{report:?}");
+  }
+
+  #[test]
+  fn lenient_reading_recovers_a_wrong_index_compression(){
+    capture_warnings();
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    let options = CreateOptions {
+      index_compression: Some("LZMA".to_string()),
+      ..Default::default()
+    };
+    let (archive_path, reader) = archive_dir(&input, work.path(), None, &options);
+    let mut header = reader.header.clone();
+    header.index_compression = "BZIP2".to_string();
+    let mut data = fs::read(&archive_path).unwrap();
+    header.write(&mut data[..header.size() as usize]).unwrap();
+    fs::write(&archive_path, data).unwrap();
+
+    let err = ArchiveReader::new(&archive_path, None).err().unwrap();
+    assert!(err.starts_with("at decompressing index data"), "{err}");
+    let options = ReaderOptions { lenient: true, ..Default::default() };
+    let reader = ArchiveReader::with_options(&archive_path, &options).unwrap();
+    assert_eq!(reader.index_compression(), "LZMA");
+    assert_eq!(reader.read_file("b/c.txt").unwrap(), b"cc");
+    let warnings = warnings_about("not BZIP2 as the header says");
+    assert_eq!(warnings, ["index is compressed with LZMA, not BZIP2 as the header says"]);
+  }
+
+  #[test]
+  fn many_small_files_of_a_block_extract_in_parallel(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    // Spread over a few folders so several threads create the same ones at once
+    let files = (0..2000)
+      .map(|i| (format!("d{}/s{}/f{i}", i % 5, i % 3), i.to_string()))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content.as_bytes())]);
+    }
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    assert_eq!(reader.block_count(), 1);
+
+    let out = tempfile::tempdir().unwrap();
+    let options = ExtractOptions { threads: 8, ..Default::default() };
+    reader.extract_files_with_options(".*", out.path(), &options).unwrap();
+    for (name, content) in &files {
+      assert_eq!(fs::read(out.path().join(name)).unwrap(), content.as_bytes());
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn file_flags_round_trip(){
+    // Settable by the owner, unlike immutable or append-only
+    let nodump = if cfg!(target_os = "linux") { 0x40 } else { 0x1 };
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"a"), ("z.txt", b"zzzzzzzz")]);
+    for name in ["a.txt", "z.txt"] {
+      if flag_utils::set_flags(&input.join(name), nodump).is_err() {
+        // Filesystem without flags
+        return;
+      }
+    }
+    let options = CreateOptions { store_file_flags: true, ..Default::default() };
+    let (_, mut reader) = archive_dir(&input, work.path(), None, &options);
+    assert_eq!(reader.file_flags("z.txt"), Some(nodump));
+
+    let is_root = unsafe { libc::geteuid() } == 0;
+    if cfg!(target_os = "linux") && !is_root {
+      // Immutable needs privileges, failing to set it only skips that entry
+      reader.file_flags.insert("a.txt".to_string(), 0x10);
+    }
+    let out = tempfile::tempdir().unwrap();
+    let options = ExtractOptions { restore_file_flags: true, ..Default::default() };
+    reader.extract_all(out.path(), &options).unwrap();
+    assert_eq!(flag_utils::get_flags(&out.path().join("z.txt")).unwrap() & nodump, nodump);
+  }
+
+  #[test]
+  fn streaming_fails_on_a_hash_mismatch_once_written(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b.txt", b"bbbb")]);
+    let options = CreateOptions { hash_files: true, ..Default::default() };
+    let (_, mut reader) = archive_dir(&input, work.path(), None, &options);
+    let mut out = vec![];
+    reader.extract_to_writer("a.txt", &mut out, true).unwrap();
+    assert_eq!(out, b"aaaa");
+
+    let (stored_b, _) = reader.file_hashes["b.txt"].clone();
+    reader.file_hashes.get_mut("a.txt").unwrap().0 = stored_b;
+    // In memory and through a temp file for blocks over the memory limit
+    for max_mem_extract_size in [i64::MAX, 0] {
+      reader.max_mem_extract_size = max_mem_extract_size;
+      let mut out = vec![];
+      let err = reader.extract_to_writer("a.txt", &mut out, true).unwrap_err();
+      assert_eq!(err, "a.txt doesn't match its hash");
+      assert_eq!(out, b"aaaa");
+      let mut out = vec![];
+      reader.extract_to_writer("a.txt", &mut out, false).unwrap();
+      assert_eq!(out, b"aaaa");
+    }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn created_dirs_are_repaired_before_anything_goes_in_them(){
+    use std::os::unix::fs::PermissionsExt;
+
+    // The umask is per process, so the test runs itself again alone with a restrictive one
+    const CHILD_VAR: &str = "BLODA_TEST_RESTRICTIVE_UMASK";
+    if std::env::var_os(CHILD_VAR).is_none() {
+      let output = std::process::Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "tests::created_dirs_are_repaired_before_anything_goes_in_them"])
+        .env(CHILD_VAR, "1")
+        .output()
+        .unwrap();
+      assert!(output.status.success(), "{}", String::from_utf8_lossy(&output.stdout));
+      return;
+    }
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a/b/c.txt", b"c"), ("a/d.txt", b"d"), ("e/f/g.txt", b"g")]);
+    fs::create_dir_all(input.join("h/i")).unwrap();
+    let (archive_path, _) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    let out = work.path().join("out");
+    let unrepaired = work.path().join("unrepaired");
+    let old_umask = unsafe { libc::umask(0o277) };
+    // Opened under the umask too, as that writes the index to a temp file
+    let options = ExtractOptions { repair_permissions: true, threads: 4, ..Default::default() };
+    let results = ArchiveReader::new(&archive_path, None).map(|reader| {
+      let unrepaired_res = reader.extract_all(&unrepaired, &ExtractOptions::default());
+      (reader.extract_all(&out, &options), unrepaired_res)
+    });
+    unsafe { libc::umask(old_umask) };
+
+    let (res, unrepaired_res) = results.unwrap();
+    res.unwrap();
+    assert_eq!(list_tree(&out), ["a/b/c.txt", "a/d.txt", "e/f/g.txt"]);
+    for dir in ["", "a", "a/b", "e", "e/f", "h", "h/i"] {
+      let mode = fs::metadata(out.join(dir)).unwrap().permissions().mode();
+      assert_eq!(mode & 0o777, 0o755, "{dir}");
+    }
+    // Without repairing, the first directory made is already closed to writing
+    let is_root = unsafe { libc::geteuid() } == 0;
+    assert_eq!(unrepaired_res.is_err(), !is_root);
+    let mode = fs::metadata(&unrepaired).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o500);
+    for entry in walkdir::WalkDir::new(&unrepaired).into_iter().map(|x| x.unwrap()) {
+      if entry.file_type().is_dir() {
+        fs::set_permissions(entry.path(), fs::Permissions::from_mode(0o755)).unwrap();
+      }
+    }
+  }
+
+  #[test]
+  fn format_version_is_read_from_the_header(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    let (archive_path, _) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    assert_eq!(archive_format_version(&archive_path).unwrap(), FORMAT_VERSION);
+
+    let mut data = fs::read(&archive_path).unwrap();
+    data[4..8].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+    fs::write(&archive_path, &data).unwrap();
+    assert_eq!(archive_format_version(&archive_path).unwrap(), FORMAT_VERSION + 1);
+    assert!(ArchiveReader::new(&archive_path, None).is_err());
+
+    // Archives from before the header start with the index length
+    let legacy = work.path().join("legacy.bda");
+    fs::write(&legacy, 5u64.to_be_bytes()).unwrap();
+    assert_eq!(archive_format_version(&legacy).unwrap(), 0);
+  }
+
+  #[test]
+  fn indexes_load_from_memory_like_from_temp_files(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    fs::create_dir_all(input.join("empty")).unwrap();
+    let options = CreateOptions { hash_files: true, ..Default::default() };
+    let (archive_path, _) = archive_dir(&input, work.path(), None, &options);
+    let temp_dir = work.path().join("index_temp");
+    fs::create_dir_all(&temp_dir).unwrap();
+
+    let open = |index_store| {
+      let options = ReaderOptions { index_store, ..Default::default() };
+      ArchiveReader::with_options(&archive_path, &options).unwrap()
+    };
+    let from_file = open(IndexStore::TempFile(Some(temp_dir.clone())));
+    let from_memory = open(IndexStore::Memory);
+    assert_eq!(fs::read_dir(&temp_dir).unwrap().count(), 0);
+    assert_eq!(from_memory.list_files(), from_file.list_files());
+    assert_eq!(from_memory.list_all_entries(), from_file.list_all_entries());
+    assert_eq!(from_memory.file_hash("b/c.txt"), from_file.file_hash("b/c.txt"));
+    assert_eq!(from_memory.read_file("b/c.txt").unwrap(), b"cc");
+    from_memory.check().unwrap();
+  }
+
+  #[test]
+  fn all_files_to_bytes_match_reading_each(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (0..30)
+      .map(|i| (format!("d{}/f{i}", i % 3), format!("file {i} ").repeat(i * 40).into_bytes()))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content)]);
+    }
+    let (_, reader) = archive_dir(&input, work.path(), Some(4 * 1024), &CreateOptions::default());
+    assert!(reader.block_count() > 2);
+
+    let contents = reader.extract_all_to_bytes(u64::MAX).unwrap();
+    assert_eq!(contents.len(), files.len());
+    for name in reader.list_files() {
+      assert_eq!(contents[name.as_str()], reader.read_file(&name).unwrap(), "{name}");
+    }
+    let total_size = files.iter().map(|x| x.1.len() as u64).sum::<u64>();
+    let err = reader.extract_all_to_bytes(total_size - 1).unwrap_err();
+    let limit = total_size - 1;
+    assert_eq!(err, format!("files add up to {total_size} bytes, over the limit of {limit}"));
+  }
+
+  #[test]
+  fn renamed_extraction_strips_prefixes_and_uses_groups(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files: [(&str, &[u8]); 4] = [
+      ("build/app/main.bin", b"main"),
+      ("build/lib/util-12.so", b"util"),
+      ("src/main.rs", b"fn main"),
+      ("build/readme", b"read"),
+    ];
+    write_tree(&input, &files);
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    let stripped = work.path().join("stripped");
+    reader.extract_files_rename("^build/", "^build/", "", &stripped).unwrap();
+    assert_eq!(list_tree(&stripped), ["app/main.bin", "lib/util-12.so", "readme"]);
+    assert_eq!(fs::read(stripped.join("app/main.bin")).unwrap(), b"main");
+
+    let versioned = work.path().join("versioned");
+    reader.extract_files_rename(r"\.so$", r"-(\d+)\.so$", "/v$1/lib.so", &versioned).unwrap();
+    assert_eq!(list_tree(&versioned), ["build/lib/util/v12/lib.so"]);
+    assert_eq!(fs::read(versioned.join("build/lib/util/v12/lib.so")).unwrap(), b"util");
+  }
+
+  #[test]
+  fn renames_to_the_same_path_fail_before_writing(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a/data.txt", b"a"), ("b/data.txt", b"b"), ("c/other.txt", b"c")]);
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    let out = work.path().join("out");
+    let err = reader.extract_files_rename(".*", "^[ab]/", "", &out).unwrap_err();
+    assert!(err.starts_with("entries are renamed to the same path"), "{err}");
+    assert!(err.contains("a/data.txt and b/data.txt"), "{err}");
+    assert!(!out.exists());
+    reader.extract_files_rename("^c/", "^c/", "", &out).unwrap();
+    assert_eq!(list_tree(&out), ["other.txt"]);
+  }
+
+  #[test]
+  fn quick_check_rejects_truncated_and_padded_archives(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (0..20)
+      .map(|i| (format!("f{i}"), format!("file {i} ").repeat(300).into_bytes()))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content)]);
+    }
+    for recoverable in [false, true] {
+      let options = CreateOptions { recoverable, ..Default::default() };
+      let (archive_path, reader) = archive_dir(&input, work.path(), Some(4 * 1024), &options);
+      assert!(reader.block_count() > 1);
+      quick_check(&archive_path).unwrap();
+
+      let data = fs::read(&archive_path).unwrap();
+      fs::write(&archive_path, &data[..data.len() - 3]).unwrap();
+      let err = quick_check(&archive_path).unwrap_err();
+      let last_block = reader.block_count() - 1;
+      assert_eq!(err, format!("block {last_block} ends past the end of the archive"));
+
+      fs::write(&archive_path, [&data[..], b"extra"].concat()).unwrap();
+      let err = quick_check(&archive_path).unwrap_err();
+      let len = data.len();
+      assert_eq!(err, format!("blocks end at {len}, but the archive is {} bytes", len + 5));
+    }
+  }
+
+  #[test]
+  fn empty_archives_create_and_open(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    fs::create_dir_all(&input).unwrap();
+    let archive_path = work.path().join("archive.bda");
+    let options = CreateOptions::default();
+    let err = create_archive_with_report(&input, &archive_path, "zstd", 2, None, &options)
+      .unwrap_err();
+    assert!(err.contains("zstd"), "{err}");
+    assert!(!archive_path.exists());
+
+    let report = create_archive_with_report(&input, &archive_path, "ZSTD", 2, None, &options)
+      .unwrap();
+    assert_eq!((report.files, report.blocks), (0, 0));
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert_eq!(reader.list_all_entries(), Vec::<String>::new());
+    assert_eq!(reader.block_count(), 0);
+    reader.check().unwrap();
+    quick_check(&archive_path).unwrap();
+    let out = work.path().join("out");
+    reader.extract_all(&out, &ExtractOptions::default()).unwrap();
+    assert!(out.is_dir());
+    assert_eq!(list_tree(&out), Vec::<String>::new());
+  }
+
+  #[test]
+  fn implausible_index_lengths_fail_to_open(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    let (archive_path, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    let data = fs::read(&archive_path).unwrap();
+    let archive_len = data.len() as u64;
+    let with_index_len = |index_len| {
+      let mut header = reader.header.clone();
+      header.index_len = index_len;
+      let mut data = data.clone();
+      header.write(&mut data[..header.size() as usize]).unwrap();
+      data
+    };
+
+    let err = ArchiveReader::from_bytes(&with_index_len(u64::MAX)).err().unwrap();
+    assert!(err.starts_with("index length implausible"), "{err}");
+    // Under the limit, but past the end of the archive
+    let err = ArchiveReader::from_bytes(&with_index_len(archive_len)).err().unwrap();
+    let expected =
+      format!("index length implausible: {archive_len} with an archive of {archive_len}");
+    assert_eq!(err, expected);
+    fs::write(&archive_path, with_index_len(reader.header.index_len)).unwrap();
+    let options = ReaderOptions { max_index_len: Some(8), ..Default::default() };
+    let err = ArchiveReader::with_options(&archive_path, &options).err().unwrap();
+    assert!(err.ends_with("is over 8"), "{err}");
+  }
+
+  #[test]
+  fn refreshing_picks_up_a_rewritten_archive(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    let (archive_path, mut reader) =
+      archive_dir(&input, work.path(), None, &CreateOptions::default());
+    assert_eq!(reader.list_files(), ["a.txt"]);
+
+    write_tree(&input, &[("a.txt", b"changed"), ("b/new.txt", b"new")]);
+    archive_dir(&input, work.path(), None, &CreateOptions::default());
+    assert_eq!(reader.list_files(), ["a.txt"]);
+    reader.refresh().unwrap();
+    assert_eq!(reader.list_files(), ["a.txt", "b/new.txt"]);
+    assert_eq!(reader.read_file("a.txt").unwrap(), b"changed");
+    assert_eq!(reader.read_file("b/new.txt").unwrap(), b"new");
+
+    // A failed refresh leaves the reader as it was
+    let data = fs::read(&archive_path).unwrap();
+    fs::write(&archive_path, &data[..20]).unwrap();
+    assert!(reader.refresh().is_err());
+    assert_eq!(reader.list_files(), ["a.txt", "b/new.txt"]);
+  }
+
+  #[test]
+  fn files_are_reachable_by_position(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc"), ("b/d.txt", b"ddd")]);
+    let (_, reader) = archive_dir(&input, work.path(), Some(4), &CreateOptions::default());
+    let names = reader.list_files();
+    assert_eq!(reader.file_count(), names.len());
+
+    for (n, name) in names.iter().enumerate() {
+      assert_eq!(reader.entry_name(n), Some(name.as_str()));
+      let by_position = work.path().join(format!("nth/{n}"));
+      let by_name = work.path().join(format!("named/{n}"));
+      reader.extract_nth(n, &by_position).unwrap();
+      reader.extract_file(name, &by_name).unwrap();
+      assert_eq!(fs::read(&by_position).unwrap(), fs::read(&by_name).unwrap());
+    }
+    let n = reader.file_count();
+    assert_eq!(reader.entry_name(n), None);
+    let err = reader.extract_nth(n, &work.path().join("nth/out")).unwrap_err();
+    assert_eq!(err, format!("file index {n} out of range"));
+  }
+
+  #[test]
+  fn benchmarks_give_a_row_per_compression_and_level(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let text = "some fairly repetitive text ".repeat(200);
+    write_tree(&input, &[("a.txt", text.as_bytes()), ("b/c.txt", text.as_bytes())]);
+
+    let results = bench_compressions(&input, 8000).unwrap();
+    assert_eq!(results.len(), BENCH_LEVELS.len());
+    let mut rows = results.iter().map(|x| (x.compression.as_str(), x.level)).collect::<Vec<_>>();
+    rows.sort();
+    let mut expected = BENCH_LEVELS.to_vec();
+    expected.sort();
+    assert_eq!(rows, expected);
+    for compression in supported_compressions() {
+      assert!(results.iter().any(|x| x.compression == *compression), "{compression}");
+    }
+    assert!(results.iter().all(|x| x.input_size == 8000));
+    assert!(results.windows(2).all(|x| x[0].ratio >= x[1].ratio));
+    let baseline = results.iter().find(|x| x.compression == "NONE").unwrap();
+    assert_eq!(baseline.output_size, 8000);
+    assert_eq!(results.last().unwrap().compression, "NONE");
+  }
+
+  #[test]
+  fn conflict_policies_handle_existing_files(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"new a"), ("d/b.txt", b"new b")]);
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    let extract = |name: &str, options: &ExtractOptions| {
+      let out = work.path().join(name);
+      write_tree(&out, &[("a.txt", b"old a"), ("other.txt", b"mine")]);
+      (reader.extract_all(&out, options), out)
+    };
+    let read = |out: &Path, name: &str| fs::read(out.join(name)).unwrap();
+
+    let options = ExtractOptions { conflict_policy: ConflictPolicy::Skip, ..Default::default() };
+    let (result, out) = extract("skip", &options);
+    result.unwrap();
+    assert_eq!(read(&out, "a.txt"), b"old a");
+    assert_eq!(read(&out, "d/b.txt"), b"new b");
+    assert_eq!(read(&out, "other.txt"), b"mine");
+
+    let options = ExtractOptions { conflict_policy: ConflictPolicy::Error, ..Default::default() };
+    let (result, out) = extract("error", &options);
+    assert!(result.is_err());
+    assert_eq!(read(&out, "a.txt"), b"old a");
+    assert_eq!(read(&out, "other.txt"), b"mine");
+
+    let options = ExtractOptions {
+      conflict_policy: ConflictPolicy::Error,
+      ignore_errors: true,
+      ..Default::default()
+    };
+    let (result, out) = extract("error_ignored", &options);
+    result.unwrap();
+    assert_eq!(read(&out, "a.txt"), b"old a");
+    assert_eq!(read(&out, "d/b.txt"), b"new b");
+
+    let (result, out) = extract("overwrite", &ExtractOptions::default());
+    result.unwrap();
+    assert_eq!(read(&out, "a.txt"), b"new a");
+    assert_eq!(read(&out, "d/b.txt"), b"new b");
+    assert_eq!(read(&out, "other.txt"), b"mine");
+  }
+
+  #[test]
+  fn file_and_dir_listings_partition_all_entries(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"a"), ("d/b.txt", b"b"), ("d/e/c.log", b"c")]);
+    fs::create_dir_all(input.join("d/empty")).unwrap();
+    fs::create_dir_all(input.join("x/empty.txt")).unwrap();
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    let mut all = reader.list_files();
+    all.extend(reader.list_dirs());
+    assert_eq!(all, reader.list_all_entries());
+    // Folders holding files or other folders aren't entries
+    assert_eq!(reader.list_dirs(), vec!["d/empty", "x/empty.txt"]);
+
+    for pattern in ["txt", "^d/", "empty", "nothing"] {
+      let mut matched = reader.list_files_re(pattern).unwrap();
+      matched.extend(reader.list_dirs_re(pattern).unwrap());
+      assert_eq!(matched, reader.list_entries_re(pattern).unwrap(), "{pattern}");
+    }
+    assert_eq!(reader.list_dirs_re("txt").unwrap(), vec!["x/empty.txt"]);
+    assert_eq!(reader.list_files_re("txt").unwrap().len(), 2);
+  }
+  #[test]
+  fn single_files_are_stored_under_their_name(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("notes.txt", b"just one file")]);
+    let (_, reader) = archive_dir(&input.join("notes.txt"), work.path(), None, &Default::default());
+    assert_eq!(reader.list_all_entries(), vec!["notes.txt"]);
+
+    let out = work.path().join("out");
+    reader.extract_all(&out, &ExtractOptions::default()).unwrap();
+    assert_eq!(list_tree(&out), vec!["notes.txt"]);
+    assert_eq!(fs::read(out.join("notes.txt")).unwrap(), b"just one file");
+  }
+  #[test]
+  fn listed_folders_add_up_the_files_under_them(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[
+      ("a.txt", b"aaa"),
+      ("d/b.txt", b"bbbbb"),
+      ("d/e/c.txt", b"ccccccc"),
+      ("d/e/f/g.txt", b"gg"),
+      ("h/i.txt", b"iiiiiiiiiii"),
+    ]);
+    fs::create_dir_all(input.join("d/empty")).unwrap();
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    let listed = |dir: &str| {
+      let mut entries = reader
+        .list_dir(dir)
+        .unwrap()
+        .into_iter()
+        .map(|x| (x.name, x.kind, x.size))
+        .collect::<Vec<_>>();
+      entries.sort();
+      entries
+    };
+    let entry = |name: &str, kind: &str, size: u64| (name.to_string(), kind.to_string(), size);
+    assert_eq!(listed(""), vec![
+      entry("a.txt", "FILE", 3),
+      entry("d", "FOLDER", 14),
+      entry("h", "FOLDER", 11),
+    ]);
+    assert_eq!(listed("d/"), vec![
+      entry("d/b.txt", "FILE", 5),
+      entry("d/e", "FOLDER", 9),
+      entry("d/empty", "FOLDER", 0),
+    ]);
+    assert_eq!(listed("d/e"), vec![entry("d/e/c.txt", "FILE", 7), entry("d/e/f", "FOLDER", 2)]);
+  }
+  #[test]
+  fn every_compression_works_for_the_index(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    fs::create_dir_all(input.join("empty")).unwrap();
+
+    for compression in supported_compressions() {
+      let options = CreateOptions {
+        index_compression: Some(compression.to_string()),
+        ..Default::default()
+      };
+      let path = work.path().join(format!("{compression}.bda"));
+      create_archive_with_options(&input, &path, "ZSTD", 2, None, &options).unwrap();
+      let reader = ArchiveReader::new(&path, None).unwrap();
+      assert_eq!(reader.index_compression(), *compression);
+      assert_eq!(reader.list_all_entries(), vec!["a.txt", "b/c.txt", "empty"], "{compression}");
+      assert_eq!(reader.read_file("b/c.txt").unwrap(), b"cc", "{compression}");
+    }
+  }
+  #[test]
+  fn missing_or_short_blobs_fail_to_open(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (0..10)
+      .map(|i| (format!("f{i}"), format!("file {i} ").repeat(500).into_bytes()))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content)]);
+    }
+    let options = CreateOptions::default();
+    let (archive_path, reader) = archive_dir(&input, work.path(), Some(4 * 1024), &options);
+    assert!(reader.block_count() > 1);
+    let data = fs::read(&archive_path).unwrap();
+    let blob_offset = (reader.header.size() + reader.header.index_len) as usize;
+    let blob_len = data.len() - blob_offset;
+
+    for kept in [0, 1, blob_len / 2, blob_len - 1] {
+      let short = &data[..blob_offset + kept];
+      let err = ArchiveReader::from_bytes(short).err().unwrap();
+      assert!(err.ends_with("ends past the end of the archive"), "{kept}: {err}");
+      fs::write(&archive_path, short).unwrap();
+      let err = ArchiveReader::new(&archive_path, None).err().unwrap();
+      assert!(err.ends_with("ends past the end of the archive"), "{kept}: {err}");
+    }
+  }
+  #[test]
+  fn path_grouping_keeps_folders_in_neighbouring_blocks(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    // Sizes interleave across folders, so sorting by size would mix them up
+    let files = (0..30)
+      .map(|i| (format!("d{}/f{i:02}", i % 3), vec![b'a' + (i % 26) as u8; 500 + i * 37]))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content)]);
+    }
+    let options = CreateOptions { pack_strategy: PackStrategy::PathGrouped, ..Default::default() };
+    let (_, reader) = archive_dir(&input, work.path(), Some(2 * 1024), &options);
+    assert!(reader.block_count() > 3);
+
+    let mut folder_blocks = BTreeMap::<&str, Vec<u64>>::new();
+    for block_id in 0..reader.block_count() as u64 {
+      for name in reader.files_in_block(block_id) {
+        let folder = name.split_once('/').unwrap().0;
+        folder_blocks.entry(folder).or_default().push(block_id);
+      }
+    }
+    let mut previous_end = None;
+    for (folder, mut blocks) in folder_blocks {
+      blocks.sort();
+      blocks.dedup();
+      let (first, last) = (blocks[0], *blocks.last().unwrap());
+      assert_eq!(blocks, (first..=last).collect::<Vec<_>>(), "{folder}");
+      // Folders come one after another, sharing at most the block where they meet
+      assert!(previous_end.is_none_or(|x| x <= first), "{folder}");
+      previous_end = Some(last);
+    }
+    for (name, content) in &files {
+      assert_eq!(&reader.read_file(name).unwrap(), content, "{name}");
+    }
+  }
+  #[test]
+  fn scanned_blocks_match_the_index(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (0..20)
+      .map(|i| (format!("f{i}"), format!("file {i} ").repeat(200 + i * 20).into_bytes()))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content)]);
+    }
+    let options = CreateOptions { recoverable: true, ..Default::default() };
+    let (archive_path, reader) = archive_dir(&input, work.path(), Some(4 * 1024), &options);
+    assert!(reader.block_count() > 3);
+
+    let data = fs::read(&archive_path).unwrap();
+    let blob_offset = reader.header.size() + reader.header.index_len;
+    let scanned = scan_blocks(&data[blob_offset as usize..]).unwrap();
+    let mut indexed = reader
+      .block_infos
+      .iter()
+      .map(|x| (x.offset as u64 - blob_offset, x.size as u64))
+      .collect::<Vec<_>>();
+    indexed.sort();
+    assert_eq!(scanned, indexed);
+    let mut sizes = reader.blocks().iter().map(|x| x.compressed_size).collect::<Vec<_>>();
+    sizes.sort();
+    let mut scanned_sizes = scanned.iter().map(|x| x.1).collect::<Vec<_>>();
+    scanned_sizes.sort();
+    assert_eq!(scanned_sizes, sizes);
+  }
+  #[test]
+  fn blocks_over_the_memory_limit_extract_whole(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let mut files = (0..20)
+      .map(|i| (format!("d{}/f{i}", i % 4), format!("file {i} ").repeat(100 + i * 30)))
+      .collect::<Vec<_>>();
+    files.push(("big.txt".to_string(), "big file ".repeat(5000)));
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content.as_bytes())]);
+    }
+    let options = CreateOptions { hash_files: true, ..Default::default() };
+    let (archive_path, reader) = archive_dir(&input, work.path(), Some(4 * 1024), &options);
+    assert!(reader.block_count() > 3);
+    // Only some blocks fit, so both ways of extracting a block are used
+    let sizes = reader.blocks().iter().map(|x| x.compressed_size).collect::<Vec<_>>();
+    let limit = *sizes.iter().min().unwrap();
+    assert!(sizes.iter().any(|x| *x > limit));
+
+    let mut expected = files.iter().map(|x| x.0.clone()).collect::<Vec<_>>();
+    expected.sort();
+    for (name, limit) in [("over_all", 1), ("over_some", limit)] {
+      let reader = ArchiveReader::new(&archive_path, Some(limit)).unwrap();
+      let out = work.path().join(name);
+      reader.extract_all(&out, &ExtractOptions::default()).unwrap();
+      assert_eq!(list_tree(&out), expected);
+      for (file, content) in &files {
+        assert_eq!(fs::read_to_string(out.join(file)).unwrap(), *content, "{name}: {file}");
+      }
+    }
+  }
 }
@@ -0,0 +1,138 @@
+use std::{
+  io::{self, ErrorKind, Read},
+  thread,
+  time::Duration,
+};
+
+/// How transient I/O errors are retried while creating an archive. The default never retries
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetryPolicy {
+  /// Retries after the first failure, 0 to fail right away
+  pub retries: u32,
+  /// Wait before the first retry, doubled for every following one
+  pub backoff: Duration,
+}
+
+impl RetryPolicy {
+  pub fn new(retries: u32, backoff: Duration) -> Self {
+    Self { retries, backoff }
+  }
+
+  /// How long to wait before retrying after `error` on the given 0 based `attempt`. None if the
+  /// error is not transient or the retries are used up
+  pub fn next_wait(&self, error: &io::Error, attempt: u32) -> Option<Duration> {
+    if attempt >= self.retries || !is_transient(error) {
+      return None;
+    }
+    Some(self.backoff.saturating_mul(1 << attempt.min(16)))
+  }
+
+  /// Run `op`, retrying it for as long as it fails with transient errors
+  pub fn run<T, F: FnMut() -> io::Result<T>>(&self, mut op: F) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+      match op() {
+        Err(e) => match self.next_wait(&e, attempt) {
+          Some(wait) => {
+            thread::sleep(wait);
+            attempt += 1;
+          }
+          None => return Err(e),
+        },
+        res => return res,
+      }
+    }
+  }
+}
+
+fn is_transient(error: &io::Error) -> bool {
+  matches!(
+    error.kind(),
+    ErrorKind::Interrupted
+      | ErrorKind::WouldBlock
+      | ErrorKind::TimedOut
+      | ErrorKind::ResourceBusy
+      | ErrorKind::ConnectionReset
+      | ErrorKind::ConnectionAborted
+  )
+}
+
+/// Reader that retries failed reads following a `RetryPolicy`. A failed read consumes nothing,
+/// so it can be repeated as is
+pub struct RetryReader<R> {
+  inner: R,
+  policy: RetryPolicy,
+}
+
+impl<R: Read> RetryReader<R> {
+  pub fn new(inner: R, policy: RetryPolicy) -> Self {
+    Self { inner, policy }
+  }
+}
+
+impl<R: Read> Read for RetryReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    let policy = self.policy;
+    policy.run(|| self.inner.read(buf))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Reader over `data` whose first `failures` reads fail with `kind`
+  struct FlakyReader {
+    data: io::Cursor<Vec<u8>>,
+    failures: u32,
+    kind: ErrorKind,
+  }
+
+  impl Read for FlakyReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+      if self.failures > 0 {
+        self.failures -= 1;
+        return Err(io::Error::from(self.kind));
+      }
+      self.data.read(buf)
+    }
+  }
+
+  fn flaky(failures: u32, kind: ErrorKind) -> FlakyReader {
+    FlakyReader { data: io::Cursor::new(b"payload".to_vec()), failures, kind }
+  }
+
+  #[test]
+  fn transient_failures_are_retried(){
+    let policy = RetryPolicy::new(3, Duration::ZERO);
+    let mut data = vec![];
+    RetryReader::new(flaky(3, ErrorKind::TimedOut), policy).read_to_end(&mut data).unwrap();
+    assert_eq!(data, b"payload");
+  }
+
+  #[test]
+  fn retries_run_out(){
+    let policy = RetryPolicy::new(2, Duration::ZERO);
+    let mut data = vec![];
+    let res = RetryReader::new(flaky(3, ErrorKind::TimedOut), policy).read_to_end(&mut data);
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::TimedOut);
+  }
+
+  #[test]
+  fn lasting_failures_are_not_retried(){
+    let policy = RetryPolicy::new(5, Duration::ZERO);
+    let mut data = vec![];
+    let res = RetryReader::new(flaky(1, ErrorKind::PermissionDenied), policy)
+      .read_to_end(&mut data);
+    assert_eq!(res.unwrap_err().kind(), ErrorKind::PermissionDenied);
+  }
+
+  #[test]
+  fn backoff_doubles(){
+    let policy = RetryPolicy::new(3, Duration::from_millis(10));
+    let error = io::Error::from(ErrorKind::TimedOut);
+    assert_eq!(policy.next_wait(&error, 0), Some(Duration::from_millis(10)));
+    assert_eq!(policy.next_wait(&error, 2), Some(Duration::from_millis(40)));
+    assert_eq!(policy.next_wait(&error, 3), None);
+  }
+}
@@ -1,5 +1,7 @@
+#[cfg(feature = "writer")]
 use diesel::prelude::{Insertable, Queryable, Selectable};
 
+#[cfg(feature = "writer")]
 diesel::table! {
   files (name) {
     name -> Text,
@@ -9,12 +11,14 @@ diesel::table! {
   }
 }
 
+#[cfg(feature = "writer")]
 diesel::table! {
   folder_leaves (name) {
     name -> Text,
   }
 }
 
+#[cfg(feature = "writer")]
 diesel::table! {
   blocks (id) {
     id -> BigInt,
@@ -24,10 +28,114 @@ diesel::table! {
   }
 }
 
+#[cfg(feature = "writer")]
+diesel::table! {
+  file_hashes (name) {
+    name -> Text,
+    hash -> Text,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  hash_algorithm (algorithm) {
+    algorithm -> Text,
+  }
+}
+
+// `file_hashes` as index schema v1 stored it, with the algorithm on every row
+#[cfg(feature = "writer")]
+diesel::table! {
+  #[sql_name = "file_hashes"]
+  file_hashes_v1 (name) {
+    name -> Text,
+    hash -> Text,
+    algorithm -> Text,
+  }
+}
+
+// SQLite's own listing of the tables in a DB
+#[cfg(feature = "writer")]
+diesel::table! {
+  sqlite_master (name) {
+    name -> Text,
+    #[sql_name = "type"]
+    kind -> Text,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  index_schema (version) {
+    version -> BigInt,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  entry_order (name) {
+    name -> Text,
+    ord -> BigInt,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  owners (name) {
+    name -> Text,
+    uid -> BigInt,
+    gid -> BigInt,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  block_levels (id) {
+    id -> BigInt,
+    level -> BigInt,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  file_flags (name) {
+    name -> Text,
+    flags -> BigInt,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  hardlinks (name) {
+    name -> Text,
+    target -> Text,
+  }
+}
+
+#[cfg(feature = "writer")]
+diesel::table! {
+  metadata (key) {
+    key -> Text,
+    value -> Text,
+  }
+}
+
+/// Bumped whenever the index tables change incompatibly. v2 records the hash algorithm once in
+/// `hash_algorithm` instead of on every `file_hashes` row
+pub const INDEX_SCHEMA_VERSION: i64 = 2;
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = index_schema))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveIndexSchema{
+  pub version: i64,
+}
+
 #[derive(Debug, Clone)]
-#[derive(Queryable, Selectable, Insertable)]
-#[diesel(table_name = files)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = files))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
 pub struct ArchiveFileEntry{
   pub name: String,
   pub block: i64,
@@ -36,20 +144,109 @@ pub struct ArchiveFileEntry{
 }
 
 #[derive(Debug, Clone)]
-#[derive(Queryable, Selectable, Insertable)]
-#[diesel(table_name = folder_leaves)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = folder_leaves))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
 pub struct ArchiveFolderLeafEntry{
   pub name: String
 }
 
 #[derive(Debug, Clone)]
-#[derive(Queryable, Selectable, Insertable)]
-#[diesel(table_name = blocks)]
-#[diesel(check_for_backend(diesel::sqlite::Sqlite))]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = blocks))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
 pub struct ArchiveBlockInfo{
   pub id: i64,
   pub size: i64,
   pub offset: i64,
   pub compression_type: String,
-}
\ No newline at end of file
+}
+
+/// Level a block was compressed with, for the compression types that have levels
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = block_levels))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveBlockLevel{
+  pub id: i64,
+  pub level: i64,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = file_hashes))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveFileHash{
+  pub name: String,
+  pub hash: String,
+}
+
+/// Algorithm of every hash in `file_hashes`, a single row
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = hash_algorithm))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveHashAlgorithm{
+  pub algorithm: String,
+}
+
+/// Row of `file_hashes` in index schema v1
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable))]
+#[cfg_attr(feature = "writer", diesel(table_name = file_hashes_v1))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveFileHashV1{
+  pub name: String,
+  pub hash: String,
+  pub algorithm: String,
+}
+
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = metadata))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveMetadataEntry{
+  pub key: String,
+  pub value: String,
+}
+
+/// Position of a file in the archive's listing order
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = entry_order))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveEntryOrder{
+  pub name: String,
+  pub ord: i64,
+}
+
+/// Unix owner of a file or folder leaf
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = owners))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveOwner{
+  pub name: String,
+  pub uid: i64,
+  pub gid: i64,
+}
+
+/// Flags like immutable or append-only of a file or folder leaf, see `flag_utils`
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = file_flags))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveFileFlags{
+  pub name: String,
+  pub flags: i64,
+}
+
+/// File stored as a hardlink to the file `target`, which holds the content
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "writer", derive(Queryable, Selectable, Insertable))]
+#[cfg_attr(feature = "writer", diesel(table_name = hardlinks))]
+#[cfg_attr(feature = "writer", diesel(check_for_backend(diesel::sqlite::Sqlite)))]
+pub struct ArchiveHardlink{
+  pub name: String,
+  pub target: String,
+}
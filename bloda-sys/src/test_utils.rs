@@ -0,0 +1,76 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, Once};
+
+use crate::{create_archive_with_options, ArchiveReader, CreateOptions};
+
+/// Write `files`, given as names relative to `dir` and contents, creating parent folders
+pub fn write_tree(dir: &Path, files: &[(&str, &[u8])]){
+  for (name, content) in files {
+    let path = dir.join(name);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    fs::write(path, content).unwrap();
+  }
+}
+
+/// Relative paths of the files under `dir`, sorted and with `/` separators
+pub fn list_tree(dir: &Path) -> Vec<String>{
+  let mut files = walkdir::WalkDir::new(dir)
+    .into_iter()
+    .map(|x| x.unwrap())
+    .filter(|x| !x.file_type().is_dir())
+    .map(|x| {
+      let relative = x.path().strip_prefix(dir).unwrap();
+      relative.components().map(|c| c.as_os_str().to_string_lossy()).collect::<Vec<_>>().join("/")
+    })
+    .collect::<Vec<_>>();
+  files.sort();
+  files
+}
+
+/// Archive `input` into `archive.bda` under `work_dir` and open it
+pub fn archive_dir(
+  input: &Path,
+  work_dir: &Path,
+  block_size: Option<u64>,
+  options: &CreateOptions
+) -> (PathBuf, ArchiveReader){
+  let archive_path = work_dir.join("archive.bda");
+  create_archive_with_options(input, &archive_path, "ZSTD", 2, block_size, options).unwrap();
+  let reader = ArchiveReader::new(&archive_path, None).unwrap();
+  (archive_path, reader)
+}
+
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+/// Logger keeping warnings in `WARNINGS`
+struct CaptureLogger;
+
+impl log::Log for CaptureLogger {
+  fn enabled(&self, metadata: &log::Metadata) -> bool {
+    metadata.level() <= log::Level::Warn
+  }
+
+  fn log(&self, record: &log::Record) {
+    if self.enabled(record.metadata()) {
+      WARNINGS.lock().unwrap().push(record.args().to_string());
+    }
+  }
+
+  fn flush(&self) {}
+}
+
+/// Start keeping logged warnings, see `warnings_about`
+pub fn capture_warnings(){
+  static INIT: Once = Once::new();
+  INIT.call_once(|| {
+    log::set_logger(&CaptureLogger).unwrap();
+    log::set_max_level(log::LevelFilter::Warn);
+  });
+}
+
+/// Warnings logged since `capture_warnings` that mention `needle`. Tests run in parallel, so
+/// `needle` should be unique to the test
+pub fn warnings_about(needle: &str) -> Vec<String>{
+  WARNINGS.lock().unwrap().iter().filter(|x| x.contains(needle)).cloned().collect()
+}
@@ -0,0 +1,2950 @@
+use std::{
+  cmp::Reverse,
+  collections::{BTreeMap, HashMap, HashSet},
+  fmt,
+  fs,
+  io::{self, Read, Seek, Write},
+  path::{Component, Path, PathBuf},
+  str::FromStr,
+  sync::Mutex,
+  time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use bytes::BytesMut;
+use diesel::{Connection, RunQueryDsl};
+use rayon::iter::{
+  IntoParallelIterator,
+  IntoParallelRefIterator,
+  ParallelBridge,
+  ParallelIterator
+};
+use tokio::io::AsyncReadExt;
+
+use crate::{
+  compress_utils,
+  flag_utils,
+  hash_utils,
+  header_utils::{self, ArchiveHeader},
+  retry_utils::{RetryPolicy, RetryReader},
+  sql_structs::{
+    self,
+    ArchiveBlockInfo,
+    ArchiveBlockLevel,
+    ArchiveEntryOrder,
+    ArchiveFileEntry,
+    ArchiveFileFlags,
+    ArchiveFileHash,
+    ArchiveFolderLeafEntry,
+    ArchiveHardlink,
+    ArchiveHashAlgorithm,
+    ArchiveIndexSchema,
+    ArchiveMetadataEntry,
+    ArchiveOwner,
+    INDEX_SCHEMA_VERSION,
+  },
+  ArchiveReader,
+  HashAlgorithm,
+  BLOCK_FRAME_LEN,
+  CREATED_AT_KEY,
+  CREATED_BY_KEY,
+  BLOCK_FRAME_MAGIC,
+};
+
+const DEFAULT_BLOCK_SIZE: u64 = 64 * 1024 * 1024; // 64MB
+const DEFAULT_INDEX_COMPRESSION: &str = "ZSTD";
+
+/// Build the index DB in memory and return it serialized
+#[allow(clippy::too_many_arguments)]
+fn make_index_db(
+  files: Vec<ArchiveFileEntry>,
+  folder_leaves: Vec<ArchiveFolderLeafEntry>,
+  block_infos: Vec<ArchiveBlockInfo>,
+  // Level the blocks were compressed with, see `compress_utils::effective_level`
+  compression_level: Option<u32>,
+  file_hashes: Vec<ArchiveFileHash>,
+  // Recorded once for all of `file_hashes`
+  hash_algorithm: HashAlgorithm,
+  metadata: Vec<ArchiveMetadataEntry>,
+  entry_order: Vec<ArchiveEntryOrder>,
+  owners: Vec<ArchiveOwner>,
+  hardlinks: Vec<ArchiveHardlink>,
+  file_flags: Vec<ArchiveFileFlags>,
+) -> Result<Vec<u8>, String>{
+  let block_levels = block_infos
+    .iter()
+    .filter_map(|x| {
+      let level = compress_utils::effective_level(&x.compression_type, compression_level)?;
+      Some(ArchiveBlockLevel{ id: x.id, level: level as _ })
+    })
+    .collect::<Vec<_>>();
+  let mut conn = diesel::SqliteConnection::establish(":memory:")
+    .map_err(|e| format!("at opening in-memory index db: {e}"))?;
+  diesel::sql_query("CREATE TABLE index_schema(version BIGINT PRIMARY KEY)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating index_schema table: {e}"))?;
+  diesel::insert_into(sql_structs::index_schema::table)
+    .values(&ArchiveIndexSchema { version: INDEX_SCHEMA_VERSION })
+    .execute(&mut conn)
+    .map_err(|e| format!("at writing index schema: {e}"))?;
+  diesel::sql_query("CREATE TABLE files(
+    name TEXT PRIMARY KEY,
+    block BIGINT,
+    offset BIGINT,
+    size BIGINT)"
+  )
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating files table: {e}"))?;
+  diesel::sql_query("CREATE TABLE folder_leaves(name TEXT PRIMARY KEY)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating folder_leaves table: {e}"))?;
+  diesel::sql_query("CREATE TABLE blocks(
+    id BIGINT PRIMARY KEY,
+    size BIGINT,
+    offset BIGINT,
+    compression_type TEXT)"
+  )
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating blocks table: {e}"))?;
+  diesel::sql_query("CREATE TABLE metadata(key TEXT PRIMARY KEY, value TEXT)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating metadata table: {e}"))?;
+  diesel::sql_query("CREATE TABLE file_hashes(name TEXT PRIMARY KEY, hash TEXT)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating file_hashes table: {e}"))?;
+  diesel::sql_query("CREATE TABLE hash_algorithm(algorithm TEXT PRIMARY KEY)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating hash_algorithm table: {e}"))?;
+  diesel::sql_query("CREATE TABLE entry_order(name TEXT PRIMARY KEY, ord BIGINT)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating entry_order table: {e}"))?;
+  diesel::sql_query("CREATE TABLE owners(name TEXT PRIMARY KEY, uid BIGINT, gid BIGINT)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating owners table: {e}"))?;
+  diesel::sql_query("CREATE TABLE hardlinks(name TEXT PRIMARY KEY, target TEXT)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating hardlinks table: {e}"))?;
+  diesel::sql_query("CREATE TABLE file_flags(name TEXT PRIMARY KEY, flags BIGINT)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating file_flags table: {e}"))?;
+  diesel::sql_query("CREATE TABLE block_levels(id BIGINT PRIMARY KEY, level BIGINT)")
+    .execute(&mut conn)
+    .map_err(|e| format!("at creating block_levels table: {e}"))?;
+  // Empty archives keep empty tables
+  if !files.is_empty(){
+    diesel::insert_into(sql_structs::files::table)
+      .values(&files)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing files info: {e}"))?;
+  }
+  if !folder_leaves.is_empty(){
+    diesel::insert_into(sql_structs::folder_leaves::table)
+      .values(&folder_leaves)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing folder leaves info: {e}"))?;
+  }
+  if !block_infos.is_empty(){
+    diesel::insert_into(sql_structs::blocks::table)
+      .values(&block_infos)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing archive info: {e}"))?;
+  }
+  if !file_hashes.is_empty(){
+    diesel::insert_into(sql_structs::file_hashes::table)
+      .values(&file_hashes)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing file hashes: {e}"))?;
+    diesel::insert_into(sql_structs::hash_algorithm::table)
+      .values(&ArchiveHashAlgorithm{ algorithm: hash_algorithm.as_str().to_string() })
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing hash algorithm: {e}"))?;
+  }
+  if !metadata.is_empty(){
+    diesel::insert_into(sql_structs::metadata::table)
+      .values(&metadata)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing metadata: {e}"))?;
+  }
+  if !entry_order.is_empty(){
+    diesel::insert_into(sql_structs::entry_order::table)
+      .values(&entry_order)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing entry order: {e}"))?;
+  }
+  if !owners.is_empty(){
+    diesel::insert_into(sql_structs::owners::table)
+      .values(&owners)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing owners: {e}"))?;
+  }
+  if !hardlinks.is_empty(){
+    diesel::insert_into(sql_structs::hardlinks::table)
+      .values(&hardlinks)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing hardlinks: {e}"))?;
+  }
+  if !file_flags.is_empty(){
+    diesel::insert_into(sql_structs::file_flags::table)
+      .values(&file_flags)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing file flags: {e}"))?;
+  }
+  if !block_levels.is_empty(){
+    diesel::insert_into(sql_structs::block_levels::table)
+      .values(&block_levels)
+      .execute(&mut conn)
+      .map_err(|e| format!("at writing block levels: {e}"))?;
+  }
+  Ok(conn.serialize_database_to_buffer().to_vec())
+}
+
+/// Flags of `path` as stored in the index. None if it has none, or where they can't be read
+fn path_flags(path: &Path, name: &str) -> Option<ArchiveFileFlags>{
+  flag_utils::get_flags(path)
+    .ok()
+    .filter(|x| *x != 0)
+    .map(|flags| ArchiveFileFlags{ name: name.to_string(), flags: flags as _ })
+}
+
+/// Unix owner of `path` as stored in the index. Always None elsewhere
+#[cfg(unix)]
+fn path_owner(path: &Path, name: &str) -> Result<Option<ArchiveOwner>, String>{
+  use std::os::unix::fs::MetadataExt;
+
+  let metadata = fs::metadata(path).map_err(|e| format!("at getting owner of {path:?}: {e}"))?;
+  Ok(Some(ArchiveOwner{
+    name: name.to_string(),
+    uid: metadata.uid() as _,
+    gid: metadata.gid() as _
+  }))
+}
+
+#[cfg(not(unix))]
+fn path_owner(_path: &Path, _name: &str) -> Result<Option<ArchiveOwner>, String>{
+  Ok(None)
+}
+
+/// Device and inode of the file at `path` if other paths link to it too. Always None elsewhere
+#[cfg(unix)]
+fn hardlink_id(path: &Path) -> Option<(u64, u64)>{
+  use std::os::unix::fs::MetadataExt;
+
+  let metadata = fs::metadata(path).ok()?;
+  (metadata.nlink() > 1).then_some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn hardlink_id(_path: &Path) -> Option<(u64, u64)>{
+  None
+}
+
+/// Items of a block with their offset in it and their size
+type PackedBlock<T> = Vec<(T, i64, i64)>;
+
+type BlockFiles = PackedBlock<PathBuf>;
+
+/// Block of `create_archive_from_streams` being filled: its data and the name, offset and size
+/// of the entries in it
+#[derive(Default)]
+struct StreamBlock {
+  data: Vec<u8>,
+  entries: Vec<(String, i64, i64)>,
+}
+
+struct FileLayout {
+  /// Compression of every block with the files in it
+  blocks: Vec<(String, BlockFiles)>,
+  folder_leaves: Vec<PathBuf>,
+  /// Paths of files linked to an earlier stored file, with the path of that file
+  hardlinks: Vec<(PathBuf, PathBuf)>,
+  hashes: HashMap<PathBuf, String>,
+  /// Folders under the input directories, empty or not
+  dir_count: usize,
+  /// Paths left out because they couldn't be listed or read
+  skipped: Vec<PathBuf>,
+}
+
+/// Where the paths to archive come from
+#[derive(Clone, Copy)]
+enum InputSource<'a> {
+  /// Everything under these directories, or these files
+  Walk(&'a [&'a Path]),
+  /// Exactly these paths, named relative to the base directory they're all under
+  List(&'a Path, &'a [PathBuf]),
+}
+
+impl<'a> InputSource<'a> {
+  /// Directories or files the entry names are relative to
+  fn inputs(&self) -> Vec<&'a Path>{
+    match *self {
+      Self::Walk(inputs) => inputs.to_vec(),
+      Self::List(base_dir, _) => vec![base_dir],
+    }
+  }
+}
+
+/// Every path of `source` and whether it's at `options.max_depth`. Paths that can't be listed
+/// fail the archive, or are added to `skipped` with `options.keep_going`
+fn list_entries(
+  source: InputSource,
+  options: &CreateOptions,
+  skipped: &mut Vec<PathBuf>,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> Result<Vec<(PathBuf, bool)>, String>{
+  let mut entries = vec![];
+  match source {
+    InputSource::Walk(inp_dirs) => {
+      let walkers = inp_dirs.iter().map(|inp_dir| {
+        let walker = walkdir::WalkDir::new(inp_dir);
+        match options.max_depth {
+          Some(max_depth) => walker.max_depth(max_depth),
+          None => walker,
+        }
+      });
+      for entry in walkers.flatten(){
+        match entry {
+          Ok(entry) => {
+            let at_max_depth = options.max_depth == Some(entry.depth());
+            entries.push((entry.path().to_owned(), at_max_depth));
+          }
+          Err(e) => {
+            let reason = e.io_error().map_or(e.to_string(), |x| x.to_string());
+            if !options.keep_going {
+              let path = e.path().unwrap_or(Path::new(""));
+              return Err(format!("at listing {path:?}: {reason}"));
+            }
+            skipped.extend(e.path().map(Path::to_owned));
+            diagnostics.push(Diagnostic{
+              path: e.path().map(Path::to_owned),
+              message: format!("skipped, can't be listed: {reason}"),
+            });
+          }
+        }
+      }
+    }
+    InputSource::List(_, paths) => {
+      let mut seen = HashSet::new();
+      for path in paths.iter().filter(|x| seen.insert(*x)){
+        match fs::symlink_metadata(path) {
+          Ok(_) => entries.push((path.clone(), false)),
+          Err(e) if !options.keep_going => return Err(format!("at listing {path:?}: {e}")),
+          Err(e) => {
+            skipped.push(path.clone());
+            diagnostics.push(Diagnostic{
+              path: Some(path.clone()),
+              message: format!("skipped, can't be listed: {e}"),
+            });
+          }
+        }
+      }
+    }
+  }
+  Ok(entries)
+}
+
+fn distribute_files_to_blocks(
+  source: InputSource,
+  max_multi_block_size: i64,
+  compression_type: &str,
+  compression_of: impl Fn(&Path) -> String,
+  options: &CreateOptions,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> Result<FileLayout, String> {
+  let inp_dirs = source.inputs();
+  let mut skipped = vec![];
+  let entries = list_entries(source, options, &mut skipped, diagnostics)?;
+  // Links whose target is gone are neither files nor folders, so nothing else would store them
+  for (path, _) in entries.iter().filter(|(path, _)| path.is_symlink() && !path.exists()){
+    let target = fs::read_link(path).unwrap_or_default();
+    skipped.push(path.clone());
+    diagnostics.push(Diagnostic{
+      path: Some(path.clone()),
+      message: format!("skipped, symlink to {target:?} which doesn't exist"),
+    });
+  }
+  let mut files_w_sizes = vec![];
+  for path in entries.iter().map(|(path, _)| path).filter(|x| x.is_file()){
+    // Opened up front so files that can't be read fail before anything is compressed
+    match fs::File::open(path).and_then(|x| x.metadata()) {
+      Ok(metadata) => files_w_sizes.push((path, metadata.len() as i64)),
+      Err(e) if !options.keep_going => return Err(format!("at opening {path:?}: {e}")),
+      Err(e) => {
+        skipped.push(path.clone());
+        diagnostics.push(Diagnostic{
+          path: Some(path.clone()),
+          message: format!("skipped, can't be read: {e}"),
+        });
+      }
+    }
+  }
+  // The first of a file's paths by name holds its content, the others only link to it
+  let mut hardlinks = vec![];
+  if options.detect_hardlinks {
+    let mut paths = files_w_sizes.iter().map(|x| x.0).collect::<Vec<_>>();
+    paths.sort();
+    let mut targets = HashMap::<_, &PathBuf>::new();
+    for path in paths{
+      let Some(id) = hardlink_id(path) else {
+        continue;
+      };
+      match targets.get(&id) {
+        Some(target) => hardlinks.push((path.clone(), (*target).clone())),
+        None => {
+          targets.insert(id, path);
+        }
+      }
+    }
+    let links = hardlinks.iter().map(|x| &x.0).collect::<HashSet<_>>();
+    files_w_sizes.retain(|x| !links.contains(x.0));
+  }
+  // Hashed before block assignment, keyed by path so the result doesn't depend on thread count
+  let mut hashes = HashMap::new();
+  if options.hash_files {
+    let results = files_w_sizes
+      .par_iter()
+      .map(|(path, _)| ((*path).clone(), hash_utils::hash_file(path, options.hash_algorithm)))
+      .collect::<Vec<_>>();
+    for (path, result) in results{
+      match result {
+        Ok(hash) => {
+          hashes.insert(path, hash);
+        }
+        Err(e) if options.keep_going => {
+          let message = format!("skipped, {e}");
+          diagnostics.push(Diagnostic{ path: Some(path.clone()), message });
+          skipped.push(path);
+        }
+        Err(e) => return Err(e),
+      }
+    }
+    let failed = skipped.iter().collect::<HashSet<_>>();
+    files_w_sizes.retain(|x| !failed.contains(x.0));
+  }
+  pack_order(&mut files_w_sizes, options.pack_strategy, |path| path.as_path());
+  // Folders at the depth limit have nothing stored under them, so they are kept as leaves
+  let is_leaf = |path: &Path, at_max_depth: bool| {
+    at_max_depth || fs::read_dir(path).map(|mut y| y.next().is_none()).unwrap_or(false)
+  };
+  let folder_leaves = entries
+    .iter()
+    // The input roots themselves are never entries, even when empty
+    .filter(|(path, _)| !inp_dirs.contains(&path.as_path()))
+    .filter(|(path, at_max_depth)| path.is_dir() && is_leaf(path, *at_max_depth))
+    .map(|(path, _)| path.clone())
+    .collect::<Vec<_>>();
+
+  // Files are never split across blocks
+  for (path, size) in files_w_sizes.iter().filter(|x| x.1 > max_multi_block_size){
+    diagnostics.push(Diagnostic{
+      path: Some((*path).clone()),
+      message: format!("{size} bytes, bigger than the block size, stored alone"),
+    });
+  }
+  let files_w_sizes = files_w_sizes.into_iter().map(|(path, size)| (path.clone(), size)).collect();
+  let blocks = pack_by_compression(
+    files_w_sizes,
+    |path: &PathBuf| compression_of(path),
+    compression_type,
+    max_multi_block_size,
+    options.max_files_per_block
+  );
+  let dir_count = entries
+    .iter()
+    .filter(|(path, _)| !inp_dirs.contains(&path.as_path()) && path.is_dir())
+    .count();
+  Ok(FileLayout { blocks, folder_leaves, hardlinks, hashes, dir_count, skipped })
+}
+
+/// Split sized items into blocks of at most `max_multi_block_size` bytes and `max_files` items,
+/// keeping their order. Each item comes with its offset in the block and its size
+fn pack_into_blocks<T>(
+  items: Vec<(T, i64)>,
+  max_multi_block_size: i64,
+  max_files: Option<usize>
+) -> Vec<Vec<(T, i64, i64)>>{
+  let mut blocks = vec![];
+  let mut curr_block_items = vec![];
+  let mut curr_block_offset = 0;
+  for (item, size) in items{
+    let block_full = curr_block_offset + size > max_multi_block_size
+      || max_files.is_some_and(|n| curr_block_items.len() >= n);
+    if block_full && !curr_block_items.is_empty(){
+      blocks.push(curr_block_items);
+      curr_block_items = vec![];
+      curr_block_offset = 0;
+    }
+    curr_block_items.push((item, curr_block_offset, size));
+    curr_block_offset += size;
+  }
+  if !curr_block_items.is_empty(){
+    blocks.push(curr_block_items);
+  }
+  blocks
+}
+
+/// Pack the items of every compression into their own blocks, the archive's compression first
+/// and overrides after it by name. Items keep their order within a compression
+fn pack_by_compression<T>(
+  items: Vec<(T, i64)>,
+  compression_of: impl Fn(&T) -> String,
+  compression_type: &str,
+  max_multi_block_size: i64,
+  max_files: Option<usize>
+) -> Vec<(String, PackedBlock<T>)>{
+  let mut groups: BTreeMap<String, Vec<(T, i64)>> = BTreeMap::new();
+  for (item, size) in items{
+    groups.entry(compression_of(&item)).or_default().push((item, size));
+  }
+  let default_group = groups.remove(compression_type).map(|x| (compression_type.to_string(), x));
+  default_group
+    .into_iter()
+    .chain(groups)
+    .flat_map(|(compression, items)| {
+      pack_into_blocks(items, max_multi_block_size, max_files)
+        .into_iter()
+        .map(move |block| (compression.clone(), block))
+    })
+    .collect()
+}
+
+/// Read the file at `file_path` into `buffer`, which is as long as the file is expected to be
+async fn read_file_into(
+  buffer: &mut [u8],
+  file_path: &Path,
+  retry: RetryPolicy,
+) -> Result<(), String>{
+  let mut attempt = 0;
+  loop {
+    // Reads start over from the beginning of the file, so a retry never leaves a partial copy
+    let res = async {
+      let mut fr = tokio::fs::File::open(file_path).await?;
+      fr.read_exact(buffer).await
+    }.await;
+    match res {
+      Ok(_) => return Ok(()),
+      Err(e) => match retry.next_wait(&e, attempt) {
+        Some(wait) => {
+          tokio::time::sleep(wait).await;
+          attempt += 1;
+        }
+        None => return Err(format!("at loading {file_path:?} to buffer: {e}")),
+      },
+    }
+  }
+}
+
+/// Buffers of finished blocks, reused by the next ones instead of allocating them for every
+/// block. Holds one pair per block compressed at the same time and is dropped, with them, at the
+/// end of the archive
+#[derive(Default)]
+struct BlockBuffers {
+  raw: Mutex<Vec<BytesMut>>,
+  compressed: Mutex<Vec<Vec<u8>>>,
+}
+
+impl BlockBuffers {
+  fn take(&self) -> (BytesMut, Vec<u8>){
+    let raw = self.raw.lock().unwrap().pop().unwrap_or_default();
+    let compressed = self.compressed.lock().unwrap().pop().unwrap_or_default();
+    (raw, compressed)
+  }
+
+  fn give_back(&self, raw: BytesMut, compressed: Vec<u8>){
+    self.raw.lock().unwrap().push(raw);
+    self.compressed.lock().unwrap().push(compressed);
+  }
+}
+
+/// Compress the single file block read from `input` into the file `output`, returning its size
+fn compress_stream_block<R: Read>(
+  output: &Path,
+  input: R,
+  compression_type: &str,
+  level: Option<u32>
+) -> Result<u64, String>{
+  let mut fw = fs::File::create(output).map_err(|e| format!("at creating {output:?}: {e}"))?;
+  fw
+    .write_all(&[compress_utils::compression_id(compression_type)?])
+    .map_err(|e| format!("at writing block marker: {e}"))?;
+  compress_utils::compress_data(input, &mut fw, compression_type, level)?;
+  // compress_data reports bytes read, the block size is what ended up on disk
+  fs::metadata(output)
+    .map(|m| m.len())
+    .map_err(|e| format!("at reading size of {output:?}: {e}"))
+}
+
+/// Compress the files of a block into the file `output`, returning its size. With `keep_going`,
+/// files that fail to read are left out of the block and returned with their error instead of
+/// failing it, the files after them moving up to take their place
+async fn compress_block(
+  output: &Path,
+  block_files: &[(PathBuf, i64, i64)],
+  compression_type: &str,
+  level: Option<u32>,
+  retry: RetryPolicy,
+  keep_going: bool,
+  buffers: &BlockBuffers
+) -> Result<(u64, Vec<(PathBuf, String)>), String>{
+  if let [(path, _, _)] = block_files {
+    let res = retry
+      .run(|| fs::File::open(path))
+      .map_err(|e| format!("at opening {:?}: {e}", &path))
+      .and_then(|fr| {
+        compress_stream_block(output, RetryReader::new(fr, retry), compression_type, level)
+      });
+    return match res {
+      Ok(size) => Ok((size, vec![])),
+      Err(e) if keep_going => {
+        let size = compress_stream_block(output, io::empty(), compression_type, level)?;
+        Ok((size, vec![(path.clone(), e)]))
+      }
+      Err(e) => Err(e),
+    };
+  }
+  let total_size = block_files.iter().map(|x| x.2).sum::<i64>();
+  let (mut block_data, mut compressed_block_data) = buffers.take();
+  block_data.clear();
+  block_data.resize(total_size as usize, 0);
+
+  // Every file is read into its own part of the block, so reads don't wait on each other.
+  // Files are laid out back to back in order, see `pack_into_blocks`
+  let mut join_set = tokio::task::JoinSet::new();
+  for (i, (path, offset, size)) in block_files.iter().enumerate(){
+    let read_len = total_size as usize - block_data.len();
+    if *offset as usize != read_len {
+      return Err(format!("{path:?} is at offset {offset} of its block instead of {read_len}"));
+    }
+    let mut part = block_data.split_to(*size as usize);
+    let path = path.to_owned();
+    join_set.spawn(async move{
+      let res = read_file_into(&mut part, &path, retry).await;
+      (i, part, res)
+    });
+  }
+  let mut parts = vec![BytesMut::new(); block_files.len()];
+  let mut failed = vec![];
+  while let Some(joined) = join_set.join_next().await{
+    let (i, part, res) = joined.map_err(|e| format!("at reading block files: {e}"))?;
+    match res {
+      Ok(()) => {}
+      Err(e) if keep_going => {
+        // Left out of the block, whatever was read of it
+        failed.push((i, e));
+        continue;
+      }
+      Err(e) => return Err(e),
+    }
+    parts[i] = part;
+  }
+  // The parts are still next to each other, so putting them back together doesn't copy. Parts
+  // after a left out one are copied up to take its place
+  for part in parts.into_iter().filter(|x| !x.is_empty()){
+    block_data.unsplit(part);
+  }
+  compressed_block_data.clear();
+  compressed_block_data.push(compress_utils::compression_id(compression_type)?);
+  compress_utils::compress_data(
+    &block_data[..],
+    &mut compressed_block_data,
+    compression_type,
+    level
+  )?;
+  fs::write(output, &compressed_block_data).map_err(|e| format!("at writing: {e}"))?;
+  let compressed_size = compressed_block_data.len();
+  buffers.give_back(block_data, compressed_block_data);
+  let failed = failed.into_iter().map(|(i, e)| (block_files[i].0.clone(), e)).collect();
+  Ok((compressed_size as _, failed))
+} 
+
+/// Read back the archive just written to `output`, see `CreateOptions::verify_after_write`
+fn verify_written(output: &Path) -> Result<(), String>{
+  ArchiveReader::new(output, None)
+    .and_then(|x| x.check())
+    .map_err(|e| format!("at verifying written archive: {e}"))
+}
+
+/// Input of an archive with the directory the names of its entries are relative to, and the
+/// prefix they get with `CreateOptions::prefix_inputs`
+struct InputRoot<'a> {
+  path: &'a Path,
+  base_dir: &'a Path,
+  prefix: Option<String>,
+}
+
+/// Roots of the `inputs` of an archive. Inputs can't overlap, or entries would be stored twice
+fn input_roots<'a>(
+  inputs: &[&'a Path],
+  options: &CreateOptions
+) -> Result<Vec<InputRoot<'a>>, String>{
+  if inputs.is_empty() {
+    return Err("no inputs to archive".to_string());
+  }
+  for (i, input) in inputs.iter().enumerate(){
+    if let Some(other) = inputs[..i].iter().find(|x| input.starts_with(x) || x.starts_with(input)) {
+      return Err(format!("inputs {other:?} and {input:?} overlap"));
+    }
+  }
+  inputs
+    .iter()
+    .map(|path| {
+      // A single file is already stored under its own name
+      let prefix = if options.prefix_inputs && path.is_dir() {
+        let full_path = fs::canonicalize(path)
+          .map_err(|e| format!("at resolving absolute path of {path:?}: {e}"))?;
+        let name = full_path
+          .file_name()
+          .ok_or(format!("input {path:?} has no name to prefix its entries with"))?;
+        Some(name.to_string_lossy().to_string())
+      } else {
+        None
+      };
+      Ok(InputRoot { path, base_dir: input_base_dir(path), prefix })
+    })
+    .collect()
+}
+
+fn entry_name(path: &Path, roots: &[InputRoot], options: &CreateOptions) -> Result<String, String>{
+  let name = if options.absolute_paths {
+    fs::canonicalize(path)
+      .map_err(|e| format!("at resolving absolute path of {path:?}: {e}"))?
+      .to_string_lossy()
+      .to_string()
+  } else {
+    let root = roots.iter().find(|x| path.starts_with(x.path));
+    let relative = root
+      .and_then(|x| path.strip_prefix(x.base_dir).ok())
+      .unwrap_or(path)
+      .to_string_lossy();
+    match root.and_then(|x| x.prefix.as_deref()) {
+      Some(prefix) => format!("{prefix}/{relative}"),
+      None => relative.to_string(),
+    }
+  };
+  Ok(prefixed_name(&name, options))
+}
+
+/// Fail if entries from different inputs end up with the same name, listing them. Empty
+/// folders of the same name are merged instead
+fn check_name_collisions(
+  layout: &FileLayout,
+  roots: &[InputRoot],
+  options: &CreateOptions
+) -> Result<(), String>{
+  if roots.len() < 2 {
+    return Ok(());
+  }
+  let files = layout.blocks.iter().flat_map(|(_, x)| x.iter().map(|(path, _, _)| (path, false)));
+  let leaves = layout.folder_leaves.iter().map(|x| (x, true));
+  let links = layout.hardlinks.iter().map(|(link, _)| (link, false));
+  let mut seen = HashMap::<String, (&PathBuf, bool)>::new();
+  let mut collisions = vec![];
+  for (path, is_leaf) in files.chain(leaves).chain(links){
+    let name = entry_name(path, roots, options)?;
+    match seen.get(&name) {
+      Some((_, other_is_leaf)) if is_leaf && *other_is_leaf => {}
+      Some((other, _)) => collisions.push(format!("{name} ({other:?} and {path:?})")),
+      None => {
+        seen.insert(name, (path, is_leaf));
+      }
+    }
+  }
+  if !collisions.is_empty() {
+    let collisions = collisions.join(", ");
+    return Err(format!("entries of different inputs have the same name: {collisions}"));
+  }
+  Ok(())
+}
+
+/// Stored name of an entry, with `options.base_name` in front
+fn prefixed_name(name: &str, options: &CreateOptions) -> String{
+  let name = name.replace("\\", "/");
+  match &options.base_name {
+    Some(base_name) => format!("{}/{name}", base_name.trim_end_matches(['/', '\\'])),
+    None => name,
+  }
+}
+
+/// Fail unless `base_name` is a relative path of plain names, so the entries it prefixes can
+/// be extracted. Trailing separators are allowed
+fn check_base_name(base_name: &str) -> Result<(), String>{
+  let base_name_path = base_name.replace('\\', "/");
+  let is_plain = |part: &str| {
+    matches!(Path::new(part).components().collect::<Vec<_>>()[..], [Component::Normal(_)])
+  };
+  if !base_name_path.trim_end_matches('/').split('/').all(is_plain) {
+    return Err(format!(
+      "base name {base_name:?} must be a relative path without empty, . or .. components"
+    ));
+  }
+  Ok(())
+}
+
+/// Check options that don't depend on the input. Returns the index compression to use
+fn check_options<'a>(options: &'a CreateOptions, compression_type: &str) -> Result<&'a str, String>{
+  if options.absolute_paths && options.base_name.is_some() {
+    return Err("base name can't be used with absolute paths".to_string());
+  }
+  if options.absolute_paths && options.prefix_inputs {
+    return Err("input prefixes can't be used with absolute paths".to_string());
+  }
+  if options.max_files_per_block == Some(0) {
+    return Err("max files per block must be at least 1".to_string());
+  }
+  if let Some(base_name) = &options.base_name {
+    check_base_name(base_name)?;
+  }
+  let index_compression = options.index_compression.as_deref().unwrap_or(DEFAULT_INDEX_COMPRESSION);
+  compress_utils::compression_id(index_compression)
+    .map_err(|e| format!("invalid index compression: {e}"))?;
+  // Checked up front, as an input without files never compresses a block
+  compress_utils::compression_id(compression_type)?;
+  let block_compressions = options.compression_overrides.iter().map(|x| x.1.as_str());
+  for compression in block_compressions.chain([compression_type]) {
+    compress_utils::check_level(compression, options.compression_level)?;
+  }
+  Ok(index_compression)
+}
+
+/// Compiled `CreateOptions::compression_overrides`
+fn compile_overrides(options: &CreateOptions) -> Result<Vec<(regex::Regex, &str)>, String>{
+  options
+    .compression_overrides
+    .iter()
+    .map(|(pattern, compression)| {
+      compress_utils::compression_id(compression)
+        .map_err(|e| format!("invalid compression override for {pattern}: {e}"))?;
+      regex::Regex::new(pattern)
+        .map(|re| (re, compression.as_str()))
+        .map_err(|e| format!("at compiling compression override {pattern}: {e}"))
+    })
+    .collect()
+}
+
+/// Compression of a stored entry: the first matching override, or `compression_type`
+fn entry_compression(
+  name: &str,
+  overrides: &[(regex::Regex, &str)],
+  compression_type: &str
+) -> String{
+  overrides
+    .iter()
+    .find(|(re, _)| re.is_match(name))
+    .map_or(compression_type, |(_, compression)| compression)
+    .to_string()
+}
+
+/// Sort `file_infos` in the listing order of `index_order` and number them accordingly
+fn order_entries(
+  file_infos: &mut [ArchiveFileEntry],
+  index_order: IndexOrder
+) -> Vec<ArchiveEntryOrder>{
+  match index_order {
+    IndexOrder::Name => file_infos.sort_by(|a, b| a.name.cmp(&b.name)),
+    IndexOrder::Size => file_infos.sort_by(|a, b| a.size.cmp(&b.size).then(a.name.cmp(&b.name))),
+  }
+  number_entries(file_infos)
+}
+
+/// Listing order of `file_infos` as they are ordered now
+fn number_entries(file_infos: &[ArchiveFileEntry]) -> Vec<ArchiveEntryOrder>{
+  file_infos
+    .iter()
+    .enumerate()
+    .map(|(i, x)| ArchiveEntryOrder{ name: x.name.clone(), ord: i as _ })
+    .collect()
+}
+
+/// Sort `items` with their sizes in the order `strategy` packs them into blocks in. `path`
+/// gives the path an item is grouped by
+fn pack_order<T>(items: &mut [(T, i64)], strategy: PackStrategy, path: impl Fn(&T) -> &Path){
+  match strategy {
+    PackStrategy::SizeSorted => items.sort_by_key(|x| x.1),
+    PackStrategy::PathGrouped => items.sort_by(|a, b| path(&a.0).cmp(path(&b.0))),
+    PackStrategy::AsFound => {}
+  }
+}
+
+/// Block infos for blocks of the given compressed sizes laid out one after the other in the
+/// blob, along with the total blob size
+fn layout_blocks(
+  blocks: &[(u64, String)],
+  recoverable: bool
+) -> (Vec<ArchiveBlockInfo>, i64){
+  let mut block_infos = vec![];
+  let mut curr_offset = 0;
+  let frame_len = if recoverable { BLOCK_FRAME_LEN as i64 } else { 0 };
+  for (i, (size, compression_type)) in blocks.iter().enumerate(){
+    block_infos.push(ArchiveBlockInfo{
+      id: i as _,
+      size: *size as _,
+      offset: curr_offset + frame_len,
+      compression_type: compression_type.to_string()
+    });
+    curr_offset += frame_len + *size as i64;
+  }
+  (block_infos, curr_offset)
+}
+
+/// Frame written before every block of recoverable archives
+fn write_block_frame<W: Write>(fw: &mut W, block_size: u64) -> io::Result<()>{
+  fw.write_all(&BLOCK_FRAME_MAGIC)?;
+  fw.write_all(&block_size.to_be_bytes())
+}
+
+/// Metadata entries to store, the generated ones overridden by `options.metadata`
+fn archive_metadata(options: &CreateOptions) -> Result<Vec<ArchiveMetadataEntry>, String>{
+  let mut metadata = BTreeMap::new();
+  metadata.insert(CREATED_BY_KEY.to_string(), format!("bloda-sys {}", env!("CARGO_PKG_VERSION")));
+  if !options.reproducible {
+    let created_at = SystemTime::now()
+      .duration_since(UNIX_EPOCH)
+      .map_err(|e| format!("at getting creation time: {e}"))?
+      .as_secs();
+    metadata.insert(CREATED_AT_KEY.to_string(), created_at.to_string());
+  }
+  // User provided values win over the generated ones
+  metadata.extend(options.metadata.clone());
+  Ok(metadata.into_iter().map(|(key, value)| ArchiveMetadataEntry{ key, value }).collect())
+}
+
+/// Feature flags of an archive, from the tables that actually went into its index
+fn feature_flags(
+  recoverable: bool,
+  file_hashes: &[ArchiveFileHash],
+  owners: &[ArchiveOwner],
+  hardlinks: &[ArchiveHardlink],
+  file_flags: &[ArchiveFileFlags]
+) -> u32{
+  let mut flags = 0;
+  if !file_hashes.is_empty() {
+    flags |= header_utils::FEATURE_HASHES;
+  }
+  if recoverable {
+    flags |= header_utils::FEATURE_RECOVERABLE;
+  }
+  if !owners.is_empty() {
+    flags |= header_utils::FEATURE_OWNERS;
+  }
+  if !hardlinks.is_empty() {
+    flags |= header_utils::FEATURE_HARDLINKS;
+  }
+  if !file_flags.is_empty() {
+    flags |= header_utils::FEATURE_FILE_FLAGS;
+  }
+  flags
+}
+
+fn compress_index(index_db: &[u8], index_compression: &str) -> Result<Vec<u8>, String>{
+  let mut compressed_index = Vec::<u8>::new();
+  compress_utils::compress_data(index_db, &mut compressed_index, index_compression, None)?;
+  Ok(compressed_index)
+}
+
+/// Write the header, `index_db` compressed with `index_compression` and the blob at
+/// `blob_path` to `output`, then remove the blob
+fn assemble_archive(
+  output: &Path,
+  index_db: &[u8],
+  blob_path: &Path,
+  index_compression: &str,
+  feature_flags: u32,
+  retry: RetryPolicy,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> Result<(), String>{
+  let mut fw = fs::File::create(output)
+    .map_err(|e| format!("at opening output file {output:?}: {e}"))?;
+  let compressed_index = compress_index(index_db, index_compression)?;
+  ArchiveHeader::new(index_compression, compressed_index.len() as u64, feature_flags)
+    .write(&mut fw)?;
+  fw.write_all(&compressed_index).map_err(|e| format!("at writing index: {e}"))?;
+  let blob_start = fw.stream_position().map_err(|e| format!("at getting output position: {e}"))?;
+  retry.run(|| {
+    fw.seek(io::SeekFrom::Start(blob_start))?;
+    let mut fr = fs::File::open(blob_path)?;
+    io::copy(&mut fr, &mut fw)
+  })
+    .map_err(|e| format!("at writing blob: {e}"))?;
+  fw.flush().map_err(|e| format!("at flushing to output: {e}"))?;
+  if let Err(e) = fs::remove_file(blob_path) {
+    diagnostics.push(Diagnostic{
+      path: Some(blob_path.to_owned()),
+      message: format!("at removing temp file: {e}"),
+    });
+  }
+  Ok(())
+}
+
+/// Directory the entry names of input `dir` are relative to. A single input file is stored
+/// under its own name
+fn input_base_dir(dir: &Path) -> &Path{
+  if dir.is_file() { dir.parent().unwrap_or(dir) } else { dir }
+}
+
+/// List the paths of `source` and lay their files out into blocks, with the compression of
+/// every block. `roots` are the roots of `source`'s inputs
+fn layout_input(
+  source: InputSource,
+  roots: &[InputRoot],
+  compression_type: &str,
+  max_multi_block_size: i64,
+  options: &CreateOptions,
+  diagnostics: &mut Vec<Diagnostic>,
+) -> Result<FileLayout, String>{
+  let overrides = compile_overrides(options)?;
+  let layout = distribute_files_to_blocks(
+    source,
+    max_multi_block_size,
+    compression_type,
+    // Names that can't be made fail later, where the error is reported
+    |path| match entry_name(path, roots, options) {
+      Ok(name) => entry_compression(&name, &overrides, compression_type),
+      Err(_) => compression_type.to_string(),
+    },
+    options,
+    diagnostics
+  )?;
+  check_name_collisions(&layout, roots, options)?;
+  Ok(layout)
+}
+
+fn create_archive_inner(
+  source: InputSource,
+  output: &Path,
+  compression_type: &str,
+  max_multi_block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<CreateReport, String>{
+  let start = Instant::now();
+  let mut diagnostics = vec![];
+  let index_compression = check_options(options, compression_type)?;
+  let max_multi_block_size = max_multi_block_size.unwrap_or(DEFAULT_BLOCK_SIZE) as i64;
+  let roots = input_roots(&source.inputs(), options)?;
+  let FileLayout { blocks: block_files, folder_leaves, hardlinks, hashes, dir_count, skipped } =
+    layout_input(
+      source,
+      &roots,
+      compression_type,
+      max_multi_block_size,
+      options,
+      &mut diagnostics
+    )?;
+
+  let mut owners = vec![];
+  let mut file_flags = vec![];
+  let mut folder_leaf_infos = vec![];
+  let mut leaf_names = HashSet::new();
+  for path in &folder_leaves{
+    let name = entry_name(path, &roots, options)?;
+    // Empty folders of the same name in different inputs are stored once
+    if !leaf_names.insert(name.clone()) {
+      continue;
+    }
+    if options.store_ownership {
+      owners.extend(path_owner(path, &name)?);
+    }
+    if options.store_file_flags {
+      file_flags.extend(path_flags(path, &name));
+    }
+    folder_leaf_infos.push(ArchiveFolderLeafEntry{ name });
+  }
+  let mut hardlink_infos = hardlinks
+    .iter()
+    .map(|(link, target)| {
+      Ok(ArchiveHardlink{
+        name: entry_name(link, &roots, options)?,
+        target: entry_name(target, &roots, options)?,
+      })
+    })
+    .collect::<Result<Vec<_>, String>>()?;
+
+  let async_rt = tokio::runtime::Builder::new_multi_thread()
+    .max_blocking_threads(128)
+    .enable_all()
+    .build()
+    .map_err(|e| format!("at building async runtime: {e}"))?;
+  let buffers = BlockBuffers::default();
+  let compress_nth = |i: usize| {
+    let (compression, x) = &block_files[i];
+    let block_path = output.with_extension(format!("temp.{i}"));
+    async_rt
+      .block_on(compress_block(
+        &block_path,
+        x,
+        compression,
+        options.compression_level,
+        options.retry,
+        options.keep_going,
+        &buffers
+      ))
+      .map(|(size, failed)| ((size, compression.clone()), failed))
+  };
+  let block_results = if options.largest_blocks_first {
+    let mut block_order = (0..block_files.len()).collect::<Vec<_>>();
+    block_order.sort_by_key(|i| Reverse(block_files[*i].1.iter().map(|x| x.2).sum::<i64>()));
+    // Bridged so idle threads take the next block in this order, instead of splitting the
+    // list between threads up front
+    let mut block_results = block_order
+      .into_iter()
+      .par_bridge()
+      .map(|i| compress_nth(i).map(|x| (i, x)))
+      .collect::<Result<Vec<_>, String>>()?;
+    block_results.sort_by_key(|x| x.0);
+    block_results.into_iter().map(|x| x.1).collect()
+  } else {
+    (0..block_files.len())
+      .into_par_iter()
+      .map(compress_nth)
+      .collect::<Result<Vec<_>, String>>()?
+  };
+  let (block_sizes, failed): (Vec<_>, Vec<_>) = block_results.into_iter().unzip();
+
+  // Files that failed to read with `keep_going` were left out of their blocks, so they're left
+  // out of the index along with links to them
+  let mut skipped = skipped;
+  let mut failed_paths = HashSet::new();
+  for (path, e) in failed.into_iter().flatten(){
+    diagnostics.push(Diagnostic{ path: Some(path.clone()), message: format!("skipped, {e}") });
+    failed_paths.insert(path.clone());
+    skipped.push(path);
+  }
+  let mut file_infos = vec![];
+  let mut file_hashes = vec![];
+  for (i, (_, in_files)) in block_files.iter().enumerate(){
+    // Files after a failed one moved up to take its place
+    let mut offset = 0;
+    for (path, _, size) in in_files.iter().filter(|x| !failed_paths.contains(&x.0)){
+      let name = entry_name(path, &roots, options)?;
+      if options.store_ownership {
+        owners.extend(path_owner(path, &name)?);
+      }
+      if options.store_file_flags {
+        file_flags.extend(path_flags(path, &name));
+      }
+      if let Some(hash) = hashes.get(path){
+        file_hashes.push(ArchiveFileHash{
+          name: name.clone(),
+          hash: hash.clone(),
+        });
+      }
+      file_infos.push(ArchiveFileEntry{
+        name,
+        block: i as _,
+        offset,
+        size: *size
+      });
+      offset += size;
+    }
+  }
+  if !failed_paths.is_empty() {
+    let failed_names = failed_paths
+      .iter()
+      .map(|x| entry_name(x, &roots, options))
+      .collect::<Result<HashSet<_>, String>>()?;
+    hardlink_infos.retain(|x| !failed_names.contains(&x.target));
+  }
+  let entry_order = order_entries(&mut file_infos, options.index_order);
+
+  let (block_infos, blob_len) = layout_blocks(&block_sizes, options.recoverable);
+  let blob_path = output.with_extension("bdablob");
+  let mut fw = fs::File::create(&blob_path).map_err(|e| format!("at creating blob: {e}"))?;
+  // Preallocating the whole blob keeps it from fragmenting. Not supported everywhere, and the
+  // writes below don't depend on it
+  if blob_len > 0 {
+    if let Err(e) = fs2::FileExt::allocate(&fw, blob_len as u64) {
+      diagnostics.push(Diagnostic{ path: None, message: format!("at preallocating blob: {e}") });
+    }
+  }
+  for (i, block_info) in block_infos.iter().enumerate(){
+    let block_path = output.with_extension(format!("temp.{i}"));
+    let block_start = fw.stream_position().map_err(|e| format!("at getting blob position: {e}"))?;
+    // A retry rewrites the whole block, frame included, from where it started
+    options.retry.run(|| {
+      fw.seek(io::SeekFrom::Start(block_start))?;
+      if options.recoverable {
+        write_block_frame(&mut fw, block_info.size as u64)?;
+      }
+      let mut fr = fs::File::open(&block_path)?;
+      io::copy(&mut fr, &mut fw)
+    })
+      .map_err(|e| format!("at writing block {i} to blob: {e}"))?;
+    if let Err(e) = fs::remove_file(&block_path) {
+      diagnostics.push(Diagnostic{
+        path: Some(block_path.clone()),
+        message: format!("at removing temp file: {e}"),
+      });
+    }
+  }
+  fw.flush().map_err(|e| format!("at flushing blob: {e}"))?;
+
+  let mut report = CreateReport {
+    files: file_infos.len() + hardlink_infos.len(),
+    dirs: dir_count,
+    blocks: block_infos.len(),
+    input_bytes: file_infos.iter().map(|x| x.size as u64).sum(),
+    skipped,
+    ..Default::default()
+  };
+  let metadata = archive_metadata(options)?;
+  let flags =
+    feature_flags(options.recoverable, &file_hashes, &owners, &hardlink_infos, &file_flags);
+  let index_db = make_index_db(
+    file_infos,
+    folder_leaf_infos,
+    block_infos,
+    options.compression_level,
+    file_hashes,
+    options.hash_algorithm,
+    metadata,
+    entry_order,
+    owners,
+    hardlink_infos,
+    file_flags
+  )
+    .map_err(|e| format!("at making index db: {e}"))?;
+
+  assemble_archive(
+    output,
+    &index_db,
+    &blob_path,
+    index_compression,
+    flags,
+    options.retry,
+    &mut diagnostics
+  )?;
+  if options.verify_after_write {
+    verify_written(output)?;
+  }
+  report.output_bytes = fs::metadata(output)
+    .map_err(|e| format!("at reading size of {output:?}: {e}"))?
+    .len();
+  report.duration = start.elapsed();
+  report.diagnostics = diagnostics;
+  Ok(report)
+}
+
+/// What `create_archive_with_report` produced
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct CreateReport {
+  /// Files stored, hardlinks included
+  pub files: usize,
+  /// Folders under the input directories, empty or not
+  pub dirs: usize,
+  pub blocks: usize,
+  /// Size of the stored files before compression
+  pub input_bytes: u64,
+  /// Size of the written archive, index and header included
+  pub output_bytes: u64,
+  pub duration: Duration,
+  /// Input paths left out because they couldn't be listed or read, see
+  /// `CreateOptions::keep_going`
+  pub skipped: Vec<PathBuf>,
+  /// What went wrong along the way without stopping it, see `create_archive_with_diagnostics`
+  pub diagnostics: Vec<Diagnostic>,
+}
+
+impl CreateReport {
+  /// Input size over output size, 0 for an empty input
+  pub fn ratio(&self) -> f64 {
+    if self.output_bytes == 0 {
+      return 0.0;
+    }
+    self.input_bytes as f64 / self.output_bytes as f64
+  }
+}
+
+/// Block of an `ArchivePlan`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlannedBlock {
+  pub compression: String,
+  /// Entry names with their sizes, in the order they'd be stored
+  pub files: Vec<(String, u64)>,
+  /// Size of the block before compression
+  pub size: u64,
+}
+
+/// How `create_archive_with_options` would lay out an archive, see `plan_archive`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ArchivePlan {
+  /// Blocks in the order they'd be written
+  pub blocks: Vec<PlannedBlock>,
+  /// Names of the empty folders that would be stored
+  pub folder_leaves: Vec<String>,
+  /// Size of all files before compression
+  pub total_size: u64,
+  /// What creating the archive would warn about, like files that can't be read
+  pub diagnostics: Vec<Diagnostic>,
+}
+
+/// Work out how the files of `dir` would be grouped into blocks by
+/// `create_archive_with_options` with the same arguments, without compressing or writing
+/// anything. Files are only listed, not read, so hashing options are ignored
+pub fn plan_archive(
+  dir: &Path,
+  compression_type: &str,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<ArchivePlan, String>{
+  check_options(options, compression_type)?;
+  let layout_options = CreateOptions { hash_files: false, ..options.clone() };
+  let mut diagnostics = vec![];
+  let max_multi_block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE) as i64;
+  let inputs = [dir];
+  let source = InputSource::Walk(&inputs);
+  let roots = input_roots(&source.inputs(), options)?;
+  let FileLayout { blocks, folder_leaves, .. } = layout_input(
+    source,
+    &roots,
+    compression_type,
+    max_multi_block_size,
+    &layout_options,
+    &mut diagnostics
+  )?;
+  let blocks = blocks
+    .into_iter()
+    .map(|(compression, in_files)| {
+      let files = in_files
+        .iter()
+        .map(|(path, _, size)| Ok((entry_name(path, &roots, options)?, *size as u64)))
+        .collect::<Result<Vec<_>, String>>()?;
+      let size = files.iter().map(|x| x.1).sum();
+      Ok(PlannedBlock { compression, files, size })
+    })
+    .collect::<Result<Vec<_>, String>>()?;
+  let folder_leaves = folder_leaves
+    .iter()
+    .map(|path| entry_name(path, &roots, options))
+    .collect::<Result<Vec<_>, String>>()?;
+  Ok(ArchivePlan {
+    total_size: blocks.iter().map(|x| x.size).sum(),
+    blocks,
+    folder_leaves,
+    diagnostics,
+  })
+}
+
+/// Something that went wrong while creating an archive without stopping it, like a file that
+/// couldn't be read and was left out
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+  /// Input or temp file it's about, if any
+  pub path: Option<PathBuf>,
+  pub message: String,
+}
+
+impl fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match &self.path {
+      Some(path) => write!(f, "{path:?}: {}", self.message),
+      None => write!(f, "{}", self.message),
+    }
+  }
+}
+
+/// Order in which files are packed into blocks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum PackStrategy {
+  /// Smallest files first
+  #[default]
+  SizeSorted,
+  /// Sorted by path, so files of a directory land in neighbouring blocks
+  PathGrouped,
+  /// Directory traversal order
+  AsFound,
+}
+
+impl FromStr for PackStrategy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "size-sorted" => Ok(Self::SizeSorted),
+      "path-grouped" => Ok(Self::PathGrouped),
+      "as-found" => Ok(Self::AsFound),
+      _ => Err(format!("unknown pack strategy {s}, expected size-sorted, path-grouped, as-found")),
+    }
+  }
+}
+
+/// Order in which files are listed by the reader, independent of how they are packed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IndexOrder {
+  /// Lexicographic by entry name
+  #[default]
+  Name,
+  /// Smallest files first, ties broken by name
+  Size,
+}
+
+impl FromStr for IndexOrder {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "name" => Ok(Self::Name),
+      "size" => Ok(Self::Size),
+      _ => Err(format!("unknown index order {s}, expected name, size")),
+    }
+  }
+}
+
+/// Extra knobs for archive creation. `Default` matches `create_archive`
+#[derive(Debug, Clone, Default)]
+pub struct CreateOptions {
+  /// Prefix prepended to every stored entry name. Has to be a relative path of plain names, so
+  /// `..`, `.` and absolute paths are an error
+  pub base_name: Option<String>,
+  /// Store the full input paths instead of paths relative to the input directory
+  pub absolute_paths: bool,
+  /// Store a content hash for every file
+  pub hash_files: bool,
+  /// Algorithm of the hashes of `hash_files`, recorded once for the whole archive
+  pub hash_algorithm: HashAlgorithm,
+  /// Order files are packed into blocks in. Only changes which files share a block, not the
+  /// order they're listed in, see `index_order`
+  pub pack_strategy: PackStrategy,
+  /// Order of entries in the index, see `ArchiveReader::list_all_entries`
+  pub index_order: IndexOrder,
+  /// Compression used for the index, independent of the blocks. Defaults to ZSTD
+  pub index_compression: Option<String>,
+  /// Key-value pairs stored in the index, see `ArchiveReader::metadata`. The creating version and
+  /// time are added automatically unless overridden here
+  pub metadata: BTreeMap<String, String>,
+  /// Leave out the creation time so identical inputs give identical archives
+  pub reproducible: bool,
+  /// Frame every block in the blob with a magic and its size so blocks can be found without
+  /// the index. See `scan_blocks`
+  pub recoverable: bool,
+  /// Level for block compression, the per type default if None. Ignored by LZ4 and SNAPPY,
+  /// out of range levels for the others are an error, see `compress_utils::check_level`
+  pub compression_level: Option<u32>,
+  /// Read the archive back once written and fail if any file doesn't decompress or doesn't match
+  /// its hash, see `ArchiveReader::check`. Reads everything a second time. A failed archive is
+  /// left in place for inspection
+  pub verify_after_write: bool,
+  /// Retrying of transient errors while reading input files and writing the archive
+  pub retry: RetryPolicy,
+  /// Regex on stored entry names and the compression to use for matching files instead of the
+  /// archive's, e.g. `NONE` for media that's already compressed. The first match wins. Matching
+  /// files are packed into blocks of their own
+  pub compression_overrides: Vec<(String, String)>,
+  /// Store the uid and gid of files and empty folders, see `ArchiveReader::owner`. Unix only,
+  /// ignored elsewhere
+  pub store_ownership: bool,
+  /// Store the content of hardlinked files once and the other paths as links to it, recreated
+  /// on extraction. Unix only, ignored elsewhere
+  pub detect_hardlinks: bool,
+  /// Only archive this many levels below the input directory, its direct children being level
+  /// 1. Folders at the limit are stored empty
+  pub max_depth: Option<usize>,
+  /// Store flags like immutable and append-only of files and empty folders, see
+  /// `ArchiveReader::file_flags`. Linux and the BSDs only. Entries whose flags can't be read are
+  /// stored without
+  pub store_file_flags: bool,
+  /// Compress the biggest blocks first when compressing in parallel, so a big block left for
+  /// the end doesn't hold up the rest. Blocks are still written in order, the archive is the
+  /// same either way
+  pub largest_blocks_first: bool,
+  /// Leave out files that can't be listed, opened, hashed or read, e.g. because they vanished
+  /// or aren't readable, instead of failing the archive. They're reported as diagnostics and
+  /// in `CreateReport::skipped`
+  pub keep_going: bool,
+  /// Store the entries of every input directory under the directory's own name, so inputs with
+  /// the same layout don't collide. Single input files are stored under their name either way
+  pub prefix_inputs: bool,
+  /// Put at most this many files in a block. A block is closed at whichever of this and the
+  /// block size is reached first, so many tiny files can be spread over blocks that decompress
+  /// independently
+  pub max_files_per_block: Option<usize>,
+}
+
+pub fn create_archive(
+  dir: &Path,
+  output: &Path,
+  compression_type: &str,
+  threads: u8,
+  block_size: Option<u64>
+) -> Result<(), String>{
+  create_archive_with_options(
+    dir,
+    output,
+    compression_type,
+    threads,
+    block_size,
+    &CreateOptions::default()
+  )
+}
+
+pub fn create_archive_with_options(
+  dir: &Path,
+  output: &Path,
+  compression_type: &str,
+  threads: u8,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<(), String>{
+  let diagnostics =
+    create_archive_with_diagnostics(dir, output, compression_type, threads, block_size, options)?;
+  log_diagnostics(&diagnostics);
+  Ok(())
+}
+
+/// Log `diagnostics` as warnings, for the functions that don't return them
+fn log_diagnostics(diagnostics: &[Diagnostic]){
+  for diagnostic in diagnostics {
+    log::warn!("{diagnostic}");
+  }
+}
+
+/// Same as `create_archive_with_options`, but returns what went wrong along the way without
+/// stopping it instead of logging it. See `CreateOptions::keep_going`
+pub fn create_archive_with_diagnostics(
+  dir: &Path,
+  output: &Path,
+  compression_type: &str,
+  threads: u8,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<Vec<Diagnostic>, String>{
+  create_archive_with_report(dir, output, compression_type, threads, block_size, options)
+    .map(|x| x.diagnostics)
+}
+
+/// Same as `create_archive_with_diagnostics`, but also returns counts and sizes of what was
+/// written and how long it took
+pub fn create_archive_with_report(
+  dir: &Path,
+  output: &Path,
+  compression_type: &str,
+  threads: u8,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<CreateReport, String>{
+  let t_pool = rayon::ThreadPoolBuilder::new()
+    .num_threads(threads as _)
+    .build()
+    .map_err(|e| format!("at creating thread pool: {e}"))?;
+  let source = InputSource::Walk(&[dir]);
+  t_pool.install(|| {create_archive_inner(source, output, compression_type, block_size, options)})
+}
+
+/// Same as `create_archive_with_report`, but merges several input directories or files into
+/// one archive. Entry names are relative to their own input, see `CreateOptions::prefix_inputs`
+/// to keep inputs apart. Fails if entries of different inputs would have the same name
+pub fn create_archive_from_inputs(
+  inputs: &[&Path],
+  output: &Path,
+  compression_type: &str,
+  threads: u8,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<CreateReport, String>{
+  let t_pool = rayon::ThreadPoolBuilder::new()
+    .num_threads(threads as _)
+    .build()
+    .map_err(|e| format!("at creating thread pool: {e}"))?;
+  let source = InputSource::Walk(inputs);
+  t_pool.install(|| {create_archive_inner(source, output, compression_type, block_size, options)})
+}
+
+/// `path` with its `.` and `..` components resolved without looking at the filesystem, so a
+/// `..` after a link goes back to where the link is, not above its target. `..` at the root
+/// stays there
+fn normalize_lexically(path: &Path) -> PathBuf{
+  let mut normalized = PathBuf::new();
+  for component in path.components(){
+    match component {
+      Component::CurDir => {}
+      Component::ParentDir => {
+        normalized.pop();
+      }
+      component => normalized.push(component),
+    }
+  }
+  normalized
+}
+
+/// Same as `create_archive_with_report`, but archives exactly the files and empty folders of
+/// `paths` instead of everything under a directory, e.g. the output of `find`. Folders in the
+/// list don't bring their contents along. Entries are named relative to `base_dir`, which all
+/// paths have to be under once their `..` are resolved. Relative paths are taken from the
+/// current directory
+pub fn create_archive_from_list(
+  paths: &[PathBuf],
+  base_dir: &Path,
+  output: &Path,
+  compression_type: &str,
+  threads: u8,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<CreateReport, String>{
+  // Made absolute without resolving links, so `./a` and `a` are the same and a listed link is
+  // stored under its own name
+  let absolute = |path: &Path| {
+    std::path::absolute(path)
+      .map(|x| normalize_lexically(&x))
+      .map_err(|e| format!("at resolving {path:?}: {e}"))
+  };
+  let base_dir = absolute(base_dir)?;
+  let paths = paths
+    .iter()
+    .map(|path| {
+      let full_path = absolute(path)?;
+      if !full_path.starts_with(&base_dir) {
+        return Err(format!("{path:?} is outside the base directory {base_dir:?}"));
+      }
+      Ok(full_path)
+    })
+    .collect::<Result<Vec<_>, String>>()?;
+  let t_pool = rayon::ThreadPoolBuilder::new()
+    .num_threads(threads as _)
+    .build()
+    .map_err(|e| format!("at creating thread pool: {e}"))?;
+  let source = InputSource::List(&base_dir, &paths);
+  t_pool.install(|| {create_archive_inner(source, output, compression_type, block_size, options)})
+}
+
+/// Build an archive from in-memory `(name, contents)` entries and return its bytes, in the same
+/// format `create_archive_with_options` writes to disk. Names are stored as given, with
+/// `options.base_name` in front. Nothing is written to disk along the way
+pub fn create_archive_to_vec(
+  entries: &[(&str, &[u8])],
+  compression_type: &str,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<Vec<u8>, String>{
+  let (archive, diagnostics) =
+    create_archive_to_vec_with_diagnostics(entries, compression_type, block_size, options)?;
+  log_diagnostics(&diagnostics);
+  Ok(archive)
+}
+
+/// Same as `create_archive_to_vec`, but also returns what went wrong along the way without
+/// stopping it instead of logging it
+pub fn create_archive_to_vec_with_diagnostics(
+  entries: &[(&str, &[u8])],
+  compression_type: &str,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<(Vec<u8>, Vec<Diagnostic>), String>{
+  let mut diagnostics = vec![];
+  let index_compression = check_options(options, compression_type)?;
+  let overrides = compile_overrides(options)?;
+  if options.absolute_paths {
+    return Err("absolute paths can't be used with in-memory entries".to_string());
+  }
+  let max_multi_block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE) as i64;
+  let mut entries_w_sizes = entries
+    .iter()
+    .map(|(name, data)| ((prefixed_name(name, options), *data), data.len() as i64))
+    .collect::<Vec<_>>();
+  pack_order(&mut entries_w_sizes, options.pack_strategy, |(name, _)| Path::new(name));
+  for ((name, _), size) in entries_w_sizes.iter().filter(|x| x.1 > max_multi_block_size){
+    diagnostics.push(Diagnostic{
+      path: None,
+      message: format!("{name}: {size} bytes, bigger than the block size, stored alone"),
+    });
+  }
+  let blocks = pack_by_compression(
+    entries_w_sizes,
+    |(name, _)| entry_compression(name, &overrides, compression_type),
+    compression_type,
+    max_multi_block_size,
+    options.max_files_per_block
+  );
+
+  let mut file_infos = vec![];
+  let mut file_hashes = vec![];
+  let mut compressed_blocks = vec![];
+  let mut block_data = vec![];
+  for (i, (compression, block_entries)) in blocks.iter().enumerate(){
+    block_data.clear();
+    for ((name, data), offset, size) in block_entries{
+      if options.hash_files {
+        file_hashes.push(ArchiveFileHash{
+          name: name.clone(),
+          hash: hash_utils::hash_bytes(data, options.hash_algorithm),
+        });
+      }
+      file_infos.push(ArchiveFileEntry{
+        name: name.clone(),
+        block: i as _,
+        offset: *offset,
+        size: *size
+      });
+      block_data.extend_from_slice(data);
+    }
+    let mut compressed_block = vec![compress_utils::compression_id(compression)?];
+    compress_utils::compress_data(
+      &block_data[..],
+      &mut compressed_block,
+      compression,
+      options.compression_level
+    )?;
+    compressed_blocks.push((compressed_block, compression.clone()));
+  }
+  let entry_order = order_entries(&mut file_infos, options.index_order);
+  let block_sizes = compressed_blocks
+    .iter()
+    .map(|(x, compression)| (x.len() as u64, compression.clone()))
+    .collect::<Vec<_>>();
+  let (block_infos, blob_len) = layout_blocks(&block_sizes, options.recoverable);
+
+  // In-memory entries have no owners, links or flags to store
+  let flags = feature_flags(options.recoverable, &file_hashes, &[], &[], &[]);
+  let index_db = make_index_db(
+    file_infos,
+    vec![],
+    block_infos,
+    options.compression_level,
+    file_hashes,
+    options.hash_algorithm,
+    archive_metadata(options)?,
+    entry_order,
+    vec![],
+    vec![],
+    vec![]
+  )
+    .map_err(|e| format!("at making index db: {e}"))?;
+  let compressed_index = compress_index(&index_db, index_compression)?;
+
+  let header =
+    ArchiveHeader::new(index_compression, compressed_index.len() as u64, flags);
+  let mut archive =
+    Vec::with_capacity(header.size() as usize + compressed_index.len() + blob_len as usize);
+  header.write(&mut archive)?;
+  archive.extend_from_slice(&compressed_index);
+  for (compressed_block, _) in compressed_blocks{
+    if options.recoverable {
+      write_block_frame(&mut archive, compressed_block.len() as u64)
+        .map_err(|e| format!("at writing block frame: {e}"))?;
+    }
+    archive.extend_from_slice(&compressed_block);
+  }
+  if options.verify_after_write {
+    ArchiveReader::from_bytes(&archive)
+      .and_then(|x| x.check())
+      .map_err(|e| format!("at verifying written archive: {e}"))?;
+  }
+  Ok((archive, diagnostics))
+}
+
+/// Create an archive from named streams of unknown size, such as generated data or network
+/// responses. Each stream is read to its end before it goes into a block, so one entry is held
+/// in memory at a time on top of the blocks being filled. Entries are never split across
+/// blocks, one bigger than `block_size` gets a block of its own
+pub fn create_archive_from_streams<I: IntoIterator<Item = (String, Box<dyn Read>)>>(
+  entries: I,
+  output: &Path,
+  compression_type: &str,
+  block_size: Option<u64>,
+  options: &CreateOptions,
+) -> Result<(), String>{
+  let index_compression = check_options(options, compression_type)?;
+  let overrides = compile_overrides(options)?;
+  if options.absolute_paths {
+    return Err("absolute paths can't be used with stream entries".to_string());
+  }
+  let max_multi_block_size = block_size.unwrap_or(DEFAULT_BLOCK_SIZE) as usize;
+  let blob_path = output.with_extension("bdablob");
+  let mut blob = fs::File::create(&blob_path).map_err(|e| format!("at creating blob: {e}"))?;
+  let mut file_infos = vec![];
+  let mut file_hashes = vec![];
+  let mut block_sizes = vec![];
+  let mut write_block = |compression: &str, block: &mut StreamBlock| -> Result<(), String> {
+    let block_id = block_sizes.len();
+    for (name, offset, size) in block.entries.drain(..){
+      file_infos.push(ArchiveFileEntry{ name, block: block_id as _, offset, size });
+    }
+    let mut compressed_block = vec![compress_utils::compression_id(compression)?];
+    compress_utils::compress_data(
+      &block.data[..],
+      &mut compressed_block,
+      compression,
+      options.compression_level
+    )?;
+    block.data.clear();
+    let block_start =
+      blob.stream_position().map_err(|e| format!("at getting blob position: {e}"))?;
+    options.retry.run(|| {
+      blob.seek(io::SeekFrom::Start(block_start))?;
+      if options.recoverable {
+        write_block_frame(&mut blob, compressed_block.len() as u64)?;
+      }
+      blob.write_all(&compressed_block)
+    })
+      .map_err(|e| format!("at writing block {block_id} to blob: {e}"))?;
+    block_sizes.push((compressed_block.len() as u64, compression.to_string()));
+    Ok(())
+  };
+
+  // One block being filled per compression
+  let mut open_blocks = BTreeMap::<String, StreamBlock>::new();
+  let mut entry_data = vec![];
+  for (name, reader) in entries{
+    let name = prefixed_name(&name, options);
+    entry_data.clear();
+    RetryReader::new(reader, options.retry)
+      .read_to_end(&mut entry_data)
+      .map_err(|e| format!("at reading {name}: {e}"))?;
+    if entry_data.len() > max_multi_block_size {
+      log::warn!(
+        "{name} ({} bytes) is bigger than the block size, storing it alone",
+        entry_data.len()
+      );
+    }
+    if options.hash_files {
+      file_hashes.push(ArchiveFileHash{
+        name: name.clone(),
+        hash: hash_utils::hash_bytes(&entry_data, options.hash_algorithm),
+      });
+    }
+    let compression = entry_compression(&name, &overrides, compression_type);
+    let block = open_blocks.entry(compression.clone()).or_default();
+    let block_full = block.data.len() + entry_data.len() > max_multi_block_size
+      || options.max_files_per_block.is_some_and(|n| block.entries.len() >= n);
+    if !block.entries.is_empty() && block_full {
+      write_block(&compression, block)?;
+    }
+    block.entries.push((name, block.data.len() as i64, entry_data.len() as i64));
+    block.data.extend_from_slice(&entry_data);
+  }
+  for (compression, mut block) in open_blocks{
+    if !block.entries.is_empty() {
+      write_block(&compression, &mut block)?;
+    }
+  }
+  blob.flush().map_err(|e| format!("at flushing blob: {e}"))?;
+  drop(blob);
+
+  let (block_infos, _) = layout_blocks(&block_sizes, options.recoverable);
+  let entry_order = order_entries(&mut file_infos, options.index_order);
+  let mut diagnostics = vec![];
+  // Streams have no owners, links or flags to store
+  let flags = feature_flags(options.recoverable, &file_hashes, &[], &[], &[]);
+  let index_db = make_index_db(
+    file_infos,
+    vec![],
+    block_infos,
+    options.compression_level,
+    file_hashes,
+    options.hash_algorithm,
+    archive_metadata(options)?,
+    entry_order,
+    vec![],
+    vec![],
+    vec![]
+  )
+    .map_err(|e| format!("at making index db: {e}"))?;
+  assemble_archive(
+    output,
+    &index_db,
+    &blob_path,
+    index_compression,
+    flags,
+    options.retry,
+    &mut diagnostics
+  )?;
+  log_diagnostics(&diagnostics);
+  if options.verify_after_write {
+    verify_written(output)?;
+  }
+  Ok(())
+}
+
+/// Re-encode every block of the archive at `input` with `compression_type` into a new archive at
+/// `output`, keeping a single block in memory at a time. Blocks bigger than the default
+/// `ReaderOptions::max_mem_extract_size` go through temp files instead. Entries, hashes, metadata
+/// and the index compression are carried over as they are
+pub fn transcode_archive(
+  input: &Path,
+  output: &Path,
+  compression_type: &str,
+  level: Option<u32>,
+) -> Result<(), String>{
+  compress_utils::compression_id(compression_type)?;
+  compress_utils::check_level(compression_type, level)?;
+  let reader = ArchiveReader::new(input, None)?;
+  let blob_path = output.with_extension("bdablob");
+  let res = transcode_reader(&reader, output, &blob_path, compression_type, level);
+  if res.is_err() {
+    // Gone already if the archive got as far as being assembled
+    let _ = fs::remove_file(&blob_path);
+  }
+  res
+}
+
+/// Re-encode the blocks of `reader` one at a time into `blob_path`
+fn transcode_blocks(
+  reader: &ArchiveReader,
+  blob_path: &Path,
+  compression_type: &str,
+  level: Option<u32>,
+  recoverable: bool
+) -> Result<Vec<(u64, String)>, String>{
+  let marker = compress_utils::compression_id(compression_type)?;
+  let mut fw = fs::File::create(blob_path).map_err(|e| format!("at creating blob: {e}"))?;
+  let mut block_sizes = vec![];
+  let mut compressed_block = vec![];
+  for block_id in 0..reader.block_count() as i64{
+    if reader.block_infos[block_id as usize].size > reader.max_mem_extract_size {
+      let raw_file = tempfile::NamedTempFile::new()
+        .map_err(|e| format!("at creating tempfile: {e}"))?;
+      reader
+        .extract_block_file(block_id, raw_file.path())
+        .map_err(|e| format!("at reading block {block_id}: {e}"))?;
+      let compressed_file = tempfile::NamedTempFile::new()
+        .map_err(|e| format!("at creating tempfile: {e}"))?;
+      let fr = fs::File::open(raw_file.path()).map_err(|e| format!("at opening tempfile: {e}"))?;
+      let size = compress_stream_block(compressed_file.path(), fr, compression_type, level)?;
+      if recoverable {
+        write_block_frame(&mut fw, size).map_err(|e| format!("at writing block frame: {e}"))?;
+      }
+      let mut fr = fs::File::open(compressed_file.path())
+        .map_err(|e| format!("at opening tempfile: {e}"))?;
+      io::copy(&mut fr, &mut fw).map_err(|e| format!("at writing block {block_id}: {e}"))?;
+      block_sizes.push((size, compression_type.to_string()));
+      continue;
+    }
+    let block_data = reader
+      .extract_block_mem(block_id)
+      .map_err(|e| format!("at reading block {block_id}: {e}"))?;
+    compressed_block.clear();
+    compressed_block.push(marker);
+    compress_utils::compress_data(&block_data[..], &mut compressed_block, compression_type, level)?;
+    if recoverable {
+      write_block_frame(&mut fw, compressed_block.len() as u64)
+        .map_err(|e| format!("at writing block frame: {e}"))?;
+    }
+    fw.write_all(&compressed_block).map_err(|e| format!("at writing block {block_id}: {e}"))?;
+    block_sizes.push((compressed_block.len() as u64, compression_type.to_string()));
+  }
+  fw.flush().map_err(|e| format!("at flushing blob: {e}"))?;
+  Ok(block_sizes)
+}
+
+fn transcode_reader(
+  reader: &ArchiveReader,
+  output: &Path,
+  blob_path: &Path,
+  compression_type: &str,
+  level: Option<u32>,
+) -> Result<(), String>{
+  let mut flags = reader.header.feature_flags;
+  // Archives from before the feature flags still say whether they are hashed through the index
+  if !reader.file_hashes.is_empty() {
+    flags |= header_utils::FEATURE_HASHES;
+  }
+  let recoverable = flags & header_utils::FEATURE_RECOVERABLE != 0;
+  let block_sizes = transcode_blocks(reader, blob_path, compression_type, level, recoverable)?;
+  let (block_infos, _) = layout_blocks(&block_sizes, recoverable);
+
+  let file_hashes = reader
+    .files
+    .iter()
+    .filter_map(|x| {
+      reader.file_hashes.get(&x.name).map(|(hash, _)| ArchiveFileHash{
+        name: x.name.clone(),
+        hash: hash.clone(),
+      })
+    })
+    .collect();
+  // Archives hash every file with the same algorithm
+  let hash_algorithm = reader.file_hashes.values().next().map(|x| x.1).unwrap_or_default();
+  // The reader keeps files in their listing order
+  let entry_order = number_entries(&reader.files);
+  let mut owners = reader
+    .owners
+    .iter()
+    .map(|(name, (uid, gid))| ArchiveOwner{ name: name.clone(), uid: *uid as _, gid: *gid as _ })
+    .collect::<Vec<_>>();
+  owners.sort_by(|a, b| a.name.cmp(&b.name));
+  let mut file_flags = reader
+    .file_flags
+    .iter()
+    .map(|(name, flags)| ArchiveFileFlags{ name: name.clone(), flags: *flags as _ })
+    .collect::<Vec<_>>();
+  file_flags.sort_by(|a, b| a.name.cmp(&b.name));
+  let mut folder_leaves = reader.folder_leaves.values().cloned().collect::<Vec<_>>();
+  folder_leaves.sort_by(|a, b| a.name.cmp(&b.name));
+  let mut metadata = reader.metadata.clone().into_iter().collect::<Vec<_>>();
+  metadata.sort();
+  let index_db = make_index_db(
+    reader.files.clone(),
+    folder_leaves,
+    block_infos,
+    level,
+    file_hashes,
+    hash_algorithm,
+    metadata.into_iter().map(|(key, value)| ArchiveMetadataEntry{ key, value }).collect(),
+    entry_order,
+    owners,
+    reader.hardlinks.clone(),
+    file_flags
+  )
+    .map_err(|e| format!("at making index db: {e}"))?;
+  let mut diagnostics = vec![];
+  assemble_archive(
+    output,
+    &index_db,
+    blob_path,
+    reader.index_compression(),
+    flags,
+    RetryPolicy::default(),
+    &mut diagnostics
+  )?;
+  log_diagnostics(&diagnostics);
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::test_utils::{archive_dir, capture_warnings, list_tree, warnings_about, write_tree};
+
+  #[test]
+  fn hashes_round_trip_with_their_algorithm(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    let options = CreateOptions {
+      hash_files: true,
+      hash_algorithm: HashAlgorithm::Sha256,
+      ..Default::default()
+    };
+    let (_, reader) = archive_dir(&input, work.path(), None, &options);
+
+    let (hash, algorithm) = reader.file_hash("b/c.txt").unwrap();
+    assert_eq!(algorithm, HashAlgorithm::Sha256);
+    assert_eq!(hash, hash_utils::hash_bytes(b"cc", HashAlgorithm::Sha256));
+    assert!(reader.verify().unwrap().is_empty());
+  }
+
+  #[test]
+  fn archive_with_foreign_index_is_unsupported(){
+    use diesel::RunQueryDsl;
+
+    let work = tempfile::tempdir().unwrap();
+    let mut conn = diesel::SqliteConnection::establish(":memory:").unwrap();
+    diesel::sql_query("CREATE TABLE files(path TEXT PRIMARY KEY, data BLOB)")
+      .execute(&mut conn)
+      .unwrap();
+    let index_db = conn.serialize_database_to_buffer().to_vec();
+    let blob_path = work.path().join("archive.bdablob");
+    fs::write(&blob_path, b"").unwrap();
+    let archive_path = work.path().join("archive.bda");
+    let retry = RetryPolicy::default();
+    assemble_archive(&archive_path, &index_db, &blob_path, "ZSTD", 0, retry, &mut vec![]).unwrap();
+
+    let err = ArchiveReader::new(&archive_path, None).err().unwrap();
+    assert!(err.contains("unsupported index schema"), "{err}");
+  }
+
+  #[test]
+  fn streams_with_failing_reads_are_retried(){
+    /// Reader whose every other read fails with a transient error
+    struct Flaky(io::Cursor<Vec<u8>>, bool);
+
+    impl Read for Flaky {
+      fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.1 = !self.1;
+        if self.1 {
+          return Err(io::Error::from(io::ErrorKind::TimedOut));
+        }
+        Read::read(&mut self.0, buf)
+      }
+    }
+
+    let work = tempfile::tempdir().unwrap();
+    let archive_path = work.path().join("archive.bda");
+    let entries: Vec<(String, Box<dyn Read>)> = vec![
+      ("a".to_string(), Box::new(Flaky(io::Cursor::new(b"first".to_vec()), false))),
+      ("b".to_string(), Box::new(Flaky(io::Cursor::new(b"second".to_vec()), false))),
+    ];
+    let options = CreateOptions {
+      retry: RetryPolicy::new(1, Duration::ZERO),
+      ..Default::default()
+    };
+    create_archive_from_streams(entries, &archive_path, "ZSTD", None, &options).unwrap();
+
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert_eq!(reader.read_file("a").unwrap(), b"first");
+    assert_eq!(reader.read_file("b").unwrap(), b"second");
+  }
+
+  #[test]
+  fn files_of_a_block_are_read_in_place(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (0..50)
+      .map(|i| (format!("f{i:02}"), format!("content of file {i}").repeat(i + 1)))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content.as_bytes())]);
+    }
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+
+    assert_eq!(reader.block_count(), 1);
+    for (name, content) in &files {
+      assert_eq!(reader.read_file(name).unwrap(), content.as_bytes());
+    }
+  }
+
+  #[test]
+  fn to_vec_packs_and_lists_like_a_directory(){
+    let files: [(&str, &[u8]); 4] = [
+      ("b/x.txt", b"xxxxxxxx"),
+      ("a.txt", b"aaaa"),
+      ("b/y.txt", b"y"),
+      ("c.txt", b"cccccc"),
+    ];
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &files);
+    for (pack_strategy, index_order) in [
+      (PackStrategy::SizeSorted, IndexOrder::Name),
+      (PackStrategy::PathGrouped, IndexOrder::Size),
+    ] {
+      let options = CreateOptions { pack_strategy, index_order, ..Default::default() };
+      let (_, from_dir) = archive_dir(&input, work.path(), Some(10), &options);
+      let data = create_archive_to_vec(&files, "ZSTD", Some(10), &options).unwrap();
+      let from_vec = ArchiveReader::from_bytes(&data).unwrap();
+
+      assert_eq!(from_vec.list_all_entries(), from_dir.list_all_entries());
+      assert_eq!(from_vec.block_count(), from_dir.block_count());
+      for block_id in 0..from_dir.block_count() as u64 {
+        assert_eq!(from_vec.files_in_block(block_id), from_dir.files_in_block(block_id));
+      }
+      for (name, content) in files {
+        assert_eq!(from_vec.read_file(name).unwrap(), content);
+      }
+    }
+  }
+
+  #[test]
+  fn oversize_entries_are_logged(){
+    capture_warnings();
+    let big = vec![7u8; 64];
+    let entries: [(&str, &[u8]); 2] = [("oversize-to-vec.bin", &big), ("small", b"s")];
+    let data = create_archive_to_vec(&entries, "ZSTD", Some(16), &CreateOptions::default())
+      .unwrap();
+    assert_eq!(warnings_about("oversize-to-vec.bin").len(), 1);
+    let reader = ArchiveReader::from_bytes(&data).unwrap();
+    assert_eq!(reader.read_file("oversize-to-vec.bin").unwrap(), big);
+
+    let work = tempfile::tempdir().unwrap();
+    let stream_entries: Vec<(String, Box<dyn Read>)> = vec![
+      ("oversize-stream.bin".to_string(), Box::new(io::Cursor::new(big.clone()))),
+    ];
+    let archive_path = work.path().join("archive.bda");
+    let options = CreateOptions::default();
+    create_archive_from_streams(stream_entries, &archive_path, "ZSTD", Some(16), &options).unwrap();
+    assert_eq!(warnings_about("oversize-stream.bin").len(), 1);
+  }
+
+  #[test]
+  fn snappy_blocks_read_back(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"snappy snappy snappy"), ("b/c.txt", b"cc")]);
+    let archive_path = work.path().join("archive.bda");
+    create_archive_with_options(&input, &archive_path, "SNAPPY", 2, None, &Default::default())
+      .unwrap();
+
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert!(reader.blocks().iter().all(|x| x.compression == "SNAPPY"));
+    assert_eq!(reader.read_file("a.txt").unwrap(), b"snappy snappy snappy");
+    assert_eq!(reader.read_file("b/c.txt").unwrap(), b"cc");
+  }
+
+  #[test]
+  fn bzip2_blocks_read_back(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"bzip2 bzip2 bzip2"), ("b/c.txt", b"cc")]);
+    let options = CreateOptions { compression_level: Some(3), ..Default::default() };
+    let archive_path = work.path().join("archive.bda");
+    create_archive_with_options(&input, &archive_path, "BZIP2", 2, None, &options).unwrap();
+
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert!(reader.blocks().iter().all(|x| x.compression == "BZIP2"));
+    assert_eq!(reader.read_file("a.txt").unwrap(), b"bzip2 bzip2 bzip2");
+    assert_eq!(reader.read_file("b/c.txt").unwrap(), b"cc");
+  }
+
+  #[test]
+  fn bad_levels_are_rejected_up_front(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    let archive_path = work.path().join("archive.bda");
+    for level in [0, 10] {
+      let options = CreateOptions { compression_level: Some(level), ..Default::default() };
+      let err = create_archive_with_options(&input, &archive_path, "BZIP2", 2, None, &options)
+        .unwrap_err();
+      assert!(err.contains("BZIP2 level"), "{err}");
+    }
+    let options = CreateOptions {
+      compression_level: Some(12),
+      compression_overrides: vec![(r"\.txt$".to_string(), "LZMA".to_string())],
+      ..Default::default()
+    };
+    assert!(create_archive_with_options(&input, &archive_path, "ZSTD", 2, None, &options).is_err());
+    assert!(!archive_path.exists());
+  }
+
+  #[test]
+  fn block_buffers_are_reused_across_blocks(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let blocks = (0..200)
+      .map(|i| {
+        let names = [format!("{i}/a"), format!("{i}/b")];
+        let contents = [format!("block {i}, first"), "second".repeat(i % 7 + 1)];
+        for (name, content) in names.iter().zip(&contents) {
+          write_tree(&input, &[(name, content.as_bytes())]);
+        }
+        let sizes = contents.map(|x| x.len() as i64);
+        vec![
+          (input.join(&names[0]), 0, sizes[0]),
+          (input.join(&names[1]), sizes[0], sizes[1]),
+        ]
+      })
+      .collect::<Vec<_>>();
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let compress = |block: &[(PathBuf, i64, i64)], output: &Path, buffers: &BlockBuffers| {
+      let retry = RetryPolicy::default();
+      runtime.block_on(compress_block(output, block, "ZSTD", None, retry, false, buffers)).unwrap();
+      fs::read(output).unwrap()
+    };
+
+    let buffers = BlockBuffers::default();
+    let shared_output = work.path().join("shared");
+    let fresh_output = work.path().join("fresh");
+    for block in &blocks {
+      let shared = compress(block, &shared_output, &buffers);
+      assert_eq!(shared, compress(block, &fresh_output, &BlockBuffers::default()));
+    }
+    // One pair of buffers served every block
+    assert_eq!(buffers.raw.lock().unwrap().len(), 1);
+    assert_eq!(buffers.compressed.lock().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn verifying_catches_a_corrupted_block(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", &b"verify me ".repeat(1000)), ("b.txt", b"bbbb")]);
+    let options = CreateOptions {
+      verify_after_write: true,
+      hash_files: true,
+      ..Default::default()
+    };
+    let (archive_path, reader) = archive_dir(&input, work.path(), None, &options);
+    verify_written(&archive_path).unwrap();
+
+    let block = &reader.block_infos[0];
+    let mut data = fs::read(&archive_path).unwrap();
+    let middle = (block.offset + block.size / 2) as usize;
+    data[middle..middle + 4].copy_from_slice(b"\xde\xad\xbe\xef");
+    fs::write(&archive_path, data).unwrap();
+    let err = verify_written(&archive_path).unwrap_err();
+    assert!(err.starts_with("at verifying written archive"), "{err}");
+  }
+
+  #[test]
+  fn archives_with_unknown_features_fail_to_open(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    let options = CreateOptions { hash_files: true, ..Default::default() };
+    let (archive_path, reader) = archive_dir(&input, work.path(), None, &options);
+    assert_eq!(reader.header.feature_names(), ["hashes"]);
+
+    let mut header = reader.header.clone();
+    header.feature_flags |= 1 << 20;
+    let mut data = fs::read(&archive_path).unwrap();
+    header.write(&mut data[..header.size() as usize]).unwrap();
+    fs::write(&archive_path, data).unwrap();
+    let err = ArchiveReader::new(&archive_path, None).err().unwrap();
+    assert!(err.contains("features this version of bloda doesn't support"), "{err}");
+  }
+
+  #[test]
+  fn overridden_entries_get_blocks_of_their_own(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[
+      ("media/clip.mp4", b"already compressed"),
+      ("notes.txt", b"text text text"),
+      ("media/readme.txt", b"more text"),
+    ]);
+    let options = CreateOptions {
+      compression_overrides: vec![(r"\.mp4$".to_string(), "NONE".to_string())],
+      ..Default::default()
+    };
+    let (_, reader) = archive_dir(&input, work.path(), None, &options);
+
+    let blocks = reader.blocks();
+    let block_of = |name: &str| {
+      blocks.iter().find(|x| reader.files_in_block(x.id).contains(&name)).unwrap()
+    };
+    assert_eq!(block_of("media/clip.mp4").compression, "NONE");
+    assert_eq!(reader.files_in_block(block_of("media/clip.mp4").id), ["media/clip.mp4"]);
+    assert_eq!(block_of("notes.txt").compression, "ZSTD");
+    assert_eq!(block_of("media/readme.txt").compression, "ZSTD");
+    assert_eq!(reader.read_file("media/clip.mp4").unwrap(), b"already compressed");
+  }
+
+  /// Files of a few blocks, some compressible and some not
+  fn multi_block_tree(input: &Path) -> Vec<(String, Vec<u8>)>{
+    let files = (0..12)
+      .map(|i| {
+        let content = match i % 3 {
+          0 => format!("file {i} ").repeat(500).into_bytes(),
+          _ => (0..3000u32).map(|x| (x.wrapping_mul(2654435761) >> (i % 24)) as u8).collect(),
+        };
+        (format!("d{}/f{i}", i % 4), content)
+      })
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(input, &[(name, content)]);
+    }
+    files
+  }
+
+  #[test]
+  fn transcoding_keeps_contents(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = multi_block_tree(&input);
+    let options = CreateOptions { hash_files: true, ..Default::default() };
+    let (archive_path, reader) = archive_dir(&input, work.path(), Some(8 * 1024), &options);
+    assert!(reader.block_count() > 2);
+
+    let output = work.path().join("lzma.bda");
+    transcode_archive(&archive_path, &output, "LZMA", Some(1)).unwrap();
+    let transcoded = ArchiveReader::new(&output, None).unwrap();
+    assert_eq!(transcoded.block_count(), reader.block_count());
+    assert!(transcoded.blocks().iter().all(|x| x.compression == "LZMA"));
+    for (name, content) in &files {
+      assert_eq!(&transcoded.read_file(name).unwrap(), content);
+    }
+    assert!(transcoded.verify().unwrap().is_empty());
+    assert!(!output.with_extension("bdablob").exists());
+  }
+
+  #[test]
+  fn blocks_over_the_memory_limit_are_transcoded_through_files(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = multi_block_tree(&input);
+    let options = CreateOptions { recoverable: true, ..Default::default() };
+    let (archive_path, _) = archive_dir(&input, work.path(), Some(8 * 1024), &options);
+
+    let reader = ArchiveReader::new(&archive_path, Some(1)).unwrap();
+    let output = work.path().join("snappy.bda");
+    let blob_path = output.with_extension("bdablob");
+    transcode_reader(&reader, &output, &blob_path, "SNAPPY", None).unwrap();
+    let transcoded = ArchiveReader::new(&output, None).unwrap();
+    for (name, content) in &files {
+      assert_eq!(&transcoded.read_file(name).unwrap(), content);
+    }
+    transcoded.check().unwrap();
+    // The streamed blocks are framed like the others
+    let mut fr = fs::File::open(&output).unwrap();
+    fr.seek(io::SeekFrom::Start(transcoded.header.size() + transcoded.header.index_len)).unwrap();
+    assert_eq!(crate::scan_blocks(fr).unwrap().len(), reader.block_count());
+  }
+
+  #[test]
+  fn failed_transcoding_leaves_no_blob(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    multi_block_tree(&input);
+    let options = CreateOptions::default();
+    let (archive_path, reader) = archive_dir(&input, work.path(), Some(8 * 1024), &options);
+    // A marker disagreeing with the index fails the last block
+    let last = reader.block_infos.last().unwrap();
+    let mut data = fs::read(&archive_path).unwrap();
+    data[last.offset as usize] = compress_utils::compression_id("LZ4").unwrap();
+    fs::write(&archive_path, data).unwrap();
+
+    let output = work.path().join("out.bda");
+    let err = transcode_archive(&archive_path, &output, "ZSTD", None).unwrap_err();
+    assert!(err.contains("marker says LZ4"), "{err}");
+    assert!(!output.exists());
+    assert!(!output.with_extension("bdablob").exists());
+  }
+
+  #[test]
+  fn feature_flags_follow_what_was_written(){
+    let entries: [(&str, &[u8]); 1] = [("a.txt", b"aaaa")];
+    let options = CreateOptions {
+      store_ownership: true,
+      detect_hardlinks: true,
+      store_file_flags: true,
+      hash_files: true,
+      ..Default::default()
+    };
+    let data = create_archive_to_vec(&entries, "ZSTD", None, &options).unwrap();
+    assert_eq!(ArchiveReader::from_bytes(&data).unwrap().header.feature_names(), ["hashes"]);
+
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    // No links or flags to store in this tree
+    let (_, reader) = archive_dir(&input, work.path(), None, &options);
+    let expected: &[&str] = if cfg!(unix) { &["hashes", "owners"] } else { &["hashes"] };
+    assert_eq!(reader.header.feature_names(), expected);
+  }
+
+  #[test]
+  fn max_depth_leaves_out_deeper_entries(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[
+      ("top.txt", b"top"),
+      ("a/one.txt", b"one"),
+      ("a/b/two.txt", b"two"),
+      ("a/b/c/three.txt", b"three"),
+    ]);
+    fs::create_dir_all(input.join("a/empty")).unwrap();
+    let options = CreateOptions { max_depth: Some(2), ..Default::default() };
+    let (_, reader) = archive_dir(&input, work.path(), None, &options);
+
+    let mut files = reader.files.iter().map(|x| x.name.as_str()).collect::<Vec<_>>();
+    files.sort();
+    assert_eq!(files, ["a/one.txt", "top.txt"]);
+    // Folders at the limit are kept, empty, whether or not they had contents
+    let mut folders = reader.folder_leaves.keys().map(|x| x.as_str()).collect::<Vec<_>>();
+    folders.sort();
+    assert_eq!(folders, ["a/b", "a/empty"]);
+  }
+
+  #[test]
+  fn streams_round_trip(){
+    let work = tempfile::tempdir().unwrap();
+    let archive_path = work.path().join("archive.bda");
+    let contents = [
+      ("logs/a.log", b"line\n".repeat(40)),
+      ("logs/b.log", b"other line\n".repeat(3)),
+      ("empty", vec![]),
+      ("data.bin", (0..200u8).collect()),
+    ];
+    let entries = contents.iter().map(|(name, data)| {
+      let stream: Box<dyn Read> = Box::new(io::Cursor::new(data.clone()));
+      (name.to_string(), stream)
+    });
+    create_archive_from_streams(entries, &archive_path, "ZSTD", Some(128), &Default::default())
+      .unwrap();
+
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert!(reader.block_count() > 1);
+    for (name, data) in &contents {
+      assert_eq!(&reader.read_file(name).unwrap(), data, "{name}");
+      assert_eq!(reader.file_size(name), Some(data.len() as u64));
+    }
+  }
+
+  /// Whether permissions can't keep this process from reading files
+  #[cfg(unix)]
+  fn reads_everything() -> bool{
+    unsafe { libc::geteuid() == 0 }
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn unreadable_files_fail_unless_skipped(){
+    use std::os::unix::fs::PermissionsExt;
+
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("secret.txt", b"hidden")]);
+    let secret = input.join("secret.txt");
+    fs::set_permissions(&secret, fs::Permissions::from_mode(0o000)).unwrap();
+    if reads_everything() {
+      return;
+    }
+    let archive_path = work.path().join("archive.bda");
+
+    let options = CreateOptions::default();
+    let err = create_archive_with_report(&input, &archive_path, "ZSTD", 2, None, &options)
+      .unwrap_err();
+    assert!(err.contains("secret.txt"), "{err}");
+
+    let options = CreateOptions { keep_going: true, ..Default::default() };
+    let report = create_archive_with_report(&input, &archive_path, "ZSTD", 2, None, &options)
+      .unwrap();
+    assert_eq!(report.skipped, [secret.as_path()]);
+    assert_eq!(report.diagnostics.len(), 1);
+    assert_eq!(report.diagnostics[0].path.as_ref(), Some(&secret));
+    assert!(report.diagnostics[0].message.starts_with("skipped, can't be read"));
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert_eq!(reader.list_files(), ["a.txt"]);
+    reader.check().unwrap();
+  }
+
+  #[test]
+  fn in_memory_oversize_entries_are_diagnostics(){
+    let big = vec![7u8; 64];
+    let entries: [(&str, &[u8]); 2] = [("big.bin", &big), ("small", b"s")];
+    let options = CreateOptions::default();
+    let (_, diagnostics) =
+      create_archive_to_vec_with_diagnostics(&entries, "ZSTD", Some(16), &options).unwrap();
+    let messages = diagnostics.iter().map(|x| x.to_string()).collect::<Vec<_>>();
+    assert_eq!(messages, ["big.bin: 64 bytes, bigger than the block size, stored alone"]);
+  }
+
+  #[test]
+  fn plans_match_created_blocks(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = multi_block_tree(&input);
+    write_tree(&input, &[("big.bin", &[9u8; 20 * 1024])]);
+    fs::create_dir_all(input.join("empty")).unwrap();
+    let options = CreateOptions::default();
+    let plan = plan_archive(&input, "ZSTD", Some(8 * 1024), &options).unwrap();
+    assert_eq!(fs::read_dir(work.path()).unwrap().count(), 1);
+    let (_, reader) = archive_dir(&input, work.path(), Some(8 * 1024), &options);
+
+    assert_eq!(plan.blocks.len(), reader.block_count());
+    for (id, (planned, block)) in plan.blocks.iter().zip(reader.blocks()).enumerate() {
+      let names = planned.files.iter().map(|x| x.0.as_str()).collect::<Vec<_>>();
+      assert_eq!(names, reader.files_in_block(id as u64), "block {id}");
+      assert_eq!(planned.size, block.raw_size, "block {id}");
+      assert_eq!(planned.compression, block.compression);
+    }
+    let file_bytes = files.iter().map(|x| x.1.len() as u64).sum::<u64>() + 20 * 1024;
+    assert_eq!(plan.total_size, file_bytes);
+    assert_eq!(plan.folder_leaves, ["empty"]);
+  }
+
+  #[test]
+  fn largest_blocks_first_writes_the_same_archive(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let mut files = (0..20)
+      .map(|i| (format!("small/{i}"), format!("small file {i}").repeat(40).into_bytes()))
+      .collect::<Vec<_>>();
+    files.push(("huge.bin".to_string(), (0..200_000u32).map(|x| (x % 251) as u8).collect()));
+    files.push(("large.bin".to_string(), (0..50_000u32).map(|x| (x % 13) as u8).collect()));
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content)]);
+    }
+    let archive = |name: &str, largest_blocks_first| {
+      let options = CreateOptions {
+        reproducible: true,
+        largest_blocks_first,
+        ..Default::default()
+      };
+      let path = work.path().join(name);
+      create_archive_with_options(&input, &path, "ZSTD", 4, Some(4 * 1024), &options).unwrap();
+      path
+    };
+    let in_order = archive("in_order.bda", false);
+    let largest_first = archive("largest_first.bda", true);
+
+    assert_eq!(fs::read(&in_order).unwrap(), fs::read(&largest_first).unwrap());
+    let reader = ArchiveReader::new(&largest_first, None).unwrap();
+    assert!(reader.block_count() > 3);
+    for (name, content) in &files {
+      assert_eq!(&reader.read_file(name).unwrap(), content, "{name}");
+    }
+  }
+
+  #[test]
+  fn reports_count_what_was_written(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc"), ("b/d/e.txt", b"eeeee")]);
+    fs::create_dir_all(input.join("empty")).unwrap();
+    let archive_path = work.path().join("archive.bda");
+    let options = CreateOptions::default();
+    let report = create_archive_with_report(&input, &archive_path, "ZSTD", 2, None, &options)
+      .unwrap();
+
+    assert_eq!(report.files, 3);
+    assert_eq!(report.dirs, 3);
+    assert_eq!(report.blocks, 1);
+    assert_eq!(report.input_bytes, 11);
+    assert_eq!(report.output_bytes, fs::metadata(&archive_path).unwrap().len());
+    assert!(report.ratio() > 0.0);
+    assert!(report.skipped.is_empty());
+    assert!(report.diagnostics.is_empty());
+  }
+
+  #[test]
+  fn block_levels_round_trip(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("raw/b.bin", b"bbbb")]);
+    let options = CreateOptions {
+      compression_level: Some(11),
+      compression_overrides: vec![("^raw/".to_string(), "NONE".to_string())],
+      ..Default::default()
+    };
+    let (archive_path, reader) = archive_dir(&input, work.path(), None, &options);
+    let levels = reader
+      .blocks()
+      .into_iter()
+      .map(|x| (x.compression, x.compression_level))
+      .collect::<Vec<_>>();
+    assert_eq!(levels, [("ZSTD".to_string(), Some(11)), ("NONE".to_string(), None)]);
+
+    let output = work.path().join("lzma.bda");
+    transcode_archive(&archive_path, &output, "LZMA", None).unwrap();
+    let transcoded = ArchiveReader::new(&output, None).unwrap();
+    assert!(transcoded.blocks().iter().all(|x| x.compression_level == Some(9)));
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn dangling_symlinks_are_skipped(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    let link = input.join("b/gone.txt");
+    std::os::unix::fs::symlink(work.path().join("nowhere.txt"), &link).unwrap();
+    let archive_path = work.path().join("archive.bda");
+    let options = CreateOptions::default();
+    let report = create_archive_with_report(&input, &archive_path, "ZSTD", 2, None, &options)
+      .unwrap();
+
+    assert_eq!(report.skipped, [link.as_path()]);
+    assert_eq!(report.diagnostics.len(), 1);
+    assert!(report.diagnostics[0].message.contains("nowhere.txt"));
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert_eq!(reader.list_files(), ["a.txt", "b/c.txt"]);
+    reader.check().unwrap();
+  }
+
+  #[test]
+  fn files_failing_mid_read_are_left_out_of_their_block(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a", b"first"), ("b", b"short"), ("c", b"third")]);
+    // b is listed as longer than it is, as if it shrank after being listed
+    let block = [(input.join("a"), 0, 5), (input.join("b"), 5, 50), (input.join("c"), 55, 5)];
+    let output = work.path().join("block");
+    let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+    let compress = |keep_going| {
+      let retry = RetryPolicy::default();
+      let buffers = BlockBuffers::default();
+      runtime.block_on(compress_block(&output, &block, "ZSTD", None, retry, keep_going, &buffers))
+    };
+
+    let err = compress(false).unwrap_err();
+    assert!(err.contains("at loading"), "{err}");
+    let (size, failed) = compress(true).unwrap();
+    assert_eq!(failed.len(), 1);
+    assert_eq!(failed[0].0, input.join("b"));
+    let compressed = fs::read(&output).unwrap();
+    assert_eq!(compressed.len() as u64, size);
+    let mut raw = vec![];
+    compress_utils::decompress_data(&compressed[1..], &mut raw, "ZSTD").unwrap();
+    assert_eq!(raw, b"firstthird");
+  }
+
+  #[test]
+  fn inputs_are_merged_into_one_archive(){
+    let work = tempfile::tempdir().unwrap();
+    let first = work.path().join("first");
+    let second = work.path().join("second");
+    write_tree(&first, &[("a.txt", b"aaaa"), ("shared/b.txt", b"bb")]);
+    write_tree(&second, &[("c.txt", b"cc"), ("shared/d.txt", b"dd")]);
+    fs::create_dir_all(first.join("empty")).unwrap();
+    fs::create_dir_all(second.join("empty")).unwrap();
+    let archive_path = work.path().join("archive.bda");
+    let inputs = [first.as_path(), second.as_path()];
+    let options = CreateOptions::default();
+    create_archive_from_inputs(&inputs, &archive_path, "ZSTD", 2, None, &options).unwrap();
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert_eq!(reader.list_files(), ["a.txt", "c.txt", "shared/b.txt", "shared/d.txt"]);
+    assert_eq!(reader.read_file("shared/d.txt").unwrap(), b"dd");
+
+    let options = CreateOptions { prefix_inputs: true, ..Default::default() };
+    create_archive_from_inputs(&inputs, &archive_path, "ZSTD", 2, None, &options).unwrap();
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    let files = ["first/a.txt", "first/shared/b.txt", "second/c.txt", "second/shared/d.txt"];
+    assert_eq!(reader.list_files(), files);
+
+    write_tree(&second, &[("shared/b.txt", b"other")]);
+    let options = CreateOptions::default();
+    let err = create_archive_from_inputs(&inputs, &archive_path, "ZSTD", 2, None, &options)
+      .unwrap_err();
+    assert!(err.contains("entries of different inputs have the same name: shared/b.txt"), "{err}");
+    assert!(!err.contains("empty"), "{err}");
+  }
+
+  #[test]
+  fn lists_archive_only_their_paths(){
+    let work = tempfile::tempdir().unwrap();
+    let base = work.path().join("base");
+    let files: [(&str, &[u8]); 4] =
+      [("a.txt", b"aaaa"), ("b/c.txt", b"cc"), ("b/d.txt", b"dd"), ("e.txt", b"e")];
+    write_tree(&base, &files);
+    fs::create_dir_all(base.join("empty")).unwrap();
+    write_tree(work.path(), &[("outside.txt", b"secret")]);
+    let archive_path = work.path().join("archive.bda");
+    let paths = ["a.txt", "b/./c.txt", "b/../e.txt", "empty"].map(|x| base.join(x));
+    let options = CreateOptions::default();
+    create_archive_from_list(&paths, &base, &archive_path, "ZSTD", 2, None, &options).unwrap();
+    let reader = ArchiveReader::new(&archive_path, None).unwrap();
+    assert_eq!(reader.list_files(), ["a.txt", "b/c.txt", "e.txt"]);
+    assert_eq!(reader.read_file("e.txt").unwrap(), b"e");
+
+    for escaping in [base.join("b/../../outside.txt"), work.path().join("outside.txt")] {
+      let paths = [base.join("a.txt"), escaping];
+      let err = create_archive_from_list(&paths, &base, &archive_path, "ZSTD", 2, None, &options)
+        .unwrap_err();
+      assert!(err.contains("is outside the base directory"), "{err}");
+    }
+  }
+
+  #[test]
+  fn paths_normalize_without_the_filesystem(){
+    let normalized = |x: &str| normalize_lexically(Path::new(x));
+    assert_eq!(normalized("/a/./b/../c"), Path::new("/a/c"));
+    assert_eq!(normalized("/a/../../b"), Path::new("/b"));
+    assert_eq!(normalized("a/b/../../.."), Path::new(""));
+  }
+
+  #[test]
+  fn blocks_respect_both_size_and_file_caps(){
+    let items = vec![("a", 4), ("b", 4), ("c", 1), ("d", 1), ("e", 1), ("f", 20), ("g", 2)];
+    let names = |blocks: &[PackedBlock<&str>]| {
+      blocks.iter().map(|x| x.iter().map(|y| y.0).collect::<String>()).collect::<Vec<_>>()
+    };
+
+    let blocks = pack_into_blocks(items.clone(), 10, None);
+    assert_eq!(names(&blocks), ["abcd", "e", "f", "g"]);
+    assert_eq!(blocks[0], [("a", 0, 4), ("b", 4, 4), ("c", 8, 1), ("d", 9, 1)]);
+    let blocks = pack_into_blocks(items.clone(), 10, Some(2));
+    assert_eq!(names(&blocks), ["ab", "cd", "e", "f", "g"]);
+    let blocks = pack_into_blocks(items.clone(), 100, Some(3));
+    assert_eq!(names(&blocks), ["abc", "def", "g"]);
+    for (max_size, max_files) in [(1, None), (10, Some(1)), (100, Some(7))] {
+      for block in pack_into_blocks(items.clone(), max_size, max_files) {
+        // An item over the size cap is stored alone
+        assert!(block.len() == 1 || block.iter().map(|x| x.2).sum::<i64>() <= max_size);
+        assert!(block.len() <= max_files.unwrap_or(usize::MAX));
+      }
+    }
+  }
+
+  #[test]
+  fn naming_modes_round_trip(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    let full_input = fs::canonicalize(&input).unwrap();
+    // Extraction puts absolute names under the output directory, without their root
+    let absolute = |name: &str| {
+      let path = full_input.join(name);
+      let parts = path.components().filter_map(|x| match x {
+        Component::Normal(x) => Some(x.to_string_lossy()),
+        _ => None,
+      });
+      parts.collect::<Vec<_>>().join("/")
+    };
+    let modes = [
+      (CreateOptions::default(), vec!["a.txt".to_string(), "b/c.txt".to_string()]),
+      (
+        CreateOptions { base_name: Some("top/level/".to_string()), ..Default::default() },
+        vec!["top/level/a.txt".to_string(), "top/level/b/c.txt".to_string()],
+      ),
+      (
+        CreateOptions { absolute_paths: true, ..Default::default() },
+        vec![absolute("a.txt"), absolute("b/c.txt")],
+      ),
+    ];
+    for (i, (options, expected)) in modes.iter().enumerate() {
+      let archive_path = work.path().join(format!("{i}.bda"));
+      create_archive_with_options(&input, &archive_path, "ZSTD", 2, None, options).unwrap();
+      let out = work.path().join(format!("out{i}"));
+      crate::decompress_archive(&archive_path, &out).unwrap();
+      assert_eq!(&list_tree(&out), expected);
+      assert_eq!(fs::read(out.join(&expected[1])).unwrap(), b"cc");
+    }
+  }
+
+  #[test]
+  fn unsafe_base_names_are_refused(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa")]);
+    let archive_path = work.path().join("archive.bda");
+    for base_name in ["../../evil", "a/../..", "/abs", "./a", "a//b", "", "/"] {
+      let options = CreateOptions { base_name: Some(base_name.to_string()), ..Default::default() };
+      let err = create_archive_with_options(&input, &archive_path, "ZSTD", 2, None, &options)
+        .unwrap_err();
+      assert!(err.starts_with(&format!("base name {base_name:?} must be")), "{err}");
+      assert!(!archive_path.exists());
+      let entries: [(&str, &[u8]); 1] = [("a.txt", b"aaaa")];
+      assert!(create_archive_to_vec(&entries, "ZSTD", None, &options).is_err());
+    }
+  }
+
+  #[test]
+  fn index_orders_round_trip(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files: [(&str, &[u8]); 5] = [
+      ("a.txt", b"aaaaa"),
+      ("b/c.txt", b"c"),
+      ("b/d.txt", b"ddd"),
+      ("e.txt", b"ee"),
+      ("f.txt", b"ee"),
+    ];
+    write_tree(&input, &files);
+    for i in 0..8 {
+      fs::create_dir_all(input.join(format!("empty{i}"))).unwrap();
+    }
+    let leaves = (0..8).map(|i| format!("empty{i}")).collect::<Vec<_>>();
+
+    let options = CreateOptions { index_order: IndexOrder::Size, ..Default::default() };
+    let (archive_path, reader) = archive_dir(&input, work.path(), None, &options);
+    assert_eq!(reader.list_files(), ["b/c.txt", "e.txt", "f.txt", "b/d.txt", "a.txt"]);
+    assert_eq!(reader.list_dirs(), leaves);
+    // The same on every open
+    for _ in 0..3 {
+      let reopened = ArchiveReader::new(&archive_path, None).unwrap();
+      assert_eq!(reopened.list_all_entries(), reader.list_all_entries());
+    }
+
+    let (_, reader) = archive_dir(&input, work.path(), None, &CreateOptions::default());
+    assert_eq!(reader.list_files(), ["a.txt", "b/c.txt", "b/d.txt", "e.txt", "f.txt"]);
+    assert_eq!(reader.list_dirs(), leaves);
+  }
+
+  #[test]
+  fn blocks_past_the_blob_end_fail_to_open(){
+    let work = tempfile::tempdir().unwrap();
+    let blob_path = work.path().join("blob");
+    let archive_path = work.path().join("archive.bda");
+    let open_with_block = |offset, size| {
+      // Taken in by the archive
+      fs::write(&blob_path, b"0123456789").unwrap();
+      let file = ArchiveFileEntry { name: "a".to_string(), block: 0, offset: 0, size: 1 };
+      let block = ArchiveBlockInfo { id: 0, size, offset, compression_type: "NONE".to_string() };
+      let index_db = make_index_db(
+        vec![file],
+        vec![],
+        vec![block],
+        None,
+        vec![],
+        HashAlgorithm::default(),
+        vec![],
+        vec![],
+        vec![],
+        vec![],
+        vec![]
+      ).unwrap();
+      let retry = RetryPolicy::default();
+      assemble_archive(&archive_path, &index_db, &blob_path, "ZSTD", 0, retry, &mut vec![])
+        .unwrap();
+      ArchiveReader::new(&archive_path, None)
+    };
+
+    assert!(open_with_block(0, 10).is_ok());
+    for (offset, size) in [(0, 11), (5, 6), (0, i64::MAX), (i64::MAX, 1), (i64::MAX - 5, 0)] {
+      let err = open_with_block(offset, size).err().unwrap();
+      assert_eq!(err, "block 0 ends past the end of the archive", "{offset} {size}");
+    }
+  }
+  #[test]
+  fn creation_metadata_is_recorded_unless_reproducible(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    write_tree(&input, &[("a.txt", b"aaaa"), ("b/c.txt", b"cc")]);
+    let archive = |name: &str, options: &CreateOptions| {
+      let path = work.path().join(name);
+      create_archive_with_options(&input, &path, "ZSTD", 2, None, options).unwrap();
+      path
+    };
+    let now = || SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+    let version = format!("bloda-sys {}", env!("CARGO_PKG_VERSION"));
+
+    let before = now();
+    let timestamped = ArchiveReader::new(&archive("timestamped.bda", &Default::default()), None)
+      .unwrap();
+    let created_at = timestamped.created_at().unwrap();
+    assert!((before..=now()).contains(&created_at));
+    assert_eq!(timestamped.created_by(), Some(version.as_str()));
+
+    let options = CreateOptions { reproducible: true, ..Default::default() };
+    let first = archive("first.bda", &options);
+    let second = archive("second.bda", &options);
+    assert_eq!(fs::read(&first).unwrap(), fs::read(&second).unwrap());
+    let reproducible = ArchiveReader::new(&first, None).unwrap();
+    assert_eq!(reproducible.created_at(), None);
+    assert!(!reproducible.metadata().contains_key(CREATED_AT_KEY));
+    assert_eq!(reproducible.created_by(), Some(version.as_str()));
+
+    let options = CreateOptions {
+      reproducible: true,
+      metadata: BTreeMap::from([(CREATED_BY_KEY.to_string(), "someone else".to_string())]),
+      ..Default::default()
+    };
+    let overridden = ArchiveReader::new(&archive("overridden.bda", &options), None).unwrap();
+    assert_eq!(overridden.created_by(), Some("someone else"));
+  }
+  #[test]
+  fn thread_counts_give_the_same_hashes_and_bytes(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (0..40)
+      .map(|i| (format!("d{}/f{i}.txt", i % 5), format!("contents of {i} ").repeat(i * 50 + 1)))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content.as_bytes())]);
+    }
+    let options = CreateOptions { hash_files: true, reproducible: true, ..Default::default() };
+    let archive = |threads: u8| {
+      let path = work.path().join(format!("{threads}.bda"));
+      create_archive_with_options(&input, &path, "ZSTD", threads, Some(8 * 1024), &options)
+        .unwrap();
+      path
+    };
+    let serial = archive(1);
+    let parallel = archive(8);
+
+    assert_eq!(fs::read(&serial).unwrap(), fs::read(&parallel).unwrap());
+    let serial = ArchiveReader::new(&serial, None).unwrap();
+    let parallel = ArchiveReader::new(&parallel, None).unwrap();
+    assert!(serial.block_count() > 1);
+    for (name, _) in &files {
+      let hash = serial.file_hash(name).unwrap();
+      assert_eq!(Some(hash), parallel.file_hash(name), "{name}");
+    }
+    serial.check().unwrap();
+  }
+  #[test]
+  fn preallocated_blob_length_is_what_gets_written(){
+    let work = tempfile::tempdir().unwrap();
+    let input = work.path().join("in");
+    let files = (0..20)
+      .map(|i| (format!("f{i}"), format!("file {i} ").repeat(200 + i * 20).into_bytes()))
+      .collect::<Vec<_>>();
+    for (name, content) in &files {
+      write_tree(&input, &[(name, content)]);
+    }
+    for recoverable in [false, true] {
+      let options = CreateOptions { recoverable, ..Default::default() };
+      let (archive_path, reader) = archive_dir(&input, work.path(), Some(4 * 1024), &options);
+      assert!(reader.block_count() > 3);
+      let block_sizes = reader
+        .blocks()
+        .iter()
+        .map(|x| (x.compressed_size, x.compression.clone()))
+        .collect::<Vec<_>>();
+      let (block_infos, blob_len) = layout_blocks(&block_sizes, recoverable);
+
+      let archive_len = fs::metadata(&archive_path).unwrap().len();
+      let blob_offset = reader.header.size() + reader.header.index_len;
+      assert_eq!(archive_len - blob_offset, blob_len as u64, "{recoverable}");
+      let offsets = reader.block_infos.iter().map(|x| x.offset).collect::<Vec<_>>();
+      let laid_out = block_infos.iter().map(|x| x.offset + blob_offset as i64).collect::<Vec<_>>();
+      assert_eq!(offsets, laid_out, "{recoverable}");
+    }
+  }
+}